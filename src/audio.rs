@@ -1,4 +1,7 @@
+#[cfg(feature = "audio")]
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+#[cfg(any(test, feature = "audio"))]
+use crossbeam_channel::{Receiver, Sender, TrySendError};
 use infinitedsp_core::core::audio_param::AudioParam;
 use infinitedsp_core::core::channels::{DualMono, Mono, Stereo};
 use infinitedsp_core::core::dsp_chain::DspChain;
@@ -14,37 +17,39 @@ use infinitedsp_core::effects::utility::stereo_widener::StereoWidener;
 use infinitedsp_core::synthesis::envelope::Adsr;
 use infinitedsp_core::synthesis::oscillator::{Oscillator, Waveform as CoreWaveform};
 use infinitedsp_core::FrameProcessor;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
 
 use crate::dsp_utils::Sum;
 use crate::fast_lfo::{FastLfo, FastLfoWaveform};
+use crate::params::{ParamId, ParamKind, PARAMS};
 use crate::protocol::{LfoWaveform, OscSettings, Preset, Waveform};
+use crate::voices::{MonoChange, NotePriority, NoteStack, VoiceAllocator, MAX_POLYPHONY};
 
 // --- Helpers ---
 
+/// A control value written from outside the audio thread and read by the
+/// graph as a constant signal.
 #[derive(Clone)]
 struct SharedValue {
-    value: Arc<Mutex<f32>>,
+    value: Parameter,
 }
 
 impl SharedValue {
     fn new(val: f32) -> Self {
         Self {
-            value: Arc::new(Mutex::new(val)),
+            value: Parameter::new(val),
         }
     }
 
     fn set(&self, val: f32) {
-        *self.value.lock().unwrap() = val;
+        self.value.set(val);
     }
 }
 
 impl FrameProcessor<Mono> for SharedValue {
     fn process(&mut self, buffer: &mut [f32], _frame_index: u64) {
-        let val = *self.value.lock().unwrap();
-        for sample in buffer.iter_mut() {
-            *sample = val;
-        }
+        buffer.fill(self.value.get());
     }
     fn set_sample_rate(&mut self, _sample_rate: f32) {}
     fn reset(&mut self) {}
@@ -59,34 +64,297 @@ impl FrameProcessor<Mono> for SharedValue {
     }
 }
 
+// --- Gate ---
+
+struct GateState {
+    open: AtomicBool,
+    /// Bumped whenever an open gate is opened again.
+    retriggers: AtomicU32,
+}
+
+/// Gate signal for a voice's envelopes. Opening an open gate drops it for
+/// one sample so the envelopes restart from their current level.
+#[derive(Clone)]
+struct GateControl {
+    state: Arc<GateState>,
+    /// Retriggers this envelope input has already played.
+    seen: u32,
+}
+
+impl GateControl {
+    fn new() -> Self {
+        Self {
+            state: Arc::new(GateState {
+                open: AtomicBool::new(false),
+                retriggers: AtomicU32::new(0),
+            }),
+            seen: 0,
+        }
+    }
+
+    /// A gate input for one envelope. Each keeps track of the retriggers it
+    /// has played, so every envelope sees every retrigger.
+    fn signal(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+            seen: self.state.retriggers.load(Ordering::Acquire),
+        }
+    }
+
+    fn open(&self) {
+        if self.state.open.swap(true, Ordering::AcqRel) {
+            self.state.retriggers.fetch_add(1, Ordering::AcqRel);
+        }
+    }
+
+    fn close(&self) {
+        self.state.open.store(false, Ordering::Release);
+    }
+}
+
+impl FrameProcessor<Mono> for GateControl {
+    fn process(&mut self, buffer: &mut [f32], _frame_index: u64) {
+        let open = self.state.open.load(Ordering::Acquire);
+        buffer.fill(if open { 1.0 } else { 0.0 });
+        let retriggers = self.state.retriggers.load(Ordering::Acquire);
+        if retriggers != self.seen {
+            self.seen = retriggers;
+            if let Some(first) = buffer.first_mut() {
+                *first = 0.0;
+            }
+        }
+    }
+    fn set_sample_rate(&mut self, _sample_rate: f32) {}
+    fn reset(&mut self) {}
+    fn latency_samples(&self) -> u32 {
+        0
+    }
+    fn name(&self) -> &str {
+        "GateControl"
+    }
+    fn visualize(&self, _indent: usize) -> String {
+        "GateControl".into()
+    }
+}
+
+// --- Envelopes ---
+
+/// A voice envelope's output for the current block, as read by the graph.
+/// The envelopes themselves run in the `Player`, outside any graph, so a
+/// rebuilt graph continues a note where the old one was.
+#[derive(Clone)]
+struct EnvelopeSignal {
+    samples: Arc<[AtomicU32]>,
+}
+
+impl EnvelopeSignal {
+    fn new() -> Self {
+        Self {
+            samples: (0..MAX_BLOCK_FRAMES).map(|_| AtomicU32::new(0)).collect(),
+        }
+    }
+
+    fn write(&self, block: &[f32]) {
+        for (sample, value) in self.samples.iter().zip(block) {
+            sample.store(value.to_bits(), Ordering::Relaxed);
+        }
+    }
+}
+
+impl FrameProcessor<Mono> for EnvelopeSignal {
+    fn process(&mut self, buffer: &mut [f32], _frame_index: u64) {
+        for (out, sample) in buffer.iter_mut().zip(self.samples.iter()) {
+            *out = f32::from_bits(sample.load(Ordering::Relaxed));
+        }
+    }
+    fn set_sample_rate(&mut self, _sample_rate: f32) {}
+    fn reset(&mut self) {}
+    fn latency_samples(&self) -> u32 {
+        0
+    }
+    fn name(&self) -> &str {
+        "EnvelopeSignal"
+    }
+    fn visualize(&self, _indent: usize) -> String {
+        "EnvelopeSignal".into()
+    }
+}
+
+/// A voice's filter and amp envelopes, run by the player once per block.
+struct VoiceEnvelopes {
+    filter: Adsr,
+    amp: Adsr,
+    scratch: Vec<f32>,
+}
+
+impl VoiceEnvelopes {
+    fn new(control: &VoiceControl, params: &LiveParams, sample_rate: f32) -> Self {
+        let adsr = |attack, decay, sustain, release| {
+            let mut env = Adsr::new(
+                AudioParam::Dynamic(Box::new(control.gate.signal())),
+                AudioParam::Linked(params.get(attack)),
+                AudioParam::Linked(params.get(decay)),
+                AudioParam::Linked(params.get(sustain)),
+                AudioParam::Linked(params.get(release)),
+            );
+            env.set_sample_rate(sample_rate);
+            env
+        };
+        let mut envelopes = Self {
+            filter: adsr(
+                ParamId::FilterAttack,
+                ParamId::FilterDecay,
+                ParamId::FilterSustain,
+                ParamId::FilterRelease,
+            ),
+            amp: adsr(
+                ParamId::AmpAttack,
+                ParamId::AmpDecay,
+                ParamId::AmpSustain,
+                ParamId::AmpRelease,
+            ),
+            scratch: vec![0.0; MAX_BLOCK_FRAMES],
+        };
+        // Lets the envelopes allocate their buffers before the audio thread
+        // gets them. The gate is still closed, so they stay idle.
+        envelopes.run(control, MAX_BLOCK_FRAMES);
+        envelopes
+    }
+
+    fn run(&mut self, control: &VoiceControl, frames: usize) {
+        let block = &mut self.scratch[..frames];
+        self.filter.process(block, 0);
+        control.filter_env.write(block);
+        self.amp.process(block, 0);
+        control.amp_env.write(block);
+    }
+}
+
+// --- Crossfade ---
+
+/// How long a rebuilt graph takes to fade in over the one it replaces.
+const CROSSFADE_SECS: f32 = 0.02;
+
+struct FadeState {
+    level: AtomicU32,
+    target: AtomicU32,
+}
+
+/// Ramps the voices into a graph's effects. Fading the voices rather than
+/// the output lets a replaced graph's delay and reverb ring out.
+#[derive(Clone)]
+struct VoiceFade {
+    state: Arc<FadeState>,
+    step: f32,
+}
+
+impl VoiceFade {
+    fn new(sample_rate: f32) -> Self {
+        Self {
+            state: Arc::new(FadeState {
+                level: AtomicU32::new(1.0f32.to_bits()),
+                target: AtomicU32::new(1.0f32.to_bits()),
+            }),
+            step: 1.0 / (CROSSFADE_SECS * sample_rate),
+        }
+    }
+
+    fn fade_in(&self) {
+        self.state.level.store(0, Ordering::Relaxed);
+        self.state.target.store(1.0f32.to_bits(), Ordering::Relaxed);
+    }
+
+    fn fade_out(&self) {
+        self.state.target.store(0.0f32.to_bits(), Ordering::Relaxed);
+    }
+
+    fn is_faded_out(&self) -> bool {
+        self.state.level.load(Ordering::Relaxed) == 0
+            && self.state.target.load(Ordering::Relaxed) == 0
+    }
+
+    /// Scales `buffer` while moving the level towards the target.
+    fn apply(&self, buffer: &mut [f32]) {
+        let target = f32::from_bits(self.state.target.load(Ordering::Relaxed));
+        let mut level = f32::from_bits(self.state.level.load(Ordering::Relaxed));
+        for sample in buffer.iter_mut() {
+            level = if level < target {
+                (level + self.step).min(target)
+            } else {
+                (level - self.step).max(target)
+            };
+            *sample *= level;
+        }
+        self.state.level.store(level.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// The voice mix of a graph behind its fade. Once faded out the voices are
+/// no longer run, so a replaced graph only costs its effects while it rings
+/// out.
+struct FadedVoices {
+    voices: DspChain<Mono>,
+    fade: VoiceFade,
+}
+
+impl FrameProcessor<Mono> for FadedVoices {
+    fn process(&mut self, buffer: &mut [f32], frame_index: u64) {
+        if self.fade.is_faded_out() {
+            buffer.fill(0.0);
+            return;
+        }
+        self.voices.process(buffer, frame_index);
+        self.fade.apply(buffer);
+    }
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.voices.set_sample_rate(sample_rate);
+    }
+    fn reset(&mut self) {
+        self.voices.reset();
+    }
+    fn latency_samples(&self) -> u32 {
+        self.voices.latency_samples()
+    }
+    fn name(&self) -> &str {
+        "FadedVoices"
+    }
+    fn visualize(&self, _indent: usize) -> String {
+        "FadedVoices".into()
+    }
+}
+
 // --- Portamento Frequency Control ---
 
+/// Glide state shared by the oscillators of a voice. Only the audio thread
+/// advances it, the atomics just make it shareable without a lock.
 struct PortamentoState {
-    current_freq: f32,
-    counter: usize,
+    target_freq: AtomicU32,
+    current_freq: AtomicU32,
+    counter: AtomicUsize,
 }
 
 #[derive(Clone)]
 struct PortamentoFreq {
-    target_freq: Arc<Mutex<f32>>,
     portamento_amount: Parameter,
-    state: Arc<Mutex<PortamentoState>>,
+    state: Arc<PortamentoState>,
 }
 
 impl PortamentoFreq {
     fn new(start_freq: f32) -> Self {
         Self {
-            target_freq: Arc::new(Mutex::new(start_freq)),
             portamento_amount: Parameter::new(0.0),
-            state: Arc::new(Mutex::new(PortamentoState {
-                current_freq: start_freq,
-                counter: 0,
-            })),
+            state: Arc::new(PortamentoState {
+                target_freq: AtomicU32::new(start_freq.to_bits()),
+                current_freq: AtomicU32::new(start_freq.to_bits()),
+                counter: AtomicUsize::new(0),
+            }),
         }
     }
 
     fn set_target(&self, freq: f32) {
-        *self.target_freq.lock().unwrap() = freq;
+        self.state
+            .target_freq
+            .store(freq.to_bits(), Ordering::Relaxed);
     }
 
     fn set_portamento(&self, amount: f32) {
@@ -96,9 +364,10 @@ impl PortamentoFreq {
 
 impl FrameProcessor<Mono> for PortamentoFreq {
     fn process(&mut self, buffer: &mut [f32], _frame_index: u64) {
-        let target = *self.target_freq.lock().unwrap();
+        let target = f32::from_bits(self.state.target_freq.load(Ordering::Relaxed));
         let amount = self.portamento_amount.get();
-        let mut state = self.state.lock().unwrap();
+        let mut current_freq = f32::from_bits(self.state.current_freq.load(Ordering::Relaxed));
+        let mut counter = self.state.counter.load(Ordering::Relaxed);
 
         // Exponential Glide (Constant Rate / Filter Glide)
         // factor = 1.0 - P
@@ -107,23 +376,28 @@ impl FrameProcessor<Mono> for PortamentoFreq {
 
         for sample in buffer.iter_mut() {
             // Update every 32 samples (Control Rate)
-            if state.counter % 32 == 0 {
+            if counter.is_multiple_of(32) {
                 if p > 0.0 {
-                    let diff = target - state.current_freq;
+                    let diff = target - current_freq;
                     // Snap to target if close enough
                     if diff.abs() < 0.1 {
-                        state.current_freq = target;
+                        current_freq = target;
                     } else {
-                        state.current_freq += diff * factor;
+                        current_freq += diff * factor;
                     }
                 } else {
-                    state.current_freq = target;
+                    current_freq = target;
                 }
             }
 
-            *sample = state.current_freq;
-            state.counter += 1;
+            *sample = current_freq;
+            counter += 1;
         }
+
+        self.state
+            .current_freq
+            .store(current_freq.to_bits(), Ordering::Relaxed);
+        self.state.counter.store(counter, Ordering::Relaxed);
     }
 
     fn set_sample_rate(&mut self, _sample_rate: f32) {}
@@ -139,123 +413,133 @@ impl FrameProcessor<Mono> for PortamentoFreq {
     }
 }
 
+// --- Voice Controls ---
+
+/// Pitch, gate and velocity of one voice, driven from the audio callback.
+#[derive(Clone)]
+struct VoiceControl {
+    freq: PortamentoFreq,
+    gate: GateControl,
+    /// Amp level and filter envelope scale for the velocity of the note.
+    amp_velocity: SharedValue,
+    filter_velocity: SharedValue,
+    filter_env: EnvelopeSignal,
+    amp_env: EnvelopeSignal,
+}
+
+impl VoiceControl {
+    fn new() -> Self {
+        Self {
+            freq: PortamentoFreq::new(440.0),
+            gate: GateControl::new(),
+            amp_velocity: SharedValue::new(1.0),
+            filter_velocity: SharedValue::new(1.0),
+            filter_env: EnvelopeSignal::new(),
+            amp_env: EnvelopeSignal::new(),
+        }
+    }
+
+    /// Applies the preset's velocity depths to `velocity` (0-127). A depth of
+    /// 0 ignores the velocity, 1 makes the level proportional to it.
+    fn set_velocity(&self, velocity: u8, params: &LiveParams) {
+        let v = velocity.min(127) as f32 / 127.0;
+        let scale = |id| {
+            let depth = params.get(id).get();
+            1.0 - depth + depth * v
+        };
+        self.amp_velocity.set(scale(ParamId::VelocityAmp));
+        self.filter_velocity.set(scale(ParamId::VelocityFilter));
+    }
+}
+
+fn note_freq(note: u8) -> f32 {
+    440.0 * 2.0f32.powf((note as f32 - 69.0) / 12.0)
+}
+
+fn play_mono(voice: &VoiceControl, change: Option<MonoChange>) {
+    match change {
+        Some(MonoChange::Play { note, retrigger }) => {
+            voice.freq.set_target(note_freq(note));
+            if retrigger {
+                voice.gate.open();
+            }
+        }
+        Some(MonoChange::Release) => voice.gate.close(),
+        None => {}
+    }
+}
+
+// --- Performance Controls ---
+
+/// Pitch bend range of the local engine in semitones.
+const BEND_RANGE: f32 = 2.0;
+/// Vibrato added by a fully raised mod wheel, as a frequency ratio.
+const WHEEL_VIBRATO_DEPTH: f32 = 0.03;
+const WHEEL_VIBRATO_RATE: f32 = 5.5;
+
+/// Frequency ratio for a 14-bit pitch bend value.
+fn bend_ratio(value: u16) -> f32 {
+    let semitones = (value as f32 - 8192.0) / 8192.0 * BEND_RANGE;
+    2.0f32.powf(semitones / 12.0)
+}
+
+/// Pitch bend and mod wheel from a controller, shared by all oscillators.
+#[derive(Clone)]
+struct PitchMod {
+    /// Frequency ratio, 1.0 when centered.
+    bend: SharedValue,
+    /// Mod wheel position, 0.0 to 1.0.
+    wheel: SharedValue,
+}
+
+impl PitchMod {
+    fn new() -> Self {
+        Self {
+            bend: SharedValue::new(1.0),
+            wheel: SharedValue::new(0.0),
+        }
+    }
+}
+
 // --- Live Parameters ---
 
+/// One `Parameter` per registry entry. Continuous values are read by the
+/// running graph; switches and choices change its structure and trigger a
+/// rebuild.
 #[derive(Clone)]
 struct LiveParams {
-    osc1_level: Parameter,
-    osc1_octave: Parameter,
-    osc1_detune: Parameter,
-    osc2_level: Parameter,
-    osc2_octave: Parameter,
-    osc2_detune: Parameter,
-    osc3_level: Parameter,
-    osc3_octave: Parameter,
-    osc3_detune: Parameter,
-    noise_level: Parameter,
-    cutoff: Parameter,
-    resonance: Parameter,
-    filter_env_amt: Parameter,
-    filter_attack: Parameter,
-    filter_decay: Parameter,
-    filter_sustain: Parameter,
-    filter_release: Parameter,
-    amp_attack: Parameter,
-    amp_decay: Parameter,
-    amp_sustain: Parameter,
-    amp_release: Parameter,
-    delay_time: Parameter,
-    delay_feedback: Parameter,
-    delay_mix: Parameter,
-    reverb_size: Parameter,
-    reverb_damping: Parameter,
-    reverb_mix: Parameter,
-    lfo_freq: Parameter,
-    lfo_vib_amt: Parameter,
-    lfo_filt_amt: Parameter,
+    values: Vec<(ParamId, Parameter)>,
     last_struct_hash: u64,
 }
 
 impl LiveParams {
     fn new() -> Self {
         Self {
-            osc1_level: Parameter::new(1.0),
-            osc1_octave: Parameter::new(0.0),
-            osc1_detune: Parameter::new(0.0),
-            osc2_level: Parameter::new(0.0),
-            osc2_octave: Parameter::new(0.0),
-            osc2_detune: Parameter::new(0.0),
-            osc3_level: Parameter::new(0.0),
-            osc3_octave: Parameter::new(0.0),
-            osc3_detune: Parameter::new(0.0),
-            noise_level: Parameter::new(0.0),
-            cutoff: Parameter::new(20000.0),
-            resonance: Parameter::new(0.0),
-            filter_env_amt: Parameter::new(0.0),
-            filter_attack: Parameter::new(0.0),
-            filter_decay: Parameter::new(0.0),
-            filter_sustain: Parameter::new(1.0),
-            filter_release: Parameter::new(0.0),
-            amp_attack: Parameter::new(0.01),
-            amp_decay: Parameter::new(0.1),
-            amp_sustain: Parameter::new(1.0),
-            amp_release: Parameter::new(0.1),
-            delay_time: Parameter::new(0.5),
-            delay_feedback: Parameter::new(0.0),
-            delay_mix: Parameter::new(0.0),
-            reverb_size: Parameter::new(0.5),
-            reverb_damping: Parameter::new(0.5),
-            reverb_mix: Parameter::new(0.0),
-            lfo_freq: Parameter::new(1.0),
-            lfo_vib_amt: Parameter::new(0.0),
-            lfo_filt_amt: Parameter::new(0.0),
+            values: PARAMS
+                .iter()
+                .map(|d| (d.id, Parameter::new(d.default)))
+                .collect(),
             last_struct_hash: 0,
         }
     }
 
-    fn update(&mut self, p: &Preset) -> bool {
-        self.osc1_level.set(p.osc1.level);
-        self.osc1_octave.set(p.osc1.octave);
-        self.osc1_detune.set(p.osc1.detune);
-        self.osc2_level.set(p.osc2.level);
-        self.osc2_octave.set(p.osc2.octave);
-        self.osc2_detune.set(p.osc2.detune);
-        self.osc3_level.set(p.osc3.level);
-        self.osc3_octave.set(p.osc3.octave);
-        self.osc3_detune.set(p.osc3.detune);
-        self.noise_level.set(p.noise);
-        self.cutoff.set(p.filter.cutoff);
-        self.resonance.set(p.filter.resonance);
-        self.filter_env_amt.set(p.filter.env_amt);
-        self.filter_attack.set(p.filter.attack);
-        self.filter_decay.set(p.filter.decay);
-        self.filter_sustain.set(p.filter.sustain);
-        self.filter_release.set(p.filter.release);
-        self.amp_attack.set(p.amp.attack);
-        self.amp_decay.set(p.amp.decay);
-        self.amp_sustain.set(p.amp.sustain);
-        self.amp_release.set(p.amp.release);
-        self.delay_time.set(p.delay.time);
-        self.delay_feedback.set(p.delay.feedback);
-        self.delay_mix.set(p.delay.mix);
-        self.reverb_size.set(p.reverb.size);
-        self.reverb_damping.set(p.reverb.damping);
-        self.reverb_mix.set(p.reverb.mix);
-        self.lfo_freq.set(p.lfo.freq);
-        self.lfo_vib_amt.set(p.lfo.vib_amt);
-        self.lfo_filt_amt.set(p.lfo.filt_amt);
+    fn get(&self, id: ParamId) -> Parameter {
+        self.values
+            .iter()
+            .find(|(param_id, _)| *param_id == id)
+            .map(|(_, p)| p.clone())
+            .expect("every ParamId has a live parameter")
+    }
 
+    fn update(&mut self, p: &Preset) -> bool {
         let mut hash = 0u64;
-        hash = hash.wrapping_add(p.osc1.waveform as u64);
-        hash = hash.wrapping_add((p.osc2.waveform as u64) << 4);
-        hash = hash.wrapping_add((p.osc3.waveform as u64) << 8);
-        hash = hash.wrapping_add(if p.osc1.vibrato { 1 } else { 0 } << 12);
-        hash = hash.wrapping_add(if p.osc2.vibrato { 1 } else { 0 } << 13);
-        hash = hash.wrapping_add(if p.osc3.vibrato { 1 } else { 0 } << 14);
-        hash = hash.wrapping_add(if p.lfo_enabled { 1 } else { 0 } << 15);
-        hash = hash.wrapping_add((p.lfo.waveform as u64) << 16);
-        hash = hash.wrapping_add(if p.delay.enabled { 1 } else { 0 } << 20);
-        hash = hash.wrapping_add(if p.reverb.enabled { 1 } else { 0 } << 21);
+        for (desc, (_, param)) in PARAMS.iter().zip(&self.values) {
+            let value = p.param(desc.id);
+            param.set(value);
+            if desc.kind != ParamKind::Float {
+                hash = hash.wrapping_mul(31).wrapping_add(value as u64 + 1);
+            }
+        }
 
         let changed = hash != self.last_struct_hash;
         self.last_struct_hash = hash;
@@ -290,6 +574,7 @@ fn create_pitch(
     vibrato_enabled: bool,
     base_freq: impl FrameProcessor<Mono> + Send + 'static + Clone,
     vib: Option<FastLfo>,
+    pitch_mod: &PitchMod,
     sample_rate: f32,
 ) -> AudioParam {
     let mut chain = DspChain::new(base_freq, sample_rate);
@@ -308,179 +593,912 @@ fn create_pitch(
         }
     }
 
+    chain = chain.and(Gain::new(AudioParam::Dynamic(Box::new(
+        pitch_mod.bend.clone(),
+    ))));
+
+    let mut wheel_lfo = FastLfo::new(WHEEL_VIBRATO_RATE, FastLfoWaveform::Sine, sample_rate);
+    wheel_lfo.set_range(-WHEEL_VIBRATO_DEPTH, WHEEL_VIBRATO_DEPTH);
+    let wheel_vibrato = DspChain::new(wheel_lfo, sample_rate)
+        .and(Gain::new(AudioParam::Dynamic(Box::new(
+            pitch_mod.wheel.clone(),
+        ))))
+        .and(Offset::new_param(AudioParam::Static(1.0)));
+    chain = chain.and(Gain::new(AudioParam::Dynamic(Box::new(wheel_vibrato))));
+
     AudioParam::Dynamic(Box::new(chain))
 }
 
-pub struct AudioManager {
-    _stream: cpal::Stream,
-    // Fields kept alive by Arc clones in closures, but we hold them here to prevent drop
-    _freq_ctrl: PortamentoFreq,
-    _gate_ctrl: SharedValue,
-    params: LiveParams,
-    sender: crossbeam_channel::Sender<AudioCommand>,
-    pub scope_buffer: Arc<Mutex<Vec<f32>>>,
+// --- Engine ---
+
+/// Largest block the graph processes at once. Longer buffers are split, so
+/// the scratch buffers allocated while priming a graph always suffice.
+const MAX_BLOCK_FRAMES: usize = 512;
+
+type StereoGraph = Box<dyn FrameProcessor<Stereo> + Send>;
+
+/// A built graph and the fade that crossfades it with the one it replaces.
+struct Graph {
+    processor: StereoGraph,
+    fade: VoiceFade,
+}
+
+/// The voice controls with their allocator and envelopes. Replaced as a
+/// whole when the polyphony changes.
+struct VoiceSet {
+    controls: Vec<VoiceControl>,
+    allocator: VoiceAllocator,
+    envelopes: Vec<VoiceEnvelopes>,
 }
 
 enum AudioCommand {
-    NoteOn(f32),
-    NoteOff,
-    UpdatePreset(Box<Preset>),
-    RebuildVoice(Box<Preset>),
+    NoteOn(u8, u8),
+    NoteOff(u8),
+    SetMonoMode(NotePriority, bool),
+    PitchBend(f32),
+    ModWheel(f32),
+    /// A graph for the current voices, after a change to the preset's
+    /// structure.
+    SwapGraph(Graph),
+    /// New voices and their graph. Notes that are playing are cut.
+    SwapVoices(Box<VoiceSet>, Graph),
 }
 
-impl AudioManager {
-    pub fn new() -> Result<Self, anyhow::Error> {
-        let host = cpal::default_host();
-        let device = host
-            .default_output_device()
-            .ok_or(anyhow::anyhow!("No output device"))?;
+/// What the player replaced, handed back so it is freed off the audio
+/// thread. Only the live worker looks inside.
+#[cfg_attr(not(feature = "audio"), allow(dead_code))]
+enum Garbage {
+    Graph(Graph),
+    Voices(Box<VoiceSet>),
+}
 
-        // Get default config to determine channels
-        let default_config = device.default_output_config()?;
-        let channels = default_config.channels() as usize;
-        let config = default_config.config();
-        let sample_rate = config.sample_rate.0 as f32;
+/// Builds graphs for the player. Owned by a worker thread when playing live,
+/// since building allocates the whole graph.
+struct GraphBuilder {
+    sample_rate: f32,
+    preset: Preset,
+    params: LiveParams,
+    pitch_mod: PitchMod,
+    controls: Vec<VoiceControl>,
+}
 
-        let (tx, rx) = crossbeam_channel::bounded(16);
+impl GraphBuilder {
+    fn new(preset: &Preset, sample_rate: f32) -> Self {
+        let mut params = LiveParams::new();
+        params.update(preset);
+        let controls = vec![VoiceControl::new()];
+        controls[0].freq.set_portamento(preset.portamento);
+        Self {
+            sample_rate,
+            preset: preset.clone(),
+            params,
+            pitch_mod: PitchMod::new(),
+            controls,
+        }
+    }
 
-        let current_preset = Box::new(Preset::default());
+    /// A graph for the current controls. It is run once on a silent block
+    /// so its processors allocate their scratch buffers here rather than on
+    /// the audio thread.
+    fn build(&self) -> Graph {
+        let fade = VoiceFade::new(self.sample_rate);
+        let mut processor = build_engine(
+            &self.preset,
+            &self.params,
+            self.sample_rate,
+            &self.controls,
+            &self.pitch_mod,
+            &fade,
+        );
+        processor.process(&mut [0.0; MAX_BLOCK_FRAMES * 2], 0);
+        Graph { processor, fade }
+    }
 
-        let freq_ctrl = PortamentoFreq::new(440.0);
-        let gate_ctrl = SharedValue::new(0.0);
-        let mut params = LiveParams::new();
+    fn voice_set(&self) -> VoiceSet {
+        VoiceSet {
+            controls: self.controls.clone(),
+            allocator: VoiceAllocator::new(self.controls.len()),
+            envelopes: self
+                .controls
+                .iter()
+                .map(|c| VoiceEnvelopes::new(c, &self.params, self.sample_rate))
+                .collect(),
+        }
+    }
 
-        let freq_ctrl_clone = freq_ctrl.clone();
-        let gate_ctrl_clone = gate_ctrl.clone();
-        let params_clone = params.clone();
+    /// A player for the current voices, sharing the live parameters.
+    fn player(&self) -> Player {
+        let frames = |secs: f32| (secs * self.sample_rate) as usize;
+        Player {
+            params: self.params.clone(),
+            pitch_mod: self.pitch_mod.clone(),
+            voices: Box::new(self.voice_set()),
+            graph: self.build(),
+            tails: Vec::with_capacity(MAX_TAILS),
+            scratch: vec![0.0; MAX_BLOCK_FRAMES * 2],
+            tail_quiet_frames: frames(TAIL_QUIET_SECS),
+            max_tail_frames: frames(MAX_TAIL_SECS),
+            note_stack: NoteStack::default(),
+        }
+    }
 
-        params.update(&current_preset);
+    /// Updates the live parameters, returning a new graph if the preset's
+    /// structure changed.
+    fn set_preset(&mut self, preset: &Preset) -> Option<AudioCommand> {
+        let struct_changed = self.params.update(preset);
+        for c in &self.controls {
+            c.freq.set_portamento(preset.portamento);
+        }
+        self.preset = preset.clone();
+        struct_changed.then(|| AudioCommand::SwapGraph(self.build()))
+    }
 
-        let mut voice: Option<Box<dyn FrameProcessor<Stereo> + Send>> = Some(build_voice(
-            &current_preset,
-            &params_clone,
-            sample_rate,
-            freq_ctrl_clone.clone(),
-            gate_ctrl_clone.clone(),
-        ));
+    fn set_polyphony(&mut self, voices: usize) -> AudioCommand {
+        self.controls = (0..voices.clamp(1, MAX_POLYPHONY))
+            .map(|_| VoiceControl::new())
+            .collect();
+        for c in &self.controls {
+            c.freq.set_portamento(self.preset.portamento);
+        }
+        AudioCommand::SwapVoices(Box::new(self.voice_set()), self.build())
+    }
+}
 
-        let scope_buffer = Arc::new(Mutex::new(vec![0.0; 1024]));
-        let scope_buffer_clone = scope_buffer.clone();
+/// How long a replaced graph has to stay silent before it is dropped. Its
+/// delay line is 2 s long, so an echo can be on its way until then.
+const TAIL_QUIET_SECS: f32 = 2.0;
+/// Longest a replaced graph keeps ringing, e.g. with feedback close to 1.
+const MAX_TAIL_SECS: f32 = 8.0;
+/// Level under which a replaced graph counts as silent.
+const TAIL_SILENCE: f32 = 1e-4;
+/// Replaced graphs ringing out at once. Beyond that the oldest is cut.
+const MAX_TAILS: usize = 4;
+
+/// A replaced graph, faded out and left to ring until it is silent.
+struct Tail {
+    graph: Graph,
+    frames: usize,
+    quiet_frames: usize,
+}
 
-        let stream = device.build_output_stream(
-            &config,
-            move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-                while let Ok(cmd) = rx.try_recv() {
-                    match cmd {
-                        AudioCommand::UpdatePreset(p) => {
-                            freq_ctrl_clone.set_portamento(p.portamento);
-                            // params_clone is updated via shared atomics by main thread
-                        }
-                        AudioCommand::RebuildVoice(p) => {
-                            freq_ctrl_clone.set_portamento(p.portamento);
-                            let new_v = build_voice(
-                                &p,
-                                &params_clone,
-                                sample_rate,
-                                freq_ctrl_clone.clone(),
-                                gate_ctrl_clone.clone(),
-                            );
-                            voice = Some(new_v);
-                        }
-                        AudioCommand::NoteOn(freq) => {
-                            freq_ctrl_clone.set_target(freq);
-                            gate_ctrl_clone.set(1.0);
-                        }
-                        AudioCommand::NoteOff => {
-                            gate_ctrl_clone.set(0.0);
-                        }
-                    }
+/// The part of the engine that runs on the audio thread. Handling commands
+/// and processing neither lock nor allocate; whatever is replaced is passed
+/// to `retire`.
+struct Player {
+    params: LiveParams,
+    pitch_mod: PitchMod,
+    voices: Box<VoiceSet>,
+    graph: Graph,
+    tails: Vec<Tail>,
+    scratch: Vec<f32>,
+    tail_quiet_frames: usize,
+    max_tail_frames: usize,
+    note_stack: NoteStack,
+}
+
+impl Player {
+    fn handle(&mut self, cmd: AudioCommand, retire: &mut impl FnMut(Garbage)) {
+        let voices = &mut self.voices;
+        match cmd {
+            AudioCommand::SwapGraph(graph) => self.replace_graph(graph, retire),
+            AudioCommand::SwapVoices(mut new_voices, graph) => {
+                std::mem::swap(voices, &mut new_voices);
+                retire(Garbage::Voices(new_voices));
+                self.note_stack.clear();
+                self.replace_graph(graph, retire);
+            }
+            AudioCommand::SetMonoMode(priority, legato) => {
+                self.note_stack.priority = priority;
+                self.note_stack.legato = legato;
+            }
+            AudioCommand::NoteOn(note, velocity) if voices.controls.len() == 1 => {
+                let change = self.note_stack.note_on(note);
+                if change.is_some() {
+                    voices.controls[0].set_velocity(velocity, &self.params);
                 }
+                play_mono(&voices.controls[0], change);
+            }
+            AudioCommand::NoteOff(note) if voices.controls.len() == 1 => {
+                play_mono(&voices.controls[0], self.note_stack.note_off(note));
+            }
+            AudioCommand::NoteOn(note, velocity) => {
+                let v = &voices.controls[voices.allocator.note_on(note)];
+                v.set_velocity(velocity, &self.params);
+                v.freq.set_target(note_freq(note));
+                v.gate.open();
+            }
+            AudioCommand::NoteOff(note) => {
+                if let Some(index) = voices.allocator.note_off(note) {
+                    voices.controls[index].gate.close();
+                }
+            }
+            AudioCommand::PitchBend(ratio) => {
+                self.pitch_mod.bend.set(ratio);
+            }
+            AudioCommand::ModWheel(depth) => {
+                self.pitch_mod.wheel.set(depth);
+            }
+        }
+    }
 
-                if let Some(v) = &mut voice {
-                    if channels == 2 {
-                        v.process(data, 0);
-                    } else {
-                        for sample in data.iter_mut() {
-                            *sample = 0.0;
-                        }
-                    }
+    /// Crossfades to `graph` and keeps the old one as a tail.
+    fn replace_graph(&mut self, graph: Graph, retire: &mut impl FnMut(Garbage)) {
+        graph.fade.fade_in();
+        let old = std::mem::replace(&mut self.graph, graph);
+        old.fade.fade_out();
+        if self.tails.len() == MAX_TAILS {
+            retire(Garbage::Graph(self.tails.remove(0).graph));
+        }
+        self.tails.push(Tail {
+            graph: old,
+            frames: 0,
+            quiet_frames: 0,
+        });
+    }
+
+    /// Fills an interleaved stereo buffer.
+    fn process(&mut self, data: &mut [f32], retire: &mut impl FnMut(Garbage)) {
+        for block in data.chunks_mut(MAX_BLOCK_FRAMES * 2) {
+            let frames = block.len() / 2;
+            let voices = &mut self.voices;
+            for (control, envelopes) in voices.controls.iter().zip(&mut voices.envelopes) {
+                envelopes.run(control, frames);
+            }
+            self.graph.processor.process(block, 0);
+
+            let mut i = 0;
+            while i < self.tails.len() {
+                let tail = &mut self.tails[i];
+                let scratch = &mut self.scratch[..block.len()];
+                tail.graph.processor.process(scratch, 0);
+                let mut peak = 0.0f32;
+                for (out, sample) in block.iter_mut().zip(scratch.iter()) {
+                    *out += sample;
+                    peak = peak.max(sample.abs());
+                }
+
+                tail.frames += frames;
+                if peak < TAIL_SILENCE && tail.graph.fade.is_faded_out() {
+                    tail.quiet_frames += frames;
                 } else {
-                    for sample in data.iter_mut() {
-                        *sample = 0.0;
-                    }
+                    tail.quiet_frames = 0;
+                }
+                if tail.quiet_frames >= self.tail_quiet_frames
+                    || tail.frames >= self.max_tail_frames
+                {
+                    retire(Garbage::Graph(self.tails.remove(i).graph));
+                } else {
+                    i += 1;
                 }
+            }
+        }
+    }
+}
 
-                // Copy to scope buffer (rolling buffer)
-                if let Ok(mut scope) = scope_buffer_clone.try_lock() {
-                    let frames = data.len() / channels;
-                    let buffer_len = scope.len();
-
-                    if frames >= buffer_len {
-                        // New data fills the entire buffer
-                        for i in 0..buffer_len {
-                            // Take last 'buffer_len' frames from data
-                            let offset = frames - buffer_len;
-                            scope[i] = data[(offset + i) * channels]; // Take first channel
-                        }
-                    } else {
-                        // Shift existing data to the left
-                        scope.copy_within(frames.., 0);
-
-                        // Append new data at the end
-                        let start_index = buffer_len - frames;
-                        for i in 0..frames {
-                            scope[start_index + i] = data[i * channels]; // Take first channel
-                        }
-                    }
+/// The voices, their controls and the shared effect chain, driven directly
+/// on the calling thread. Used for rendering offline; `AudioManager` splits
+/// the same parts across the audio and a worker thread.
+pub struct Engine {
+    builder: GraphBuilder,
+    player: Player,
+}
+
+impl Engine {
+    /// A single voice engine playing `preset`.
+    pub fn new(preset: &Preset, sample_rate: f32) -> Self {
+        let builder = GraphBuilder::new(preset, sample_rate);
+        let player = builder.player();
+        Self { builder, player }
+    }
+
+    /// Replaced parts are simply dropped, as nothing here is real-time.
+    fn handle(&mut self, cmd: AudioCommand) {
+        self.player.handle(cmd, &mut drop);
+    }
+
+    pub fn note_on(&mut self, note: u8, velocity: u8) {
+        self.handle(AudioCommand::NoteOn(note, velocity));
+    }
+
+    pub fn note_off(&mut self, note: u8) {
+        self.handle(AudioCommand::NoteOff(note));
+    }
+
+    /// Rebuilds the engine with `voices` voices (clamped to
+    /// `MAX_POLYPHONY`). Notes that are playing are cut.
+    pub fn set_polyphony(&mut self, voices: usize) {
+        let cmd = self.builder.set_polyphony(voices);
+        self.handle(cmd);
+    }
+
+    /// How the engine picks the sounding note when it has a single voice.
+    pub fn set_mono_mode(&mut self, priority: NotePriority, legato: bool) {
+        self.handle(AudioCommand::SetMonoMode(priority, legato));
+    }
+
+    /// Takes the 14-bit pitch bend value, 8192 being centered.
+    pub fn pitch_bend(&mut self, value: u16) {
+        self.handle(AudioCommand::PitchBend(bend_ratio(value)));
+    }
+
+    pub fn mod_wheel(&mut self, value: u8) {
+        self.handle(AudioCommand::ModWheel(value as f32 / 127.0));
+    }
+
+    /// Switches to `preset`. If its structure differs, the voices crossfade
+    /// into a rebuilt graph while the old effects ring out.
+    pub fn set_preset(&mut self, preset: &Preset) {
+        if let Some(cmd) = self.builder.set_preset(preset) {
+            self.handle(cmd);
+        }
+    }
+
+    /// Fills an interleaved stereo buffer.
+    pub fn process(&mut self, data: &mut [f32]) {
+        self.player.process(data, &mut drop);
+    }
+}
+
+// --- Live Playback ---
+
+/// Commands queued for the audio thread. Senders never block, so the audio
+/// thread never has to wake one up.
+#[cfg(any(test, feature = "audio"))]
+const COMMAND_QUEUE: usize = 256;
+#[cfg(any(test, feature = "audio"))]
+const GARBAGE_QUEUE: usize = 64;
+/// How often the worker retries rebuilds the command queue had no room for.
+#[cfg(feature = "audio")]
+const RETRY_INTERVAL: std::time::Duration = std::time::Duration::from_millis(5);
+
+/// Rebuilds waiting for room in the command queue, oldest first. The builder
+/// has already moved on to them, so dropping one would leave the player on a
+/// graph the builder no longer knows about.
+#[cfg(any(test, feature = "audio"))]
+#[derive(Default)]
+struct Outbox {
+    commands: std::collections::VecDeque<AudioCommand>,
+}
+
+#[cfg(any(test, feature = "audio"))]
+impl Outbox {
+    fn push(&mut self, cmd: AudioCommand) {
+        // A graph for the same voices supersedes the one still waiting.
+        if let (Some(AudioCommand::SwapGraph(_)), AudioCommand::SwapGraph(_)) =
+            (self.commands.back(), &cmd)
+        {
+            self.commands.pop_back();
+        }
+        self.commands.push_back(cmd);
+    }
+
+    /// Sends what fits into `queue`.
+    fn flush(&mut self, queue: &Sender<AudioCommand>) {
+        while let Some(cmd) = self.commands.pop_front() {
+            match queue.try_send(cmd) {
+                Ok(()) => {}
+                Err(TrySendError::Full(cmd)) => {
+                    self.commands.push_front(cmd);
+                    break;
                 }
+                Err(TrySendError::Disconnected(_)) => self.commands.clear(),
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+}
+
+/// The latest left channel of the engine's output, for the oscilloscope. The audio
+/// thread writes it without locking; a reader may catch a block halfway
+/// through, which a scope display does not mind.
+#[cfg(any(test, feature = "audio"))]
+#[derive(Clone)]
+pub struct ScopeBuffer {
+    state: Arc<ScopeState>,
+}
+
+#[cfg(any(test, feature = "audio"))]
+struct ScopeState {
+    samples: Box<[AtomicU32]>,
+    /// Where the next sample goes, which is also the oldest one.
+    next: AtomicUsize,
+}
+
+#[cfg(any(test, feature = "audio"))]
+impl ScopeBuffer {
+    fn new(len: usize) -> Self {
+        Self {
+            state: Arc::new(ScopeState {
+                samples: (0..len).map(|_| AtomicU32::new(0)).collect(),
+                next: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// Appends the first channel of an interleaved buffer.
+    fn push(&self, data: &[f32], channels: usize) {
+        let samples = &self.state.samples;
+        let frames = data.len() / channels;
+        let mut next = self.state.next.load(Ordering::Relaxed);
+        for frame in data
+            .chunks_exact(channels)
+            .skip(frames.saturating_sub(samples.len()))
+        {
+            samples[next].store(frame[0].to_bits(), Ordering::Relaxed);
+            next = (next + 1) % samples.len();
+        }
+        self.state.next.store(next, Ordering::Release);
+    }
+
+    /// The samples, oldest first.
+    pub fn snapshot(&self) -> Vec<f32> {
+        let samples = &self.state.samples;
+        let next = self.state.next.load(Ordering::Acquire);
+        (0..samples.len())
+            .map(|i| f32::from_bits(samples[(next + i) % samples.len()].load(Ordering::Relaxed)))
+            .collect()
+    }
+}
+
+/// What the output stream's callback owns.
+#[cfg(any(test, feature = "audio"))]
+struct Callback {
+    player: Player,
+    commands: Receiver<AudioCommand>,
+    garbage: Sender<Garbage>,
+    scope: ScopeBuffer,
+    /// Where the engine renders for outputs that are not stereo.
+    stereo: Vec<f32>,
+}
+
+#[cfg(any(test, feature = "audio"))]
+impl Callback {
+    fn new(
+        player: Player,
+        commands: Receiver<AudioCommand>,
+        garbage: Sender<Garbage>,
+        scope: ScopeBuffer,
+    ) -> Self {
+        Self {
+            player,
+            commands,
+            garbage,
+            scope,
+            stereo: vec![0.0; MAX_BLOCK_FRAMES * 2],
+        }
+    }
+
+    fn run(&mut self, data: &mut [f32], channels: usize) {
+        // Only fails if the worker is stuck, in which case freeing here is
+        // the lesser evil.
+        let garbage = &self.garbage;
+        let mut retire = |old| {
+            let _ = garbage.try_send(old);
+        };
+        while let Ok(cmd) = self.commands.try_recv() {
+            self.player.handle(cmd, &mut retire);
+        }
+
+        if channels == 2 {
+            self.player.process(data, &mut retire);
+            self.scope.push(data, 2);
+            return;
+        }
+        for chunk in data.chunks_mut(MAX_BLOCK_FRAMES * channels) {
+            let stereo = &mut self.stereo[..chunk.len() / channels * 2];
+            self.player.process(stereo, &mut retire);
+            map_stereo(stereo, chunk, channels);
+            self.scope.push(stereo, 2);
+        }
+    }
+}
+
+/// Spreads interleaved stereo over `channels` outputs: a mono output gets
+/// the mix of both sides, wider ones get left and right on their first two
+/// channels and silence on the rest.
+#[cfg(any(test, feature = "audio"))]
+fn map_stereo(stereo: &[f32], out: &mut [f32], channels: usize) {
+    for (frame, lr) in out.chunks_exact_mut(channels).zip(stereo.chunks_exact(2)) {
+        match frame {
+            [mono] => *mono = 0.5 * (lr[0] + lr[1]),
+            [left, right, rest @ ..] => {
+                *left = lr[0];
+                *right = lr[1];
+                rest.fill(0.0);
+            }
+            [] => {}
+        }
+    }
+}
+
+#[cfg(feature = "audio")]
+enum BuildRequest {
+    Preset(Box<Preset>),
+    Polyphony(usize),
+}
+
+/// Builds graphs for the audio thread and frees the ones it replaced, until
+/// the `AudioManager` is dropped.
+#[cfg(feature = "audio")]
+fn run_builder(
+    mut builder: GraphBuilder,
+    requests: Receiver<BuildRequest>,
+    garbage: Receiver<Garbage>,
+    commands: Sender<AudioCommand>,
+) {
+    let mut outbox = Outbox::default();
+    loop {
+        let retry = if outbox.is_empty() {
+            crossbeam_channel::never()
+        } else {
+            crossbeam_channel::after(RETRY_INTERVAL)
+        };
+        crossbeam_channel::select! {
+            recv(requests) -> request => {
+                let cmd = match request {
+                    Ok(BuildRequest::Preset(preset)) => builder.set_preset(&preset),
+                    Ok(BuildRequest::Polyphony(voices)) => Some(builder.set_polyphony(voices)),
+                    Err(_) => break,
+                };
+                if let Some(cmd) = cmd {
+                    outbox.push(cmd);
+                }
+            }
+            recv(garbage) -> old => match old {
+                Ok(Garbage::Graph(graph)) => drop(graph),
+                Ok(Garbage::Voices(voices)) => drop(voices),
+                Err(_) => {}
             },
+            recv(retry) -> _ => {}
+        }
+        outbox.flush(&commands);
+    }
+}
+
+/// Which output `AudioManager` opens. `None` picks the system default.
+#[cfg(feature = "audio")]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AudioSettings {
+    /// A cpal host name, such as "ALSA" or "JACK".
+    pub host: Option<String>,
+    pub device: Option<String>,
+    pub sample_rate: Option<u32>,
+    /// Frames per callback.
+    pub buffer_frames: Option<u32>,
+}
+
+/// Rates offered when a device accepts a range.
+#[cfg(feature = "audio")]
+const SAMPLE_RATES: [u32; 6] = [22050, 32000, 44100, 48000, 88200, 96000];
+#[cfg(feature = "audio")]
+const BUFFER_FRAMES: [u32; 8] = [32, 64, 128, 256, 512, 1024, 2048, 4096];
+
+/// What can be picked for each `AudioSettings` field, given the host and
+/// device already picked.
+#[cfg(feature = "audio")]
+#[derive(Debug, Clone, Default)]
+pub struct OutputChoices {
+    pub hosts: Vec<String>,
+    pub devices: Vec<String>,
+    pub sample_rates: Vec<u32>,
+    pub buffer_frames: Vec<u32>,
+}
+
+#[cfg(feature = "audio")]
+impl OutputChoices {
+    /// Asks the system. Slow on some hosts, so callers should keep the result.
+    pub fn query(settings: &AudioSettings) -> Self {
+        let mut choices = Self {
+            hosts: cpal::available_hosts()
+                .iter()
+                .map(|id| id.name().to_string())
+                .collect(),
+            ..Default::default()
+        };
+        let Ok(host) = find_host(settings.host.as_deref()) else {
+            return choices;
+        };
+        if let Ok(devices) = host.output_devices() {
+            choices.devices = devices.filter_map(|d| d.name().ok()).collect();
+        }
+        let Ok(device) = find_device(&host, settings.device.as_deref()) else {
+            return choices;
+        };
+        let Ok(configs) = device.supported_output_configs() else {
+            return choices;
+        };
+        for config in configs.filter(|c| c.sample_format() == cpal::SampleFormat::F32) {
+            let (min, max) = (config.min_sample_rate().0, config.max_sample_rate().0);
+            choices
+                .sample_rates
+                .extend(SAMPLE_RATES.iter().filter(|&&r| (min..=max).contains(&r)));
+            let buffer_size = *config.buffer_size();
+            choices
+                .buffer_frames
+                .extend(BUFFER_FRAMES.iter().filter(|&&n| match buffer_size {
+                    cpal::SupportedBufferSize::Range { min, max } => (min..=max).contains(&n),
+                    cpal::SupportedBufferSize::Unknown => true,
+                }));
+        }
+        choices.sample_rates.sort_unstable();
+        choices.sample_rates.dedup();
+        choices.buffer_frames.sort_unstable();
+        choices.buffer_frames.dedup();
+        choices
+    }
+}
+
+/// The output an `AudioManager` ended up with.
+#[cfg(feature = "audio")]
+#[derive(Debug, Clone)]
+pub struct OutputInfo {
+    pub host: String,
+    pub device: String,
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// `None` when the host picks.
+    pub buffer_frames: Option<u32>,
+}
+
+#[cfg(feature = "audio")]
+impl std::fmt::Display for OutputInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} ({}), {} Hz, {} ch",
+            self.device, self.host, self.sample_rate, self.channels
+        )?;
+        if let Some(frames) = self.buffer_frames {
+            write!(f, ", {} frames", frames)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "audio")]
+fn find_host(name: Option<&str>) -> Result<cpal::Host, anyhow::Error> {
+    let Some(name) = name else {
+        return Ok(cpal::default_host());
+    };
+    let id = cpal::available_hosts()
+        .into_iter()
+        .find(|id| id.name() == name)
+        .ok_or(anyhow::anyhow!("Audio host {} is not available", name))?;
+    Ok(cpal::host_from_id(id)?)
+}
+
+#[cfg(feature = "audio")]
+fn find_device(host: &cpal::Host, name: Option<&str>) -> Result<cpal::Device, anyhow::Error> {
+    match name {
+        None => host
+            .default_output_device()
+            .ok_or(anyhow::anyhow!("No output device")),
+        Some(name) => host
+            .output_devices()?
+            .find(|d| d.name().ok().as_deref() == Some(name))
+            .ok_or(anyhow::anyhow!("Output device {} not found", name)),
+    }
+}
+
+#[cfg(feature = "audio")]
+fn stream_config(
+    device: &cpal::Device,
+    settings: &AudioSettings,
+) -> Result<cpal::StreamConfig, anyhow::Error> {
+    let mut config = match settings.sample_rate {
+        None => device.default_output_config()?.config(),
+        Some(rate) => device
+            .supported_output_configs()?
+            .filter(|c| c.sample_format() == cpal::SampleFormat::F32)
+            .find(|c| (c.min_sample_rate().0..=c.max_sample_rate().0).contains(&rate))
+            .ok_or(anyhow::anyhow!("{} Hz is not supported", rate))?
+            .with_sample_rate(cpal::SampleRate(rate))
+            .config(),
+    };
+    if let Some(frames) = settings.buffer_frames {
+        config.buffer_size = cpal::BufferSize::Fixed(frames);
+    }
+    Ok(config)
+}
+
+/// Plays the engine on an output device. The UI thread sends notes
+/// straight to the audio thread and preset and polyphony changes to a
+/// worker, which builds new graphs and swaps them in. Dropping it closes the
+/// device, so another one can be opened in its place.
+#[cfg(feature = "audio")]
+pub struct AudioManager {
+    _stream: cpal::Stream,
+    commands: Sender<AudioCommand>,
+    requests: Sender<BuildRequest>,
+    pub scope_buffer: ScopeBuffer,
+    pub output: OutputInfo,
+}
+
+#[cfg(feature = "audio")]
+impl AudioManager {
+    /// Opens the output in `settings`. The engine is mixed in stereo and
+    /// spread over however many channels the device has.
+    pub fn new(settings: &AudioSettings) -> Result<Self, anyhow::Error> {
+        let host = find_host(settings.host.as_deref())?;
+        let device = find_device(&host, settings.device.as_deref())?;
+        let config = stream_config(&device, settings)?;
+        let channels = config.channels as usize;
+        let sample_rate = config.sample_rate.0 as f32;
+        let output = OutputInfo {
+            host: host.id().name().to_string(),
+            device: device.name()?,
+            sample_rate: config.sample_rate.0,
+            channels: config.channels,
+            buffer_frames: match config.buffer_size {
+                cpal::BufferSize::Fixed(frames) => Some(frames),
+                cpal::BufferSize::Default => None,
+            },
+        };
+
+        let (commands, commands_rx) = crossbeam_channel::bounded(COMMAND_QUEUE);
+        let (garbage_tx, garbage) = crossbeam_channel::bounded(GARBAGE_QUEUE);
+        let (requests, requests_rx) = crossbeam_channel::unbounded();
+
+        let builder = GraphBuilder::new(&Preset::default(), sample_rate);
+        let scope_buffer = ScopeBuffer::new(1024);
+        let mut callback = Callback::new(
+            builder.player(),
+            commands_rx,
+            garbage_tx,
+            scope_buffer.clone(),
+        );
+
+        let stream = device.build_output_stream(
+            &config,
+            move |data: &mut [f32], _: &cpal::OutputCallbackInfo| callback.run(data, channels),
             |err| eprintln!("Stream error: {}", err),
             None,
         )?;
 
+        let builder_commands = commands.clone();
+        std::thread::Builder::new()
+            .name("picoedit-graph-builder".into())
+            .spawn(move || run_builder(builder, requests_rx, garbage, builder_commands))?;
+
         stream.play()?;
 
         Ok(Self {
             _stream: stream,
-            _freq_ctrl: freq_ctrl,
-            _gate_ctrl: gate_ctrl,
-            params,
-            sender: tx,
+            commands,
+            requests,
             scope_buffer,
+            output,
         })
     }
 
-    pub fn note_on(&self, note: u8) {
-        let freq = 440.0 * 2.0f32.powf((note as f32 - 69.0) / 12.0);
-        let _ = self.sender.send(AudioCommand::NoteOn(freq));
+    fn send(&self, cmd: AudioCommand) {
+        if self.commands.try_send(cmd).is_err() {
+            log::warn!("Audio command queue full, dropping a command");
+        }
     }
 
-    pub fn note_off(&self) {
-        let _ = self.sender.send(AudioCommand::NoteOff);
+    pub fn note_on(&self, note: u8, velocity: u8) {
+        self.send(AudioCommand::NoteOn(note, velocity));
     }
 
-    pub fn update_preset(&mut self, preset: &Preset) {
-        let struct_changed = self.params.update(preset);
+    pub fn note_off(&self, note: u8) {
+        self.send(AudioCommand::NoteOff(note));
+    }
 
-        if struct_changed {
-            let _ = self
-                .sender
-                .send(AudioCommand::RebuildVoice(Box::new(preset.clone())));
-        } else {
-            let _ = self
-                .sender
-                .send(AudioCommand::UpdatePreset(Box::new(preset.clone())));
-        }
+    /// Rebuilds the engine with `voices` voices (clamped to
+    /// `MAX_POLYPHONY`). Notes that are playing are cut.
+    pub fn set_polyphony(&self, voices: usize) {
+        let _ = self.requests.send(BuildRequest::Polyphony(voices));
+    }
+
+    /// Takes the 14-bit pitch bend value, 8192 being centered.
+    pub fn pitch_bend(&self, value: u16) {
+        self.send(AudioCommand::PitchBend(bend_ratio(value)));
+    }
+
+    pub fn mod_wheel(&self, value: u8) {
+        self.send(AudioCommand::ModWheel(value as f32 / 127.0));
+    }
+
+    /// How the engine picks the sounding note when it has a single voice.
+    pub fn set_mono_mode(&self, priority: NotePriority, legato: bool) {
+        self.send(AudioCommand::SetMonoMode(priority, legato));
+    }
+
+    pub fn update_preset(&self, preset: &Preset) {
+        let _ = self
+            .requests
+            .send(BuildRequest::Preset(Box::new(preset.clone())));
     }
 }
 
-fn build_voice(
+/// Builds one voice per control and mixes them, through `fade`, into the
+/// shared delay, reverb and stereo stage.
+fn build_engine(
     preset: &Preset,
     params: &LiveParams,
     sample_rate: f32,
-    freq_ctrl: PortamentoFreq,
-    gate_ctrl: SharedValue,
+    voices: &[VoiceControl],
+    pitch_mod: &PitchMod,
+    fade: &VoiceFade,
 ) -> Box<dyn FrameProcessor<Stereo> + Send> {
+    let voice_mix = SummingMixer::new(
+        voices
+            .iter()
+            .map(|v| build_voice(preset, params, sample_rate, v, pitch_mod))
+            .collect(),
+    );
+    // Keeps a full chord roughly at the level of a single voice.
+    let voice_gain = 1.0 / (voices.len() as f32).sqrt();
+    let voices = DspChain::new(voice_mix, sample_rate).and(Gain::new_fixed(voice_gain));
+    let voice_sum = DspChain::new(
+        FadedVoices {
+            voices,
+            fade: fade.clone(),
+        },
+        sample_rate,
+    );
+
+    let mut chain: Box<dyn FrameProcessor<Stereo> + Send> = Box::new(voice_sum.to_stereo());
+
+    if preset.delay.enabled {
+        let d = &preset.delay;
+        let time_l = d.time;
+        let time_r = d.time * 1.15;
+
+        let delay_l = Delay::new(
+            2.0,
+            AudioParam::Static(time_l),
+            AudioParam::Linked(params.get(ParamId::DelayFeedback)),
+            AudioParam::Linked(params.get(ParamId::DelayMix)),
+        );
+        let delay_r = Delay::new(
+            2.0,
+            AudioParam::Static(time_r),
+            AudioParam::Linked(params.get(ParamId::DelayFeedback)),
+            AudioParam::Linked(params.get(ParamId::DelayMix)),
+        );
+
+        chain = Box::new(
+            DspChain::new(chain, sample_rate)
+                .and(ParallelMixer::new(1.0, DualMono::new(delay_l, delay_r))),
+        );
+    }
+
+    if preset.reverb.enabled {
+        let reverb = Reverb::new_with_params(
+            AudioParam::Linked(params.get(ParamId::ReverbSize)),
+            AudioParam::Linked(params.get(ParamId::ReverbDamping)),
+            0,
+        );
+        chain = Box::new(
+            DspChain::new(chain, sample_rate)
+                .and_mix_param(AudioParam::Linked(params.get(ParamId::ReverbMix)), reverb),
+        );
+    }
+
+    let widener = StereoWidener::new(AudioParam::Static(1.5));
+    chain = Box::new(
+        DspChain::new(chain, sample_rate)
+            .and(widener)
+            .and(Gain::new_fixed(0.5)),
+    );
+
+    chain
+}
+
+fn build_voice(
+    preset: &Preset,
+    params: &LiveParams,
+    sample_rate: f32,
+    control: &VoiceControl,
+    pitch_mod: &PitchMod,
+) -> Box<dyn FrameProcessor<Mono> + Send> {
+    let freq_ctrl = control.freq.clone();
+
     let (vibrato_node, filter_lfo_node) = if preset.lfo_enabled {
         let p = &preset.lfo;
         let mut lfo_vib = FastLfo::new(p.freq, map_lfo_waveform(p.waveform), sample_rate);
@@ -509,10 +1527,11 @@ fn build_voice(
     let osc1_node = Oscillator::new(
         create_pitch(
             &preset.osc1,
-            params.osc1_detune.clone(),
+            params.get(ParamId::Osc1Detune),
             preset.osc1.vibrato,
             freq_ctrl.clone(),
             osc1_vib,
+            pitch_mod,
             sample_rate,
         ),
         map_waveform(preset.osc1.waveform),
@@ -520,10 +1539,11 @@ fn build_voice(
     let osc2_node = Oscillator::new(
         create_pitch(
             &preset.osc2,
-            params.osc2_detune.clone(),
+            params.get(ParamId::Osc2Detune),
             preset.osc2.vibrato,
             freq_ctrl.clone(),
             osc2_vib,
+            pitch_mod,
             sample_rate,
         ),
         map_waveform(preset.osc2.waveform),
@@ -531,24 +1551,28 @@ fn build_voice(
     let osc3_node = Oscillator::new(
         create_pitch(
             &preset.osc3,
-            params.osc3_detune.clone(),
+            params.get(ParamId::Osc3Detune),
             preset.osc3.vibrato,
             freq_ctrl.clone(),
             osc3_vib,
+            pitch_mod,
             sample_rate,
         ),
         map_waveform(preset.osc3.waveform),
     );
     let noise_node = Oscillator::new(AudioParam::Static(0.0), CoreWaveform::WhiteNoise);
 
-    let osc1_gained = DspChain::new(osc1_node, sample_rate)
-        .and(Gain::new(AudioParam::Linked(params.osc1_level.clone())));
-    let osc2_gained = DspChain::new(osc2_node, sample_rate)
-        .and(Gain::new(AudioParam::Linked(params.osc2_level.clone())));
-    let osc3_gained = DspChain::new(osc3_node, sample_rate)
-        .and(Gain::new(AudioParam::Linked(params.osc3_level.clone())));
+    let osc1_gained = DspChain::new(osc1_node, sample_rate).and(Gain::new(AudioParam::Linked(
+        params.get(ParamId::Osc1Level),
+    )));
+    let osc2_gained = DspChain::new(osc2_node, sample_rate).and(Gain::new(AudioParam::Linked(
+        params.get(ParamId::Osc2Level),
+    )));
+    let osc3_gained = DspChain::new(osc3_node, sample_rate).and(Gain::new(AudioParam::Linked(
+        params.get(ParamId::Osc3Level),
+    )));
     let noise_gained = DspChain::new(noise_node, sample_rate)
-        .and(Gain::new(AudioParam::Linked(params.noise_level.clone())));
+        .and(Gain::new(AudioParam::Linked(params.get(ParamId::Noise))));
 
     let mixer = SummingMixer::new(vec![
         Box::new(osc1_gained),
@@ -557,88 +1581,274 @@ fn build_voice(
         Box::new(noise_gained),
     ]);
 
-    let filter_env = Adsr::new(
-        AudioParam::Dynamic(Box::new(gate_ctrl.clone())),
-        AudioParam::Linked(params.filter_attack.clone()),
-        AudioParam::Linked(params.filter_decay.clone()),
-        AudioParam::Linked(params.filter_sustain.clone()),
-        AudioParam::Linked(params.filter_release.clone()),
-    );
-
     let mut cutoff_mod_chain = DspChain::new(SharedValue::new(0.0), sample_rate)
-        .and(Offset::new_param(AudioParam::Linked(params.cutoff.clone())))
+        .and(Offset::new_param(AudioParam::Linked(
+            params.get(ParamId::FilterCutoff),
+        )))
         .and(Sum::new(AudioParam::Dynamic(Box::new(
-            DspChain::new(filter_env, sample_rate)
-                .and(Gain::new(AudioParam::Linked(params.filter_env_amt.clone()))),
+            DspChain::new(control.filter_env.clone(), sample_rate)
+                .and(Gain::new(AudioParam::Linked(
+                    params.get(ParamId::FilterEnvAmt),
+                )))
+                .and(Gain::new(AudioParam::Dynamic(Box::new(
+                    control.filter_velocity.clone(),
+                )))),
         ))));
 
     if let Some(lfo) = filter_lfo_node {
-        let lfo_chain = DspChain::new(lfo, sample_rate)
-            .and(Gain::new(AudioParam::Linked(params.lfo_filt_amt.clone())));
+        let lfo_chain = DspChain::new(lfo, sample_rate).and(Gain::new(AudioParam::Linked(
+            params.get(ParamId::LfoFiltAmt),
+        )));
         cutoff_mod_chain = cutoff_mod_chain.and(Sum::new(AudioParam::Dynamic(Box::new(lfo_chain))));
     }
 
     let filter_node = PredictiveLadderFilter::new(
         AudioParam::Dynamic(Box::new(cutoff_mod_chain)),
-        AudioParam::Linked(params.resonance.clone()),
+        AudioParam::Linked(params.get(ParamId::FilterResonance)),
     );
 
-    let amp_env = Adsr::new(
-        AudioParam::Dynamic(Box::new(gate_ctrl)),
-        AudioParam::Linked(params.amp_attack.clone()),
-        AudioParam::Linked(params.amp_decay.clone()),
-        AudioParam::Linked(params.amp_sustain.clone()),
-        AudioParam::Linked(params.amp_release.clone()),
-    );
+    let amp_level = DspChain::new(control.amp_env.clone(), sample_rate).and(Gain::new(
+        AudioParam::Dynamic(Box::new(control.amp_velocity.clone())),
+    ));
+    let vca = Gain::new(AudioParam::Dynamic(Box::new(amp_level)));
 
-    let vca = Gain::new(AudioParam::Dynamic(Box::new(amp_env)));
+    Box::new(DspChain::new(mixer, sample_rate).and(filter_node).and(vca))
+}
 
-    let voice = DspChain::new(mixer, sample_rate).and(filter_node).and(vca);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
 
-    let mut chain: Box<dyn FrameProcessor<Stereo> + Send> = Box::new(voice.to_stereo());
+    /// Counts allocations and frees on threads that armed it, so the
+    /// callback can be checked while other tests run in parallel.
+    struct CountingAllocator;
 
-    if preset.delay.enabled {
-        let d = &preset.delay;
-        let time_l = d.time;
-        let time_r = d.time * 1.15;
+    thread_local! {
+        static ARMED: Cell<bool> = const { Cell::new(false) };
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
 
-        let delay_l = Delay::new(
-            2.0,
-            AudioParam::Static(time_l),
-            AudioParam::Linked(params.delay_feedback.clone()),
-            AudioParam::Linked(params.delay_mix.clone()),
-        );
-        let delay_r = Delay::new(
-            2.0,
-            AudioParam::Static(time_r),
-            AudioParam::Linked(params.delay_feedback.clone()),
-            AudioParam::Linked(params.delay_mix.clone()),
-        );
+    fn count() {
+        let _ = ARMED.try_with(|armed| {
+            if armed.get() {
+                ALLOCATIONS.with(|n| n.set(n.get() + 1));
+            }
+        });
+    }
 
-        chain = Box::new(
-            DspChain::new(chain, sample_rate)
-                .and(ParallelMixer::new(1.0, DualMono::new(delay_l, delay_r))),
-        );
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            count();
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            count();
+            System.dealloc(ptr, layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            count();
+            System.realloc(ptr, layout, new_size)
+        }
     }
 
-    if preset.reverb.enabled {
-        let reverb = Reverb::new_with_params(
-            AudioParam::Linked(params.reverb_size.clone()),
-            AudioParam::Linked(params.reverb_damping.clone()),
-            0,
-        );
-        chain = Box::new(
-            DspChain::new(chain, sample_rate)
-                .and_mix_param(AudioParam::Linked(params.reverb_mix.clone()), reverb),
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    /// How often `f` allocated or freed memory.
+    fn allocations(f: impl FnOnce()) -> usize {
+        ALLOCATIONS.with(|n| n.set(0));
+        ARMED.with(|armed| armed.set(true));
+        f();
+        ARMED.with(|armed| armed.set(false));
+        ALLOCATIONS.with(|n| n.get())
+    }
+
+    #[test]
+    fn callback_does_not_allocate() {
+        let mut preset = Preset {
+            lfo_enabled: true,
+            ..Default::default()
+        };
+        preset.delay.enabled = true;
+        preset.reverb.enabled = true;
+
+        let mut builder = GraphBuilder::new(&preset, 48000.0);
+        let (commands, commands_rx) = crossbeam_channel::bounded(COMMAND_QUEUE);
+        let (garbage_tx, garbage) = crossbeam_channel::bounded(GARBAGE_QUEUE);
+        let mut callback = Callback::new(
+            builder.player(),
+            commands_rx,
+            garbage_tx,
+            ScopeBuffer::new(1024),
         );
+        // Longer than a graph block, as some hosts ask for.
+        let mut data = vec![0.0; MAX_BLOCK_FRAMES * 3];
+        let mut peak = 0.0f32;
+        let mut run = |callback: &mut Callback, channels: usize| {
+            let n = allocations(|| callback.run(&mut data, channels));
+            peak = data.iter().fold(peak, |m, s| m.max(s.abs()));
+            n
+        };
+
+        commands.send(AudioCommand::NoteOn(60, 100)).unwrap();
+        commands.send(AudioCommand::NoteOn(64, 90)).unwrap();
+        commands
+            .send(AudioCommand::PitchBend(bend_ratio(12000)))
+            .unwrap();
+        commands.send(AudioCommand::ModWheel(0.5)).unwrap();
+        assert_eq!(run(&mut callback, 2), 0);
+
+        // Rebuilds prepared off the audio thread are swapped in as they are.
+        commands.send(builder.set_polyphony(4)).unwrap();
+        commands.send(AudioCommand::NoteOn(60, 100)).unwrap();
+        commands.send(AudioCommand::NoteOn(67, 100)).unwrap();
+        assert_eq!(run(&mut callback, 2), 0);
+
+        preset.osc2.waveform = Waveform::Square;
+        let swap = builder.set_preset(&preset).expect("structure changed");
+        commands.send(swap).unwrap();
+        commands.send(AudioCommand::NoteOff(60)).unwrap();
+        for _ in 0..4 {
+            assert_eq!(run(&mut callback, 2), 0);
+        }
+        // Other channel counts go through the stereo scratch buffer.
+        assert_eq!(run(&mut callback, 1), 0);
+        assert_eq!(run(&mut callback, 6), 0);
+
+        assert!(peak > 0.01, "peak {}", peak);
+        // The old voices are freed at once, the old graphs ring out first.
+        assert_eq!(garbage.len(), 1);
+        assert_eq!(callback.player.tails.len(), 2);
     }
 
-    let widener = StereoWidener::new(AudioParam::Static(1.5));
-    chain = Box::new(
-        DspChain::new(chain, sample_rate)
-            .and(widener)
-            .and(Gain::new_fixed(0.5)),
-    );
+    const RATE: f32 = 22050.0;
 
-    chain
+    fn render(engine: &mut Engine, secs: f32) -> Vec<f32> {
+        let mut data = vec![0.0; (secs * RATE) as usize * 2];
+        engine.process(&mut data);
+        data
+    }
+
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn rebuild_keeps_the_held_note() {
+        let mut preset = Preset::default();
+        // A restarted envelope would still be near the start of its attack.
+        preset.amp.attack = 0.2;
+        let mut engine = Engine::new(&preset, RATE);
+        engine.note_on(57, 100);
+        render(&mut engine, 0.5);
+        let before = rms(&render(&mut engine, 0.05));
+
+        preset.osc1.vibrato = !preset.osc1.vibrato;
+        engine.set_preset(&preset);
+        let after = rms(&render(&mut engine, 0.05));
+        assert!(after / before > 0.6, "level {} -> {}", before, after);
+    }
+
+    #[test]
+    fn disabled_reverb_rings_out() {
+        let mut preset = Preset::default();
+        preset.reverb.enabled = true;
+        preset.reverb.size = 0.8;
+        preset.reverb.mix = 0.5;
+        let mut engine = Engine::new(&preset, RATE);
+        engine.note_on(60, 127);
+        render(&mut engine, 0.3);
+        engine.note_off(60);
+
+        preset.reverb.enabled = false;
+        engine.set_preset(&preset);
+        let out = render(&mut engine, 0.6);
+        // The amp release is 0.1 s, by the end only the old reverb is left.
+        let tail = &out[out.len() * 3 / 4..];
+        assert!(rms(tail) > 1e-3, "tail {}", rms(tail));
+    }
+
+    #[test]
+    fn rebuilds_wait_for_room_in_the_queue() {
+        let mut builder = GraphBuilder::new(&Preset::default(), RATE);
+        let (commands, commands_rx) = crossbeam_channel::bounded(1);
+        let mut outbox = Outbox::default();
+
+        outbox.push(builder.set_polyphony(4));
+        let mut preset = Preset::default();
+        for waveform in [Waveform::Square, Waveform::Sine] {
+            preset.osc1.waveform = waveform;
+            outbox.push(builder.set_preset(&preset).expect("structure changed"));
+        }
+        outbox.flush(&commands);
+        assert!(matches!(
+            commands_rx.try_recv(),
+            Ok(AudioCommand::SwapVoices(..))
+        ));
+
+        // Only the latest graph is still waiting.
+        outbox.flush(&commands);
+        assert!(outbox.is_empty());
+        assert!(matches!(
+            commands_rx.try_recv(),
+            Ok(AudioCommand::SwapGraph(_))
+        ));
+        assert!(commands_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn stereo_maps_to_any_channel_count() {
+        let stereo = [1.0, 0.5, -1.0, 0.0];
+        let mut mono = [9.0; 2];
+        map_stereo(&stereo, &mut mono, 1);
+        assert_eq!(mono, [0.75, -0.5]);
+        let mut quad = [9.0; 8];
+        map_stereo(&stereo, &mut quad, 4);
+        assert_eq!(quad, [1.0, 0.5, 0.0, 0.0, -1.0, 0.0, 0.0, 0.0]);
+    }
+
+    /// Counts the samples it is asked for.
+    struct Counter(Arc<AtomicUsize>);
+
+    impl FrameProcessor<Mono> for Counter {
+        fn process(&mut self, buffer: &mut [f32], _frame_index: u64) {
+            self.0.fetch_add(buffer.len(), Ordering::Relaxed);
+            buffer.fill(1.0);
+        }
+    }
+
+    #[test]
+    fn faded_out_voices_stop_running() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let fade = VoiceFade::new(RATE);
+        let mut voices = FadedVoices {
+            voices: DspChain::new(Counter(count.clone()), RATE),
+            fade: fade.clone(),
+        };
+        let mut block = [0.0; MAX_BLOCK_FRAMES];
+        fade.fade_out();
+        let fade_frames = (CROSSFADE_SECS * RATE) as usize + 1;
+        for _ in 0..fade_frames.div_ceil(MAX_BLOCK_FRAMES) {
+            voices.process(&mut block, 0);
+        }
+        assert!(fade.is_faded_out());
+        let ran = count.load(Ordering::Relaxed);
+
+        voices.process(&mut block, 0);
+        assert_eq!(count.load(Ordering::Relaxed), ran);
+        assert!(block.iter().all(|&s| s == 0.0));
+    }
+
+    #[test]
+    fn scope_keeps_the_latest_first_channel() {
+        let scope = ScopeBuffer::new(4);
+        scope.push(&[1.0, -1.0, 2.0, -2.0, 3.0, -3.0], 2);
+        assert_eq!(scope.snapshot(), vec![0.0, 1.0, 2.0, 3.0]);
+        scope.push(&[4.0, 0.0, 5.0, 0.0, 6.0, 0.0, 7.0, 0.0, 8.0, 0.0], 2);
+        assert_eq!(scope.snapshot(), vec![5.0, 6.0, 7.0, 8.0]);
+    }
 }
@@ -0,0 +1,469 @@
+//! Headless companion to the editor, for scripting lab benches and CI.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context};
+use crossbeam_channel::Receiver;
+
+use picoedit::preset_doc::{self, DocFormat};
+use picoedit::protocol::*;
+use picoedit::read_session::{self, ReadSession, ReadStep, ReadTarget};
+use picoedit::render::{self, RenderNote, WavFormat};
+use picoedit::syx::{self, SyxLayout};
+use picoedit::transport::{MidiTransport, MidirTransport};
+use picoedit::write_session::{Step, WriteSession, WriteTarget};
+
+const USAGE: &str = "\
+Usage: picoedit-cli <command> [options]
+
+Commands:
+  ports                    List MIDI input and output ports
+  dump <file>              Read the bank from the device into a file
+  write <file>             Write a bank file to the device
+  convert <in> <out>       Convert a bank between .pdsp, .syx, .json and .toml
+  info <file>              Print a summary of a bank file
+  render <file> <out.wav>  Render a preset of a bank file to WAV
+
+Options:
+  --port <name>     MIDI port, matched by substring (default: picodsp)
+  --timeout <secs>  Reply timeout for device requests (default: 2)
+  --retries <n>     Retries for device requests (default: 2)
+  --version <n>     Format version to write (default: 7)
+  --per-preset      Write .syx files as one message per preset
+  --crc             Append a CRC to written SysEx
+  --verify          Read the bank back after writing and compare it
+  --preset <n>      Preset to render, starting at 1 (default: 1)
+  --all             Render every preset to <out>-NN.wav
+  --notes <list>    Notes to render as note:seconds[:velocity], comma
+                    separated (default: 60:1:100)
+  --rate <hz>       Sample rate (default: 48000)
+  --tail <secs>     Time rendered after the last note (default: 2)
+  --float           Write 32-bit float instead of 24-bit WAV";
+
+/// How long the device loops wait for input between timeout checks.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+struct Options {
+    args: Vec<String>,
+    port: String,
+    timeout: Duration,
+    retries: u32,
+    version: u32,
+    layout: SyxLayout,
+    checksum: bool,
+    verify: bool,
+    preset: usize,
+    all: bool,
+    notes: Vec<RenderNote>,
+    sample_rate: u32,
+    tail: f32,
+    wav_format: WavFormat,
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> anyhow::Result<Self> {
+        let mut opts = Options {
+            args: Vec::new(),
+            port: "picodsp".to_string(),
+            timeout: read_session::DEFAULT_TIMEOUT,
+            retries: read_session::DEFAULT_RETRIES,
+            version: DEFAULT_WRITE_VERSION,
+            layout: SyxLayout::Dump,
+            checksum: false,
+            verify: false,
+            preset: 1,
+            all: false,
+            notes: vec![RenderNote {
+                note: 60,
+                velocity: 100,
+                duration: 1.0,
+            }],
+            sample_rate: 48000,
+            tail: 2.0,
+            wav_format: WavFormat::Pcm24,
+        };
+
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| anyhow!("{} needs a value", arg));
+            match arg.as_str() {
+                "--port" => opts.port = value()?,
+                "--timeout" => opts.timeout = seconds(&value()?)?,
+                "--retries" => opts.retries = number(&value()?)?,
+                "--version" => {
+                    opts.version = number(&value()?)?;
+                    if !SUPPORTED_VERSIONS.contains(&opts.version) {
+                        bail!("unsupported format version {}", opts.version);
+                    }
+                }
+                "--per-preset" => opts.layout = SyxLayout::PerPreset,
+                "--crc" => opts.checksum = true,
+                "--verify" => opts.verify = true,
+                "--preset" => opts.preset = number(&value()?)?,
+                "--all" => opts.all = true,
+                "--notes" => opts.notes = parse_notes(&value()?)?,
+                "--rate" => opts.sample_rate = number(&value()?)?,
+                "--tail" => opts.tail = seconds(&value()?)?.as_secs_f32(),
+                "--float" => opts.wav_format = WavFormat::Float32,
+                "-h" | "--help" => {
+                    println!("{}", USAGE);
+                    std::process::exit(0);
+                }
+                flag if flag.starts_with("--") => bail!("unknown option {}", flag),
+                _ => opts.args.push(arg),
+            }
+        }
+        Ok(opts)
+    }
+
+    /// The positional arguments after the command, which must be `count`.
+    fn files(&self, count: usize) -> anyhow::Result<Vec<PathBuf>> {
+        if self.args.len() != count + 1 {
+            bail!(
+                "{} takes {} file argument(s)\n\n{}",
+                self.args[0],
+                count,
+                USAGE
+            );
+        }
+        Ok(self.args[1..].iter().map(PathBuf::from).collect())
+    }
+}
+
+fn number<T: std::str::FromStr>(text: &str) -> anyhow::Result<T> {
+    text.parse()
+        .map_err(|_| anyhow!("'{}' is not a valid number", text))
+}
+
+/// Parses a time in seconds, rejecting negative, infinite and NaN values.
+fn seconds(text: &str) -> anyhow::Result<Duration> {
+    Duration::try_from_secs_f32(number(text)?)
+        .map_err(|_| anyhow!("'{}' is not a valid number of seconds", text))
+}
+
+/// Parses `note:seconds[:velocity]` entries, e.g. `60:1,64:0.5:80`.
+fn parse_notes(text: &str) -> anyhow::Result<Vec<RenderNote>> {
+    text.split(',')
+        .map(|entry| {
+            let parts: Vec<&str> = entry.split(':').collect();
+            let (note, duration, velocity) = match parts[..] {
+                [note, duration] => (note, duration, "100"),
+                [note, duration, velocity] => (note, duration, velocity),
+                _ => bail!("note '{}' is not note:seconds[:velocity]", entry),
+            };
+            let note: u8 = number(note)?;
+            let velocity: u8 = number(velocity)?;
+            if note > 127 || velocity > 127 {
+                bail!("note '{}' is out of the MIDI range", entry);
+            }
+            Ok(RenderNote {
+                note,
+                velocity,
+                duration: number(duration)?,
+            })
+        })
+        .collect()
+}
+
+fn main() -> ExitCode {
+    env_logger::init();
+
+    let result = Options::parse(std::env::args().skip(1)).and_then(|opts| run(&opts));
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {:#}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(opts: &Options) -> anyhow::Result<()> {
+    let Some(command) = opts.args.first() else {
+        bail!("no command given\n\n{}", USAGE);
+    };
+    match command.as_str() {
+        "ports" => list_ports(),
+        "dump" => {
+            let files = opts.files(1)?;
+            let mut device = Device::connect(&opts.port)?;
+            let storage = device.read_bank(opts)?;
+            save_bank(&files[0], &storage, opts)?;
+            println!(
+                "Saved {} presets to {}{}",
+                storage.presets.len(),
+                files[0].display(),
+                downgrade_note(&storage, opts.version)
+            );
+            Ok(())
+        }
+        "write" => {
+            let files = opts.files(1)?;
+            let storage = load_bank(&files[0])?;
+            let mut device = Device::connect(&opts.port)?;
+            device.write_bank(&storage, opts)
+        }
+        "convert" => {
+            let files = opts.files(2)?;
+            let storage = load_bank(&files[0])?;
+            save_bank(&files[1], &storage, opts)?;
+            println!(
+                "Converted {} presets to {}{}",
+                storage.presets.len(),
+                files[1].display(),
+                downgrade_note(&storage, opts.version)
+            );
+            Ok(())
+        }
+        "info" => {
+            let files = opts.files(1)?;
+            print_summary(&load_bank(&files[0])?);
+            Ok(())
+        }
+        "render" => {
+            let files = opts.files(2)?;
+            render_bank(&load_bank(&files[0])?, &files[1], opts)
+        }
+        other => bail!("unknown command '{}'\n\n{}", other, USAGE),
+    }
+}
+
+fn list_ports() -> anyhow::Result<()> {
+    let transport = MidirTransport::new()?;
+    println!("Inputs:");
+    for name in transport.input_ports() {
+        println!("  {}", name);
+    }
+    println!("Outputs:");
+    for name in transport.output_ports() {
+        println!("  {}", name);
+    }
+    Ok(())
+}
+
+/// Reads a bank from a JSON or TOML document, or from a `.pdsp`/`.syx` file.
+fn load_bank(path: &Path) -> anyhow::Result<Storage> {
+    if let Some(format) = DocFormat::from_path(path) {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        return preset_doc::bank_from_str(&text, format)
+            .with_context(|| format!("failed to parse {}", path.display()));
+    }
+
+    let data = fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let file =
+        syx::read_syx(&data).with_context(|| format!("failed to parse {}", path.display()))?;
+    for skipped in &file.skipped {
+        eprintln!("{}: skipped {}", path.display(), skipped);
+    }
+    Ok(file.storage)
+}
+
+fn save_bank(path: &Path, storage: &Storage, opts: &Options) -> anyhow::Result<()> {
+    let data = match DocFormat::from_path(path) {
+        Some(format) => preset_doc::bank_to_string(storage, format)?.into_bytes(),
+        None => syx::write_syx(storage, opts.version, opts.layout, opts.checksum)?,
+    };
+    fs::write(path, data).with_context(|| format!("failed to write {}", path.display()))
+}
+
+fn downgrade_note(storage: &Storage, version: u32) -> String {
+    let warnings = storage.downgrade_warnings(version);
+    for w in &warnings {
+        eprintln!("warning: v{} drops data in preset {}", version, w);
+    }
+    if warnings.is_empty() {
+        String::new()
+    } else {
+        format!(" as v{} ({} presets lose data)", version, warnings.len())
+    }
+}
+
+fn print_summary(storage: &Storage) {
+    println!(
+        "Format v{}, {}/{} slots used",
+        storage.version,
+        storage.presets.len(),
+        storage.capacity()
+    );
+    for (index, preset) in storage.presets.iter().enumerate() {
+        let effects: Vec<&str> = [
+            (preset.lfo_enabled, "LFO"),
+            (preset.delay.enabled, "delay"),
+            (preset.reverb.enabled, "reverb"),
+        ]
+        .into_iter()
+        .filter_map(|(on, name)| on.then_some(name))
+        .collect();
+        println!(
+            "{:3}  {:<32}  {}",
+            index + 1,
+            preset.name,
+            effects.join(", ")
+        );
+    }
+}
+
+fn render_bank(storage: &Storage, out: &Path, opts: &Options) -> anyhow::Result<()> {
+    let selection: Vec<(usize, PathBuf)> = if opts.all {
+        let stem = out.with_extension("");
+        (0..storage.presets.len())
+            .map(|i| {
+                (
+                    i,
+                    PathBuf::from(format!("{}-{:02}.wav", stem.display(), i + 1)),
+                )
+            })
+            .collect()
+    } else {
+        if opts.preset == 0 || opts.preset > storage.presets.len() {
+            bail!(
+                "preset {} does not exist, the bank has {}",
+                opts.preset,
+                storage.presets.len()
+            );
+        }
+        vec![(opts.preset - 1, out.to_path_buf())]
+    };
+
+    for (index, path) in selection {
+        let preset = &storage.presets[index];
+        let samples = render::render(preset, &opts.notes, opts.sample_rate, opts.tail);
+        let wav = render::encode_wav(&samples, opts.sample_rate, opts.wav_format);
+        fs::write(&path, wav).with_context(|| format!("failed to write {}", path.display()))?;
+        println!("Rendered '{}' to {}", preset.name, path.display());
+    }
+    Ok(())
+}
+
+/// What the input thread passes to the request loops.
+enum Incoming {
+    Sysex(Vec<u8>),
+    /// Bytes of a SysEx message received so far.
+    Progress(usize),
+}
+
+struct Device {
+    transport: MidirTransport,
+    incoming: Receiver<Incoming>,
+}
+
+impl Device {
+    /// Connects to the first input and output port whose name contains
+    /// `pattern`, ignoring case.
+    fn connect(pattern: &str) -> anyhow::Result<Self> {
+        let mut transport = MidirTransport::new()?;
+        let find = |ports: Vec<String>, kind: &str| {
+            ports
+                .into_iter()
+                .find(|name| name.to_lowercase().contains(&pattern.to_lowercase()))
+                .ok_or_else(|| {
+                    anyhow!(
+                        "no MIDI {} port matches '{}', see `picoedit-cli ports`",
+                        kind,
+                        pattern
+                    )
+                })
+        };
+        let in_name = find(transport.input_ports(), "input")?;
+        let out_name = find(transport.output_ports(), "output")?;
+
+        let (tx, incoming) = crossbeam_channel::unbounded();
+        let mut framer = SysexFramer::new(MAX_SYSEX_LEN);
+        transport.connect_output(&out_name)?;
+        transport.connect_input(
+            &in_name,
+            Box::new(move |message| {
+                for event in framer.feed(message) {
+                    if let MidiEvent::Sysex(msg) = event {
+                        let _ = tx.send(Incoming::Sysex(msg));
+                    }
+                }
+                if let Some(received) = framer.sysex_progress() {
+                    let _ = tx.send(Incoming::Progress(received));
+                }
+            }),
+        )?;
+        eprintln!("Connected to {} / {}", in_name, out_name);
+        Ok(Self {
+            transport,
+            incoming,
+        })
+    }
+
+    fn read_bank(&mut self, opts: &Options) -> anyhow::Result<Storage> {
+        let mut session = ReadSession::new(
+            ReadTarget::Bank,
+            opts.version,
+            opts.checksum,
+            opts.timeout,
+            opts.retries,
+            Instant::now(),
+        );
+        self.transport.send(session.message())?;
+
+        loop {
+            match self.incoming.recv_timeout(POLL_INTERVAL) {
+                Ok(Incoming::Sysex(msg)) if session.accepts(&msg) => {
+                    let storage = Storage::from_sysex(&msg)?;
+                    if storage.version != VERSION {
+                        eprintln!("Upgraded bank from v{}", storage.version);
+                    }
+                    return Ok(storage);
+                }
+                Ok(Incoming::Progress(received)) => session.on_progress(received, Instant::now()),
+                _ => {}
+            }
+            match session.poll(Instant::now()) {
+                ReadStep::Wait => {}
+                ReadStep::Resend(msg, status) => {
+                    eprintln!("{}", status);
+                    self.transport.send(&msg)?;
+                }
+                ReadStep::Failed(status) => bail!(status),
+            }
+        }
+    }
+
+    fn write_bank(&mut self, storage: &Storage, opts: &Options) -> anyhow::Result<()> {
+        downgrade_note(storage, opts.version);
+        let msg = storage.to_sysex(opts.version, opts.checksum)?;
+        self.transport.send(&msg)?;
+        let mut session = WriteSession::new(WriteTarget::Bank, msg, opts.verify, Instant::now())?;
+
+        loop {
+            if let Ok(Incoming::Sysex(reply)) = self.incoming.recv_timeout(POLL_INTERVAL) {
+                let status = match reply.get(1..4) {
+                    Some(&[MANUFACTURER_ID, MODEL_ID, CMD_WRITE_SUCCESS]) => Some(session.on_ack()),
+                    Some(&[MANUFACTURER_ID, MODEL_ID, CMD_WRITE_ERROR]) => {
+                        Some(session.on_nak(reply.get(4).copied().unwrap_or(0)))
+                    }
+                    Some(&[MANUFACTURER_ID, MODEL_ID, CMD_WRITE_REQ]) if session.wants_dump() => {
+                        Some(session.on_dump(Storage::from_sysex(&reply)))
+                    }
+                    _ => None,
+                };
+                if let Some(status) = status {
+                    eprintln!("{}", status);
+                }
+            }
+            match session.poll(Instant::now()) {
+                Step::Wait => {}
+                Step::Send(msg, status) => {
+                    eprintln!("{}", status);
+                    self.transport.send(&msg)?;
+                }
+                Step::Failed(status) => bail!(status),
+                // Only single-preset writes fall back to a bank write.
+                Step::FallbackToBank | Step::Done => break,
+            }
+        }
+
+        if !session.succeeded() {
+            bail!("the device did not accept the bank");
+        }
+        Ok(())
+    }
+}
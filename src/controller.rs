@@ -0,0 +1,154 @@
+//! Messages from an external keyboard on the controller input, and the
+//! sustain pedal handling used when they play the local engine.
+
+pub const CC_MOD_WHEEL: u8 = 1;
+pub const CC_SUSTAIN: u8 = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ControllerEvent {
+    NoteOn {
+        note: u8,
+        velocity: u8,
+    },
+    NoteOff {
+        note: u8,
+    },
+    /// 14-bit bend, 8192 is centered.
+    PitchBend(u16),
+    ModWheel(u8),
+    Sustain(bool),
+}
+
+/// A channel message the controller input acts on, together with the bytes
+/// it arrived as so it can be passed through to the device unchanged.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ControllerMessage {
+    /// Zero-based MIDI channel.
+    pub channel: u8,
+    pub event: ControllerEvent,
+    pub raw: Vec<u8>,
+}
+
+impl ControllerMessage {
+    /// Returns `None` for anything other than notes, pitch bend, mod wheel
+    /// and sustain.
+    pub fn parse(msg: &[u8]) -> Option<Self> {
+        let (&status, data) = msg.split_first()?;
+        let event = match (status & 0xF0, data) {
+            (0x90, &[note, velocity]) if velocity > 0 => ControllerEvent::NoteOn { note, velocity },
+            (0x80 | 0x90, &[note, _]) => ControllerEvent::NoteOff { note },
+            (0xE0, &[lsb, msb]) => ControllerEvent::PitchBend((msb as u16) << 7 | lsb as u16),
+            (0xB0, &[CC_MOD_WHEEL, value]) => ControllerEvent::ModWheel(value),
+            (0xB0, &[CC_SUSTAIN, value]) => ControllerEvent::Sustain(value >= 64),
+            _ => return None,
+        };
+        Some(Self {
+            channel: status & 0x0F,
+            event,
+            raw: msg.to_vec(),
+        })
+    }
+
+    /// Whether the message passes a channel filter; `None` is omni.
+    pub fn on_channel(&self, filter: Option<u8>) -> bool {
+        filter.is_none_or(|channel| channel == self.channel)
+    }
+}
+
+/// Keys and sustain pedal for the local engine: releases are held back while
+/// the pedal is down and sent when it comes up.
+#[derive(Default)]
+pub struct HeldNotes {
+    sustained: Vec<u8>,
+    pedal: bool,
+}
+
+impl HeldNotes {
+    pub fn note_on(&mut self, note: u8) {
+        self.sustained.retain(|&n| n != note);
+    }
+
+    /// Returns the note to release, unless the pedal holds it.
+    pub fn note_off(&mut self, note: u8) -> Option<u8> {
+        if self.pedal {
+            self.sustained.push(note);
+            return None;
+        }
+        Some(note)
+    }
+
+    /// Returns the notes to release when the pedal comes up.
+    pub fn sustain(&mut self, down: bool) -> Vec<u8> {
+        self.pedal = down;
+        if down {
+            Vec::new()
+        } else {
+            std::mem::take(&mut self.sustained)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_controller_messages() {
+        let parse = |msg: &[u8]| ControllerMessage::parse(msg).map(|m| (m.channel, m.event));
+
+        assert_eq!(
+            parse(&[0x92, 60, 100]),
+            Some((
+                2,
+                ControllerEvent::NoteOn {
+                    note: 60,
+                    velocity: 100
+                }
+            ))
+        );
+        assert_eq!(
+            parse(&[0x90, 60, 0]),
+            Some((0, ControllerEvent::NoteOff { note: 60 }))
+        );
+        assert_eq!(
+            parse(&[0xE0, 0x00, 0x40]),
+            Some((0, ControllerEvent::PitchBend(8192)))
+        );
+        assert_eq!(
+            parse(&[0xBF, CC_SUSTAIN, 127]),
+            Some((15, ControllerEvent::Sustain(true)))
+        );
+        assert_eq!(parse(&[0xB0, 74, 10]), None);
+        assert_eq!(parse(&[0xC0, 3]), None);
+    }
+
+    #[test]
+    fn filters_by_channel() {
+        let msg = ControllerMessage::parse(&[0x93, 60, 100]).unwrap();
+        assert!(msg.on_channel(None));
+        assert!(msg.on_channel(Some(3)));
+        assert!(!msg.on_channel(Some(0)));
+    }
+
+    #[test]
+    fn sustain_holds_the_release() {
+        let mut notes = HeldNotes::default();
+        notes.note_on(60);
+        assert_eq!(notes.note_off(60), Some(60));
+
+        assert!(notes.sustain(true).is_empty());
+        notes.note_on(60);
+        notes.note_on(64);
+        assert_eq!(notes.note_off(60), None);
+        assert_eq!(notes.note_off(64), None);
+        assert_eq!(notes.sustain(false), vec![60, 64]);
+
+        // A key struck again while sustained is held by the key, not the pedal.
+        notes.sustain(true);
+        notes.note_on(62);
+        notes.note_off(62);
+        notes.note_on(62);
+        assert!(notes.sustain(false).is_empty());
+        assert_eq!(notes.note_off(62), Some(62));
+    }
+}
@@ -0,0 +1,327 @@
+//! Headless stand-in for a picoDSP, so the editor's dump, write and program
+//! change flow can be tried without hardware. It opens virtual MIDI ports
+//! named "picoDSP Emulator", which `auto_connect` picks up like the device.
+
+use crate::params::desc_for_cc;
+use crate::protocol::*;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const PORT_NAME: &str = "picoDSP Emulator";
+pub const DEFAULT_FLASH_PATH: &str = "picodsp-flash.bin";
+/// Firmware version the emulator reports.
+const FIRMWARE: [u8; 3] = [1, 0, 0];
+
+/// `CMD_WRITE_ERROR` codes sent by the emulator.
+const ERR_INVALID_DATA: u8 = 0x01;
+const ERR_CHECKSUM: u8 = 0x02;
+
+/// What the device does to its sound engine in response to a message.
+pub enum SoundEvent {
+    Preset(Box<Preset>),
+    NoteOn(u8, u8),
+    NoteOff(u8),
+}
+
+/// The device's message handling, without any MIDI or audio I/O.
+pub struct EmulatedDevice {
+    /// Raw contents of the preset flash sector, `STORAGE_SIZE` bytes.
+    flash: Vec<u8>,
+    flash_path: Option<PathBuf>,
+    storage: Storage,
+    program: usize,
+    /// The current preset including live parameter changes.
+    live: Preset,
+    /// Optional commands the firmware understands; the others are ignored.
+    features: Features,
+}
+
+impl EmulatedDevice {
+    /// Creates a device with the given flash image, or a fresh bank holding
+    /// one default preset if the image is missing or unreadable.
+    pub fn new(flash: Option<Vec<u8>>) -> Self {
+        let image = flash.and_then(|flash| {
+            let mut msg = vec![SYSEX_START, MANUFACTURER_ID, MODEL_ID, CMD_WRITE_REQ];
+            msg.extend_from_slice(&nibbleize(&flash));
+            msg.push(SYSEX_END);
+            match Storage::from_sysex(&msg) {
+                Ok(storage) => Some((flash, storage)),
+                Err(e) => {
+                    log::warn!("Flash image unusable, starting empty: {}", e);
+                    None
+                }
+            }
+        });
+
+        match image {
+            Some((flash, storage)) => Self {
+                flash,
+                flash_path: None,
+                live: storage.presets.first().cloned().unwrap_or_default(),
+                storage,
+                program: 0,
+                features: Features::ALL,
+            },
+            None => {
+                let mut device = Self {
+                    flash: Vec::new(),
+                    flash_path: None,
+                    storage: Storage::default(),
+                    program: 0,
+                    live: Preset::default(),
+                    features: Features::ALL,
+                };
+                device.store_bank();
+                device
+            }
+        }
+    }
+
+    /// Opens the flash image at `path`, saving it back after every write.
+    pub fn open(path: &Path) -> Self {
+        let mut device = Self::new(fs::read(path).ok());
+        device.flash_path = Some(path.to_path_buf());
+        device
+    }
+
+    /// Behaves like older firmware that lacks some of the optional commands.
+    pub fn set_features(&mut self, features: Features) {
+        self.features = features;
+    }
+
+    /// Handles one complete MIDI message, returning the replies to send and
+    /// what changed for the sound engine.
+    pub fn handle(&mut self, msg: &[u8]) -> (Vec<Vec<u8>>, Vec<SoundEvent>) {
+        let mut replies = Vec::new();
+        let mut events = Vec::new();
+
+        match msg {
+            [SYSEX_START, 0x7E, _, 0x06, 0x01, SYSEX_END] => {
+                replies.push(Identity::picodsp(FIRMWARE).to_sysex());
+            }
+            [SYSEX_START, MANUFACTURER_ID, MODEL_ID, cmd, ..] => match *cmd {
+                CMD_INFO_REQ => replies.push(
+                    FirmwareInfo {
+                        firmware: FIRMWARE,
+                        storage_version: VERSION,
+                        capacity: MAX_PRESETS,
+                        features: self.features,
+                    }
+                    .to_sysex(),
+                ),
+                CMD_PRESET_REQ | CMD_PRESET_WRITE if !self.features.preset_commands => {
+                    log::info!("Ignoring command {:02X}", cmd)
+                }
+                CMD_SET_PARAM if !self.features.live_params => {
+                    log::info!("Ignoring command {:02X}", cmd)
+                }
+                CMD_DUMP_REQ => {
+                    let mut reply = vec![SYSEX_START, MANUFACTURER_ID, MODEL_ID, CMD_WRITE_REQ];
+                    reply.extend_from_slice(&nibbleize(&self.flash));
+                    reply.push(SYSEX_END);
+                    replies.push(reply);
+                }
+                CMD_WRITE_REQ => {
+                    replies.push(match Storage::from_sysex(msg) {
+                        Ok(storage) => {
+                            self.flash = denibbleize(&msg[4..4 + STORAGE_SIZE * 2])
+                                .expect("validated by from_sysex");
+                            self.storage = storage;
+                            self.save_flash();
+                            self.select_program(self.program, &mut events);
+                            ack()
+                        }
+                        Err(e) => nak(e),
+                    });
+                }
+                CMD_PRESET_REQ if msg.len() == 6 => {
+                    let index = msg[4] as usize;
+                    match self.storage.presets.get(index) {
+                        Some(preset) => {
+                            if let Ok(reply) =
+                                preset.to_sysex(CMD_PRESET_DATA, index, VERSION, false)
+                            {
+                                replies.push(reply);
+                            }
+                        }
+                        None => replies.push(nak(ProtocolError::PresetIndexOutOfRange {
+                            index,
+                            count: self.storage.presets.len(),
+                        })),
+                    }
+                }
+                CMD_PRESET_WRITE => {
+                    replies.push(match self.storage.apply_preset_sysex(msg) {
+                        Ok(index) => {
+                            self.store_bank();
+                            if index == self.program {
+                                self.select_program(index, &mut events);
+                            }
+                            ack()
+                        }
+                        Err(e) => nak(e),
+                    });
+                }
+                CMD_SET_PARAM => match parse_param_sysex(msg) {
+                    Ok((id, value)) => {
+                        if self.live.set_param(id, value).is_ok() {
+                            events.push(SoundEvent::Preset(Box::new(self.live.clone())));
+                        }
+                    }
+                    Err(e) => log::warn!("Bad parameter message: {}", e),
+                },
+                cmd => log::info!("Ignoring command {:02X}", cmd),
+            },
+            [status, program] if status & 0xF0 == 0xC0 => {
+                self.select_program(*program as usize, &mut events);
+            }
+            [status, note, velocity] if status & 0xF0 == 0x90 && *velocity > 0 => {
+                events.push(SoundEvent::NoteOn(*note, *velocity));
+            }
+            [status, note, _] if status & 0xF0 == 0x80 || status & 0xF0 == 0x90 => {
+                events.push(SoundEvent::NoteOff(*note));
+            }
+            [status, cc, value] if status & 0xF0 == 0xB0 => {
+                if let Some(desc) = desc_for_cc(*cc) {
+                    if self
+                        .live
+                        .set_param(desc.id, desc.value_from_cc(*value))
+                        .is_ok()
+                    {
+                        events.push(SoundEvent::Preset(Box::new(self.live.clone())));
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        (replies, events)
+    }
+
+    fn select_program(&mut self, program: usize, events: &mut Vec<SoundEvent>) {
+        if let Some(preset) = self.storage.presets.get(program) {
+            self.program = program;
+            self.live = preset.clone();
+            events.push(SoundEvent::Preset(Box::new(preset.clone())));
+        }
+    }
+
+    /// Re-encodes the bank into the flash image, e.g. after a preset write.
+    fn store_bank(&mut self) {
+        if let Ok(msg) = self.storage.to_sysex(VERSION, false) {
+            self.flash = denibbleize(&msg[4..msg.len() - 1]).expect("encoded by to_sysex");
+            self.save_flash();
+        }
+    }
+
+    fn save_flash(&self) {
+        if let Some(path) = &self.flash_path {
+            if let Err(e) = fs::write(path, &self.flash) {
+                log::error!("Failed to save flash image {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+fn ack() -> Vec<u8> {
+    vec![
+        SYSEX_START,
+        MANUFACTURER_ID,
+        MODEL_ID,
+        CMD_WRITE_SUCCESS,
+        SYSEX_END,
+    ]
+}
+
+fn nak(error: ProtocolError) -> Vec<u8> {
+    log::warn!("Rejecting write: {}", error);
+    let code = match error {
+        ProtocolError::ChecksumMismatch { .. } => ERR_CHECKSUM,
+        _ => ERR_INVALID_DATA,
+    };
+    vec![
+        SYSEX_START,
+        MANUFACTURER_ID,
+        MODEL_ID,
+        CMD_WRITE_ERROR,
+        code,
+        SYSEX_END,
+    ]
+}
+
+/// Runs the emulator until the process is killed.
+#[cfg(all(unix, feature = "midi", feature = "audio"))]
+pub fn run(flash_path: &Path) -> anyhow::Result<()> {
+    use crate::audio::{AudioManager, AudioSettings};
+    use midir::os::unix::{VirtualInput, VirtualOutput};
+    use midir::{Ignore, MidiInput, MidiOutput};
+    use std::sync::{Arc, Mutex};
+
+    let device = Arc::new(Mutex::new(EmulatedDevice::open(flash_path)));
+    let audio = AudioManager::new(&AudioSettings::default())
+        .map_err(|e| println!("Running without audio: {}", e))
+        .ok();
+
+    let out = MidiOutput::new(PORT_NAME)?
+        .create_virtual(PORT_NAME)
+        .map_err(|e| anyhow::anyhow!("Failed to create virtual output: {}", e))?;
+    let out = Arc::new(Mutex::new(out));
+
+    let (events_tx, events_rx) = crossbeam_channel::unbounded();
+    let mut midi_in = MidiInput::new(PORT_NAME)?;
+    midi_in.ignore(Ignore::None);
+    let mut framer = SysexFramer::new(MAX_SYSEX_LEN);
+    let _conn_in = midi_in
+        .create_virtual(
+            PORT_NAME,
+            move |_stamp, message, _| {
+                let mut replies = Vec::new();
+                for event in framer.feed(message) {
+                    let (MidiEvent::Sysex(msg) | MidiEvent::Message(msg)) = event else {
+                        continue;
+                    };
+                    let (reply, events) = device.lock().unwrap().handle(&msg);
+                    replies.extend(reply);
+                    for event in events {
+                        let _ = events_tx.send(event);
+                    }
+                }
+
+                let mut out = out.lock().unwrap();
+                for reply in replies {
+                    if let Err(e) = out.send(&reply) {
+                        log::error!("Failed to send reply: {}", e);
+                    }
+                }
+            },
+            (),
+        )
+        .map_err(|e| anyhow::anyhow!("Failed to create virtual input: {}", e))?;
+
+    println!(
+        "{} running with flash image {}. Press Ctrl+C to quit.",
+        PORT_NAME,
+        flash_path.display()
+    );
+
+    // The audio stream is not Send, so the engine is driven from here.
+    let mut audio = audio;
+    for event in events_rx {
+        let Some(audio) = &mut audio else {
+            continue;
+        };
+        match event {
+            SoundEvent::Preset(preset) => audio.update_preset(&preset),
+            SoundEvent::NoteOn(note, velocity) => audio.note_on(note, velocity),
+            SoundEvent::NoteOff(note) => audio.note_off(note),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(not(unix), feature = "midi", feature = "audio"))]
+pub fn run(_flash_path: &Path) -> anyhow::Result<()> {
+    anyhow::bail!(
+        "The emulator needs virtual MIDI ports, which are only available on Linux and macOS"
+    )
+}
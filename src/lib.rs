@@ -0,0 +1,38 @@
+//! picoDSP preset codec, device protocol and local synth engine, shared by
+//! the editor and `picoedit-cli`.
+//!
+//! The preset model and SysEx codec live in [`protocol`], with the parameter
+//! registry in [`params`] and file formats in [`syx`] and [`preset_doc`].
+//! Talking to a device goes through a [`transport::MidiTransport`] driven by
+//! [`read_session`] and [`write_session`]. [`audio::Engine`] is the local
+//! replica of the synth, which [`render`] runs offline.
+//!
+//! Cargo features:
+//! - `midi`: [`transport::MidirTransport`] for system MIDI ports.
+//! - `audio`: [`audio::AudioManager`], playing the engine on a system
+//!   output device.
+//! - `gui` (default): the editor binary, implies both of the above.
+//!
+//! With `default-features = false` the crate builds without eframe, cpal
+//! and midir.
+
+pub mod audio;
+pub mod controller;
+pub mod emulator;
+pub mod param_stream;
+pub mod params;
+pub mod preset_doc;
+pub mod protocol;
+pub mod read_session;
+pub mod render;
+pub mod syx;
+pub mod transport;
+pub mod voices;
+pub mod write_session;
+
+mod dsp_utils;
+mod fast_lfo;
+
+pub use audio::Engine;
+pub use params::ParamId;
+pub use protocol::{Preset, ProtocolError, Storage};
@@ -1,25 +1,68 @@
 use eframe::egui;
-use midir::{Ignore, MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
 use rustfft::FftPlanner;
 use std::error::Error;
 use std::fs;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-mod protocol;
-use protocol::*;
+use picoedit::audio::{AudioManager, AudioSettings, OutputChoices};
+use picoedit::controller::{ControllerEvent, ControllerMessage, HeldNotes};
+use picoedit::emulator;
+use picoedit::param_stream::ParamStreamer;
+use picoedit::params;
+use picoedit::preset_doc::{self, DocFormat};
+use picoedit::protocol::*;
+use picoedit::read_session::{self, ReadSession, ReadStep, ReadTarget};
+use picoedit::render::{self, RenderNote, WavFormat};
+use picoedit::syx::{self, SyxLayout};
+use picoedit::transport::{LoopbackTransport, MidiTransport, MidirTransport};
+use picoedit::voices::{self, NotePriority};
+use picoedit::write_session::{Step, WriteSession, WriteTarget};
 
 mod piano;
 use piano::PianoWidget;
 
-mod audio;
-mod dsp_utils;
-mod fast_lfo;
-use audio::AudioManager;
+mod settings;
 
 mod ui;
 
+/// What "Render Preset to WAV" plays.
+const PREVIEW_NOTE: RenderNote = RenderNote {
+    note: 60,
+    velocity: 100,
+    duration: 1.0,
+};
+const PREVIEW_TAIL_SECS: f32 = 2.0;
+const PREVIEW_SAMPLE_RATE: u32 = 48000;
+
 fn main() -> Result<(), Box<dyn Error>> {
     env_logger::init();
+
+    // `picoedit --emulator [flash.bin]` runs a headless virtual device instead
+    // of the editor.
+    let mut args = std::env::args().skip(1);
+    if args.next().as_deref() == Some("--emulator") {
+        let flash = args
+            .next()
+            .unwrap_or_else(|| emulator::DEFAULT_FLASH_PATH.to_string());
+        return emulator::run(std::path::Path::new(&flash)).map_err(|e| e.into());
+    }
+    // `picoedit --loopback` runs the editor against an in-process emulator.
+    let transport: Box<dyn MidiTransport> = if std::env::args().any(|a| a == "--loopback") {
+        let mut device = emulator::EmulatedDevice::new(None);
+        Box::new(LoopbackTransport::new(move |msg| device.handle(msg).0))
+    } else {
+        Box::new(MidirTransport::new()?)
+    };
+    // A keyboard is always a system MIDI port, even in loopback mode.
+    let controller: Option<Box<dyn MidiTransport>> = match MidirTransport::new() {
+        Ok(t) => Some(Box::new(t)),
+        Err(e) => {
+            println!("Controller input unavailable: {}", e);
+            None
+        }
+    };
+
     let options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default()
             .with_inner_size([1100.0, 800.0])
@@ -29,7 +72,7 @@ fn main() -> Result<(), Box<dyn Error>> {
     eframe::run_native(
         "PicoDSP Editor 1.0",
         options,
-        Box::new(|_cc| Ok(Box::new(PicoEditApp::default()))),
+        Box::new(|_cc| Ok(Box::new(PicoEditApp::new(transport, controller)))),
     )
     .map_err(|e| e.into())
 }
@@ -40,66 +83,139 @@ enum AudioMode {
     Remote,
 }
 
+/// Replies from the input thread that describe the connected device.
+enum DeviceReply {
+    Identity(Identity),
+    Info(FirmwareInfo),
+    /// Format version of a bank dump the device sent.
+    DumpVersion(u32),
+}
+
 struct PicoEditApp {
-    midi_in: Option<MidiInput>,
-    midi_out: Option<MidiOutput>,
+    transport: Box<dyn MidiTransport>,
     in_port_name: Option<String>,
     out_port_name: Option<String>,
+    /// Separate input for a keyboard that plays the local engine or the device.
+    controller: Option<Box<dyn MidiTransport>>,
+    controller_port_name: Option<String>,
+    /// Zero-based channel the controller input listens on; `None` is omni.
+    controller_channel: Option<u8>,
+    controller_tx: crossbeam_channel::Sender<ControllerMessage>,
+    controller_rx: crossbeam_channel::Receiver<ControllerMessage>,
+    held_notes: HeldNotes,
 
     audio_mode: AudioMode,
 
-    conn_out: Option<MidiOutputConnection>,
-    conn_in: Option<MidiInputConnection<()>>,
-
     storage: Arc<Mutex<Storage>>,
+    /// Format version used when writing to the device or to a file.
+    target_version: u32,
+    /// Write only the current preset instead of the whole bank.
+    single_preset_writes: bool,
+    /// Read the bank back after a write and compare it with what was sent.
+    verify_writes: bool,
+    /// Append a CRC to outgoing dumps and presets (needs firmware support).
+    send_checksums: bool,
+    /// The last "Save to Device", so ACK, NAK and read-back can be tied to it.
+    pending_write: Arc<Mutex<Option<WriteSession>>>,
+    /// The outstanding dump or preset request; replies to anything else are
+    /// ignored.
+    pending_read: Arc<Mutex<Option<ReadSession>>>,
+    read_timeout_secs: f32,
+    read_retries: u32,
     current_preset_index: usize,
     last_preset_index: usize,
     status_msg: Arc<Mutex<String>>,
 
+    /// Streams slider edits to the device while in Remote mode.
+    param_stream: ParamStreamer,
+    /// Control changes received from the device, applied via the registry.
+    cc_tx: crossbeam_channel::Sender<(u8, u8)>,
+    cc_rx: crossbeam_channel::Receiver<(u8, u8)>,
+    device_tx: crossbeam_channel::Sender<DeviceReply>,
+    device_rx: crossbeam_channel::Receiver<DeviceReply>,
+    /// What the connected device reported about itself, if anything.
+    identity: Option<Identity>,
+    firmware_info: Option<FirmwareInfo>,
+
     active_notes: Vec<u8>,
+    /// Number of voices in the local engine.
+    polyphony: usize,
+    /// Note priority and legato of the local engine with a single voice.
+    note_priority: NotePriority,
+    legato: bool,
     audio: Option<AudioManager>,
+    /// The output picked in the Audio Output window, saved on change.
+    audio_settings: AudioSettings,
+    /// Queried when the Audio Output window opens, as it can be slow.
+    output_choices: Option<OutputChoices>,
+    show_output_window: bool,
     fft_planner: Arc<Mutex<FftPlanner<f32>>>,
 }
 
-impl Default for PicoEditApp {
-    fn default() -> Self {
-        let mut midi_in = MidiInput::new("PicoEdit Input").unwrap();
-        midi_in.ignore(Ignore::None);
-        let midi_out = MidiOutput::new("PicoEdit Output").unwrap();
+impl PicoEditApp {
+    fn new(transport: Box<dyn MidiTransport>, controller: Option<Box<dyn MidiTransport>>) -> Self {
+        let audio_settings = settings::load().audio;
+        // The saved output may be unplugged; keep it for next time but play
+        // on the default one meanwhile.
+        let audio = AudioManager::new(&audio_settings)
+            .or_else(|e| {
+                println!("Failed to open the saved audio output: {}", e);
+                AudioManager::new(&AudioSettings::default())
+            })
+            .map_err(|e| println!("Failed to initialize audio: {}", e))
+            .ok();
 
-        let audio = match AudioManager::new() {
-            Ok(a) => Some(a),
-            Err(e) => {
-                println!("Failed to initialize audio: {}", e);
-                None
-            }
-        };
+        let (cc_tx, cc_rx) = crossbeam_channel::unbounded();
+        let (device_tx, device_rx) = crossbeam_channel::unbounded();
+        let (controller_tx, controller_rx) = crossbeam_channel::unbounded();
 
         let mut app = Self {
-            midi_in: Some(midi_in),
-            midi_out: Some(midi_out),
+            transport,
             in_port_name: None,
             out_port_name: None,
+            controller,
+            controller_port_name: None,
+            controller_channel: None,
+            controller_tx,
+            controller_rx,
+            held_notes: HeldNotes::default(),
             audio_mode: AudioMode::Local,
-            conn_out: None,
-            conn_in: None,
-            storage: Arc::new(Mutex::new(Storage {
-                presets: vec![Preset::default()],
-            })),
+            storage: Arc::new(Mutex::new(Storage::default())),
+            target_version: DEFAULT_WRITE_VERSION,
+            // Older firmware ignores single-preset writes, so they are only
+            // used once the device reports support for them.
+            single_preset_writes: false,
+            verify_writes: false,
+            send_checksums: false,
+            pending_write: Arc::new(Mutex::new(None)),
+            pending_read: Arc::new(Mutex::new(None)),
+            read_timeout_secs: read_session::DEFAULT_TIMEOUT.as_secs_f32(),
+            read_retries: read_session::DEFAULT_RETRIES,
             current_preset_index: 0,
             last_preset_index: 0,
             status_msg: Arc::new(Mutex::new("Ready".to_string())),
+            param_stream: ParamStreamer::new(),
+            cc_tx,
+            cc_rx,
+            device_tx,
+            device_rx,
+            identity: None,
+            firmware_info: None,
             active_notes: Vec::new(),
+            polyphony: 1,
+            note_priority: NotePriority::default(),
+            legato: false,
             audio,
+            audio_settings,
+            output_choices: None,
+            show_output_window: false,
             fft_planner: Arc::new(Mutex::new(FftPlanner::new())),
         };
 
         app.auto_connect();
         app
     }
-}
 
-impl PicoEditApp {
     fn auto_connect(&mut self) {
         let target_in = self.find_port_by_name(true, "picodsp");
         let target_out = self.find_port_by_name(false, "picodsp");
@@ -113,176 +229,193 @@ impl PicoEditApp {
 
     fn find_port_by_name(&self, is_input: bool, pattern: &str) -> Option<String> {
         let pattern = pattern.to_lowercase();
-        if is_input {
-            if let Some(midi_in) = &self.midi_in {
-                for port in midi_in.ports() {
-                    if let Ok(name) = midi_in.port_name(&port) {
-                        if name.to_lowercase().contains(&pattern) {
-                            return Some(name);
-                        }
-                    }
-                }
-            }
-        } else if let Some(midi_out) = &self.midi_out {
-            for port in midi_out.ports() {
-                if let Ok(name) = midi_out.port_name(&port) {
-                    if name.to_lowercase().contains(&pattern) {
-                        return Some(name);
-                    }
-                }
-            }
-        }
-        None
+        let ports = if is_input {
+            self.transport.input_ports()
+        } else {
+            self.transport.output_ports()
+        };
+        ports
+            .into_iter()
+            .find(|name| name.to_lowercase().contains(&pattern))
     }
 
     fn refresh_midi(&mut self) {
-        self.conn_in = None;
-        self.conn_out = None;
-
-        let mut midi_in = MidiInput::new("PicoEdit Input").unwrap();
-        midi_in.ignore(Ignore::None);
-        self.midi_in = Some(midi_in);
-
-        self.midi_out = Some(MidiOutput::new("PicoEdit Output").unwrap());
+        self.transport.disconnect();
         *self.status_msg.lock().unwrap() = "Ports refreshed".to_string();
 
         self.auto_connect();
     }
 
     fn connect_midi(&mut self, in_name: &str, out_name: &str) {
-        self.conn_in = None;
-        self.conn_out = None;
-
-        self.ensure_midi_instances();
+        self.transport.disconnect();
 
-        if !self.connect_output(out_name) {
+        if let Err(e) = self.transport.connect_output(out_name) {
+            *self.status_msg.lock().unwrap() = e.to_string();
             return;
         }
 
         // Always connect input if available
         self.connect_input(in_name);
 
-        if self.conn_out.is_some() {
-            let in_status = if self.conn_in.is_some() {
-                " + Input"
-            } else {
-                ""
-            };
-            *self.status_msg.lock().unwrap() = format!("Connected to Output{}", in_status);
-            self.send_dump_request();
-        }
+        let in_status = if self.transport.has_input() {
+            " + Input"
+        } else {
+            ""
+        };
+        *self.status_msg.lock().unwrap() = format!("Connected to Output{}", in_status);
+        self.send_identity_requests();
+        self.send_dump_request();
     }
 
-    fn ensure_midi_instances(&mut self) {
-        if self.midi_in.is_none() {
-            let mut midi_in = MidiInput::new("PicoEdit Input").unwrap();
-            midi_in.ignore(Ignore::None);
-            self.midi_in = Some(midi_in);
-        }
-        if self.midi_out.is_none() {
-            self.midi_out = Some(MidiOutput::new("PicoEdit Output").unwrap());
+    fn connect_input(&mut self, in_name: &str) {
+        let storage_clone = self.storage.clone();
+        let status_clone = self.status_msg.clone();
+        let pending_clone = self.pending_write.clone();
+        let read_clone = self.pending_read.clone();
+        let cc_clone = self.cc_tx.clone();
+        let device_clone = self.device_tx.clone();
+        let mut framer = SysexFramer::new(MAX_SYSEX_LEN);
+
+        let result = self.transport.connect_input(
+            in_name,
+            Box::new(move |message| {
+                for event in framer.feed(message) {
+                    Self::handle_midi_event(
+                        event,
+                        &storage_clone,
+                        &status_clone,
+                        &pending_clone,
+                        &read_clone,
+                        &cc_clone,
+                        &device_clone,
+                    );
+                }
+                if let Some(received) = framer.sysex_progress() {
+                    if let Some(read) = read_clone.lock().unwrap().as_mut() {
+                        read.on_progress(received, Instant::now());
+                    }
+                }
+            }),
+        );
+        if let Err(e) = result {
+            *self.status_msg.lock().unwrap() = e.to_string();
         }
     }
 
-    fn connect_output(&mut self, out_name: &str) -> bool {
-        let midi_out = self.midi_out.take().unwrap();
-        let out_ports = midi_out.ports();
-        let out_port = out_ports
-            .iter()
-            .find(|p| midi_out.port_name(p).unwrap() == out_name);
+    fn connect_controller(&mut self, ctx: &egui::Context) {
+        let Some(controller) = &mut self.controller else {
+            return;
+        };
+        controller.disconnect();
+        let Some(name) = self.controller_port_name.clone() else {
+            return;
+        };
 
-        if let Some(op) = out_port {
-            match midi_out.connect(op, "PicoEdit Out") {
-                Ok(conn) => {
-                    self.conn_out = Some(conn);
-                    true
+        let tx = self.controller_tx.clone();
+        let ctx = ctx.clone();
+        let mut framer = SysexFramer::new(MAX_SYSEX_LEN);
+        let result = controller.connect_input(
+            &name,
+            Box::new(move |message| {
+                for event in framer.feed(message) {
+                    if let MidiEvent::Message(msg) = event {
+                        if let Some(msg) = ControllerMessage::parse(&msg) {
+                            let _ = tx.send(msg);
+                            ctx.request_repaint();
+                        }
+                    }
                 }
-                Err(e) => {
-                    *self.status_msg.lock().unwrap() = format!("Error connecting output: {}", e);
-                    self.midi_out = Some(MidiOutput::new("PicoEdit Output").unwrap());
-                    false
+            }),
+        );
+        *self.status_msg.lock().unwrap() = match result {
+            Ok(()) => format!("Controller connected: {}", name),
+            Err(e) => format!("Controller: {}", e),
+        };
+    }
+
+    /// Plays the local engine from the controller in Local mode and passes
+    /// its messages through to the device in Remote mode.
+    fn apply_controller_events(&mut self) {
+        while let Ok(msg) = self.controller_rx.try_recv() {
+            if !msg.on_channel(self.controller_channel) {
+                continue;
+            }
+            if self.audio_mode == AudioMode::Remote {
+                if self.transport.has_output() {
+                    if let Err(e) = self.transport.send(&msg.raw) {
+                        println!("Failed to forward controller message: {}", e);
+                    }
                 }
+                continue;
             }
-        } else {
-            *self.status_msg.lock().unwrap() = "Output port not found".to_string();
-            self.midi_out = Some(midi_out);
-            false
-        }
-    }
 
-    fn connect_input(&mut self, in_name: &str) {
-        let midi_in = self.midi_in.take().unwrap();
-        let in_ports = midi_in.ports();
-        let in_port = in_ports
-            .iter()
-            .find(|p| midi_in.port_name(p).unwrap() == in_name);
-
-        if let Some(ip) = in_port {
-            let storage_clone = self.storage.clone();
-            let status_clone = self.status_msg.clone();
-            let sysex_buffer = Arc::new(Mutex::new(Vec::<u8>::new()));
-            let buffer_clone = sysex_buffer.clone();
-
-            match midi_in.connect(
-                ip,
-                "PicoEdit In",
-                move |_stamp, message, _| {
-                    Self::handle_midi_message(
-                        message,
-                        &buffer_clone,
-                        &storage_clone,
-                        &status_clone,
-                    );
-                },
-                (),
-            ) {
-                Ok(conn) => {
-                    self.conn_in = Some(conn);
+            match msg.event {
+                ControllerEvent::NoteOn { note, velocity } => {
+                    self.held_notes.note_on(note);
+                    self.send_note(note, velocity, true);
                 }
-                Err(e) => {
-                    *self.status_msg.lock().unwrap() = format!("Error connecting input: {}", e);
-                    let mut midi_in = MidiInput::new("PicoEdit Input").unwrap();
-                    midi_in.ignore(Ignore::None);
-                    self.midi_in = Some(midi_in);
+                ControllerEvent::NoteOff { note } => {
+                    if let Some(note) = self.held_notes.note_off(note) {
+                        self.send_note(note, 0, false);
+                    }
+                }
+                ControllerEvent::Sustain(down) => {
+                    for note in self.held_notes.sustain(down) {
+                        self.send_note(note, 0, false);
+                    }
+                }
+                ControllerEvent::PitchBend(value) => {
+                    if let Some(audio) = &self.audio {
+                        audio.pitch_bend(value);
+                    }
+                }
+                ControllerEvent::ModWheel(value) => {
+                    if let Some(audio) = &self.audio {
+                        audio.mod_wheel(value);
+                    }
                 }
             }
-        } else {
-            *self.status_msg.lock().unwrap() = "Input port not found".to_string();
-            let mut midi_in = MidiInput::new("PicoEdit Input").unwrap();
-            midi_in.ignore(Ignore::None);
-            self.midi_in = Some(midi_in);
         }
     }
 
-    fn handle_midi_message(
-        message: &[u8],
-        buffer_clone: &Arc<Mutex<Vec<u8>>>,
+    fn handle_midi_event(
+        event: MidiEvent,
         storage_clone: &Arc<Mutex<Storage>>,
         status_clone: &Arc<Mutex<String>>,
+        pending_clone: &Arc<Mutex<Option<WriteSession>>>,
+        read_clone: &Arc<Mutex<Option<ReadSession>>>,
+        cc_clone: &crossbeam_channel::Sender<(u8, u8)>,
+        device_clone: &crossbeam_channel::Sender<DeviceReply>,
     ) {
-        /*if message.len() < 20 {
-            println!("Rx Chunk ({} bytes): {:02X?}", message.len(), message);
-        } else {
-             println!("Rx Chunk ({} bytes): [First 10: {:02X?} ...]", message.len(), &message[0..10]);
-        }*/
-
-        let mut buffer = buffer_clone.lock().unwrap();
-
-        if message.contains(&0xF0) {
-            buffer.clear();
-            if let Some(start) = message.iter().position(|&x| x == 0xF0) {
-                buffer.extend_from_slice(&message[start..]);
+        match event {
+            MidiEvent::Sysex(msg) => {
+                if let Ok(identity) = Identity::from_sysex(&msg) {
+                    let _ = device_clone.send(DeviceReply::Identity(identity));
+                    return;
+                }
+                if let Ok(info) = FirmwareInfo::from_sysex(&msg) {
+                    let _ = device_clone.send(DeviceReply::Info(info));
+                    return;
+                }
+                Self::process_sysex(
+                    &msg,
+                    storage_clone,
+                    status_clone,
+                    pending_clone,
+                    read_clone,
+                    device_clone,
+                );
             }
-        } else if !buffer.is_empty() {
-            buffer.extend_from_slice(message);
-        }
-
-        if let Some(&last) = buffer.last() {
-            if last == 0xF7 {
-                //     println!("Full SysEx received: {} bytes", buffer.len());
-                Self::process_sysex(&buffer, storage_clone, status_clone);
-                buffer.clear();
+            MidiEvent::Message(msg) if msg[0] & 0xF0 == 0xB0 => {
+                let _ = cc_clone.send((msg[1], msg[2]));
             }
+            MidiEvent::Aborted { len } => {
+                println!("SysEx aborted after {} bytes", len);
+            }
+            MidiEvent::Overflow { len } => {
+                println!("Dropped oversized SysEx ({} bytes)", len);
+            }
+            MidiEvent::Message(_) | MidiEvent::Realtime(_) => {}
         }
     }
 
@@ -290,28 +423,69 @@ impl PicoEditApp {
         buffer: &[u8],
         storage_clone: &Arc<Mutex<Storage>>,
         status_clone: &Arc<Mutex<String>>,
+        pending_clone: &Arc<Mutex<Option<WriteSession>>>,
+        read_clone: &Arc<Mutex<Option<ReadSession>>>,
+        device_clone: &crossbeam_channel::Sender<DeviceReply>,
     ) {
         if buffer.len() >= 5 && buffer[1] == MANUFACTURER_ID && buffer[2] == MODEL_ID {
             match buffer[3] {
+                CMD_WRITE_REQ
+                    if pending_clone
+                        .lock()
+                        .unwrap()
+                        .as_ref()
+                        .is_some_and(WriteSession::wants_dump) =>
+                {
+                    let result = Storage::from_sysex(buffer);
+                    if let Some(session) = pending_clone.lock().unwrap().as_mut() {
+                        *status_clone.lock().unwrap() = session.on_dump(result);
+                    }
+                }
+                CMD_WRITE_REQ | CMD_PRESET_DATA if !Self::take_read_reply(buffer, read_clone) => {
+                    log::warn!(
+                        "Ignoring late or duplicate reply (command {:02X})",
+                        buffer[3]
+                    );
+                }
                 CMD_WRITE_REQ => match Storage::from_sysex(buffer) {
-                    Some(new_storage) => {
+                    Ok(new_storage) => {
                         let count = new_storage.presets.len();
+                        let upgraded = upgrade_note(new_storage.version);
+                        let _ = device_clone.send(DeviceReply::DumpVersion(new_storage.version));
                         *storage_clone.lock().unwrap() = new_storage;
-                        *status_clone.lock().unwrap() = format!("Loaded {} presets!", count);
+                        *status_clone.lock().unwrap() =
+                            format!("Loaded {} presets!{}", count, upgraded);
                     }
-                    None => {
-                        println!("Failed to parse SysEx via Storage::from_sysex!");
-                        *status_clone.lock().unwrap() = "Failed to parse Dump!".to_string();
+                    Err(e) => {
+                        println!("Failed to parse SysEx via Storage::from_sysex: {}", e);
+                        *status_clone.lock().unwrap() = receive_error("Dump", &e);
                     }
                 },
+                CMD_PRESET_DATA => {
+                    let result = storage_clone.lock().unwrap().apply_preset_sysex(buffer);
+                    match result {
+                        Ok(index) => {
+                            *status_clone.lock().unwrap() = format!("Loaded preset {}!", index + 1);
+                        }
+                        Err(e) => {
+                            println!("Failed to parse Preset Data: {}", e);
+                            *status_clone.lock().unwrap() = receive_error("Preset", &e);
+                        }
+                    }
+                }
                 CMD_WRITE_SUCCESS => {
-                    *status_clone.lock().unwrap() = "Save Successful!".to_string();
+                    *status_clone.lock().unwrap() = match pending_clone.lock().unwrap().as_mut() {
+                        Some(session) => session.on_ack(),
+                        None => "Save Successful!".to_string(),
+                    };
                 }
                 CMD_WRITE_ERROR => {
                     let err_code = if buffer.len() > 4 { buffer[4] } else { 0 };
                     println!("Received Write Error (NAK): Code {}", err_code);
-                    *status_clone.lock().unwrap() =
-                        format!("Save Failed! Error Code: {}", err_code);
+                    *status_clone.lock().unwrap() = match pending_clone.lock().unwrap().as_mut() {
+                        Some(session) => session.on_nak(err_code),
+                        None => format!("Save Failed! Error Code: {}", err_code),
+                    };
                 }
                 _ => {
                     println!("Unknown Command: {:02X}", buffer[3]);
@@ -322,15 +496,155 @@ impl PicoEditApp {
         }
     }
 
+    /// Ends the outstanding request if `msg` is its reply. Returns false for
+    /// replies nobody is waiting for.
+    fn take_read_reply(msg: &[u8], read_clone: &Arc<Mutex<Option<ReadSession>>>) -> bool {
+        let mut read = read_clone.lock().unwrap();
+        if read.as_ref().is_some_and(|r| r.accepts(msg)) {
+            *read = None;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn start_read_session(&self, target: ReadTarget) -> Vec<u8> {
+        let session = ReadSession::new(
+            target,
+            self.target_version,
+            self.send_checksums,
+            Duration::from_secs_f32(self.read_timeout_secs),
+            self.read_retries,
+            Instant::now(),
+        );
+        let msg = session.message().to_vec();
+        *self.pending_read.lock().unwrap() = Some(session);
+        msg
+    }
+
+    /// Retries or gives up on the outstanding dump or preset request.
+    fn poll_read_session(&mut self, ctx: &egui::Context) {
+        let step = match self.pending_read.lock().unwrap().as_mut() {
+            Some(session) => session.poll(Instant::now()),
+            None => return,
+        };
+        match step {
+            ReadStep::Wait => {}
+            ReadStep::Resend(msg, status) => {
+                *self.status_msg.lock().unwrap() = status;
+                if let Err(e) = self.transport.send(&msg) {
+                    *self.pending_read.lock().unwrap() = None;
+                    *self.status_msg.lock().unwrap() = format!("Failed to resend request: {}", e);
+                }
+            }
+            ReadStep::Failed(status) => {
+                *self.pending_read.lock().unwrap() = None;
+                *self.status_msg.lock().unwrap() = status;
+            }
+        }
+        if self.pending_read.lock().unwrap().is_some() {
+            ctx.request_repaint_after(Duration::from_millis(100));
+        }
+    }
+
+    fn cancel_read(&mut self) {
+        if self.pending_read.lock().unwrap().take().is_some() {
+            *self.status_msg.lock().unwrap() = "Transfer cancelled".to_string();
+        }
+    }
+
+    /// Asks the device who it is. Firmware without `CMD_INFO_REQ` support
+    /// simply does not answer the second request.
+    fn send_identity_requests(&mut self) {
+        self.identity = None;
+        self.firmware_info = None;
+        self.target_version = DEFAULT_WRITE_VERSION;
+        let info_req = [
+            SYSEX_START,
+            MANUFACTURER_ID,
+            MODEL_ID,
+            CMD_INFO_REQ,
+            SYSEX_END,
+        ];
+        for msg in [&IDENTITY_REQUEST[..], &info_req[..]] {
+            if let Err(e) = self.transport.send(msg) {
+                println!("Failed to send Identity Request: {}", e);
+            }
+        }
+    }
+
+    /// Adopts the preset format and features the device reported.
+    fn apply_device_replies(&mut self) {
+        while let Ok(reply) = self.device_rx.try_recv() {
+            match reply {
+                DeviceReply::Identity(identity) => {
+                    if !identity.is_picodsp() {
+                        *self.status_msg.lock().unwrap() =
+                            format!("Connected device is not a picoDSP: {}", identity.model());
+                    }
+                    self.identity = Some(identity);
+                }
+                DeviceReply::Info(info) => {
+                    if SUPPORTED_VERSIONS.contains(&info.storage_version) {
+                        self.target_version = info.storage_version;
+                    } else {
+                        *self.status_msg.lock().unwrap() = format!(
+                            "Device stores preset format v{}, which this editor does not support",
+                            info.storage_version
+                        );
+                    }
+                    self.single_preset_writes = info.features.preset_commands;
+                    self.send_checksums = info.features.checksums;
+                    self.firmware_info = Some(info);
+                }
+                // The device reads back what it sent.
+                DeviceReply::DumpVersion(version) => self.target_version = version,
+            }
+        }
+    }
+
+    /// Preset slots on the device in the target format: what fits in flash,
+    /// or fewer if the device reported so.
+    fn capacity(&self) -> usize {
+        let fits = max_presets(self.target_version).unwrap_or(MAX_PRESETS);
+        match &self.firmware_info {
+            Some(info) if info.storage_version == self.target_version => fits.min(info.capacity),
+            _ => fits,
+        }
+    }
+
+    /// One line summary of the connected device for the top panel.
+    fn device_summary(&self) -> String {
+        let mut parts = Vec::new();
+        match &self.identity {
+            Some(identity) => {
+                parts.push(identity.model());
+                parts.push(format!("firmware {}", identity.firmware()));
+            }
+            None if self.firmware_info.is_none() => return "Device: not identified".to_string(),
+            None => parts.push("picoDSP".to_string()),
+        }
+        if let Some(info) = &self.firmware_info {
+            if self.identity.is_none() {
+                let [major, minor, patch] = info.firmware;
+                parts.push(format!("firmware {}.{}.{}", major, minor, patch));
+            }
+            parts.push(format!("format v{}", info.storage_version));
+            parts.push(format!("{} slots", info.capacity));
+        }
+        format!("Device: {}", parts.join(", "))
+    }
+
     fn send_dump_request(&mut self) {
-        if let Some(conn) = &mut self.conn_out {
-            let msg = [0xF0, MANUFACTURER_ID, MODEL_ID, CMD_DUMP_REQ, 0xF7];
-            match conn.send(&msg) {
+        if self.transport.has_output() {
+            let msg = self.start_read_session(ReadTarget::Bank);
+            match self.transport.send(&msg) {
                 Ok(_) => {
                     *self.status_msg.lock().unwrap() = "Sent Dump Request".to_string();
                 }
                 Err(e) => {
                     println!("Failed to send Dump Request: {}", e);
+                    *self.pending_read.lock().unwrap() = None;
                     *self.status_msg.lock().unwrap() =
                         format!("Failed to send Dump Request: {}", e);
                 }
@@ -341,13 +655,88 @@ impl PicoEditApp {
         }
     }
 
+    fn send_current_preset(&mut self) {
+        if !self.single_preset_writes {
+            self.send_storage();
+            return;
+        }
+
+        if self.transport.has_output() {
+            let storage = self.storage.lock().unwrap();
+            let index = self.current_preset_index;
+            let warnings: Vec<MigrationWarning> = storage
+                .downgrade_warnings(self.target_version)
+                .into_iter()
+                .filter(|w| w.index == index)
+                .collect();
+            let msg = match storage.preset_sysex(index, self.target_version, self.send_checksums) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    *self.status_msg.lock().unwrap() = format!("Failed to encode Preset: {}", e);
+                    return;
+                }
+            };
+            match self.transport.send(&msg) {
+                Ok(_) => {
+                    self.start_write_session(WriteTarget::Preset(index), msg.clone());
+                    *self.status_msg.lock().unwrap() = format!(
+                        "Sent preset {} ({} bytes, v{}){}",
+                        index + 1,
+                        msg.len(),
+                        self.target_version,
+                        downgrade_note(&warnings)
+                    );
+                }
+                Err(e) => {
+                    println!("Failed to send Preset: {}", e);
+                    *self.status_msg.lock().unwrap() = format!("Failed to send Preset: {}", e);
+                }
+            }
+        } else {
+            *self.status_msg.lock().unwrap() = "Not connected to MIDI Output".to_string();
+        }
+    }
+
+    fn send_preset_request(&mut self) {
+        if self.transport.has_output() {
+            let msg = self.start_read_session(ReadTarget::Preset(self.current_preset_index));
+            match self.transport.send(&msg) {
+                Ok(_) => {
+                    *self.status_msg.lock().unwrap() =
+                        format!("Requested preset {}", self.current_preset_index + 1);
+                }
+                Err(e) => {
+                    println!("Failed to send Preset Request: {}", e);
+                    *self.pending_read.lock().unwrap() = None;
+                    *self.status_msg.lock().unwrap() =
+                        format!("Failed to send Preset Request: {}", e);
+                }
+            }
+        } else {
+            *self.status_msg.lock().unwrap() = "Not connected to MIDI Output".to_string();
+        }
+    }
+
     fn send_storage(&mut self) {
-        if let Some(conn) = &mut self.conn_out {
+        if self.transport.has_output() {
             let storage = self.storage.lock().unwrap();
-            let msg = storage.to_sysex();
-            match conn.send(&msg) {
+            let warnings = storage.downgrade_warnings(self.target_version);
+            let msg = match storage.to_sysex(self.target_version, self.send_checksums) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    *self.status_msg.lock().unwrap() = format!("Failed to encode Storage: {}", e);
+                    return;
+                }
+            };
+            match self.transport.send(&msg) {
                 Ok(_) => {
-                    *self.status_msg.lock().unwrap() = format!("Sent {} bytes", msg.len());
+                    self.start_write_session(WriteTarget::Bank, msg.clone());
+                    *self.status_msg.lock().unwrap() = format!(
+                        "Sent {} bytes (v{}){}",
+                        msg.len(),
+                        self.target_version,
+                        downgrade_note(&warnings)
+                    );
                 }
                 Err(e) => {
                     println!("Failed to send Storage: {}", e);
@@ -359,23 +748,214 @@ impl PicoEditApp {
         }
     }
 
+    fn start_write_session(&self, target: WriteTarget, msg: Vec<u8>) {
+        // The message was built by us, so decoding it cannot fail.
+        *self.pending_write.lock().unwrap() =
+            WriteSession::new(target, msg, self.verify_writes, Instant::now()).ok();
+    }
+
+    /// Drives timeouts, retries and the verify read-back of the last write.
+    fn poll_write_session(&mut self, ctx: &egui::Context) {
+        let step = match self.pending_write.lock().unwrap().as_mut() {
+            Some(session) => session.poll(Instant::now()),
+            None => return,
+        };
+        match step {
+            Step::Wait => {}
+            Step::Send(msg, status) => {
+                *self.status_msg.lock().unwrap() = status;
+                if self.transport.send(&msg).is_err() {
+                    *self.pending_write.lock().unwrap() = None;
+                    *self.status_msg.lock().unwrap() =
+                        "Lost connection to MIDI Output during write".to_string();
+                }
+            }
+            Step::Failed(status) => {
+                *self.pending_write.lock().unwrap() = None;
+                *self.status_msg.lock().unwrap() = status;
+            }
+            Step::FallbackToBank => {
+                *self.pending_write.lock().unwrap() = None;
+                self.send_storage();
+            }
+            Step::Done => {
+                *self.pending_write.lock().unwrap() = None;
+            }
+        }
+        if self.pending_write.lock().unwrap().is_some() {
+            ctx.request_repaint_after(Duration::from_millis(100));
+        }
+    }
+
     fn send_program_change(&mut self, program: u8) {
-        if let Some(conn) = &mut self.conn_out {
+        if self.transport.has_output() {
             let msg = [0xC0, program];
-            if let Err(e) = conn.send(&msg) {
+            if let Err(e) = self.transport.send(&msg) {
                 println!("Failed to send Program Change: {}", e);
             }
         }
     }
 
-    fn send_note(&mut self, note: u8, velocity: u8, on: bool) {
-        if self.audio_mode == AudioMode::Remote {
-            if let Some(conn) = &mut self.conn_out {
-                let cmd = if on { 0x90 } else { 0x80 };
-                let msg = [cmd, note, velocity];
-                if let Err(e) = conn.send(&msg) {
-                    println!("Failed to send Note: {}", e);
+    fn apply_incoming_cc(&mut self) {
+        let mut storage = self.storage.lock().unwrap();
+        while let Ok((cc, value)) = self.cc_rx.try_recv() {
+            let Some(d) = params::desc_for_cc(cc) else {
+                continue;
+            };
+            let index = self.current_preset_index;
+            if let Some(preset) = storage.presets.get_mut(index) {
+                let value = d.value_from_cc(value);
+                if preset.set_param(d.id, value).is_ok() {
+                    // The device already has this value, do not echo it back
+                    self.param_stream.sync(index, d.id, value);
+                }
+            }
+        }
+    }
+
+    fn stream_params(&mut self, ctx: &egui::Context) {
+        let supported = self
+            .firmware_info
+            .as_ref()
+            .is_none_or(|info| info.features.live_params);
+        if self.audio_mode != AudioMode::Remote || !self.transport.has_output() || !supported {
+            self.param_stream.reset();
+            return;
+        }
+
+        {
+            let storage = self.storage.lock().unwrap();
+            if let Some(preset) = storage.presets.get(self.current_preset_index) {
+                self.param_stream.track(self.current_preset_index, preset);
+            }
+        }
+
+        if self.transport.has_output() {
+            for msg in self.param_stream.poll(Instant::now()) {
+                if let Err(e) = self.transport.send(&msg) {
+                    println!("Failed to send Parameter: {}", e);
+                }
+            }
+        }
+
+        if self.param_stream.has_pending() {
+            ctx.request_repaint_after(Duration::from_millis(5));
+        }
+    }
+
+    /// Closes the audio output and opens the one in `audio_settings`, with
+    /// the engine set up as before.
+    fn reopen_audio(&mut self) {
+        // Some devices can only be opened once.
+        self.audio = None;
+        self.output_choices = None;
+        let status = match AudioManager::new(&self.audio_settings) {
+            Ok(audio) => {
+                audio.set_polyphony(self.polyphony);
+                audio.set_mono_mode(self.note_priority, self.legato);
+                let storage = self.storage.lock().unwrap();
+                if let Some(preset) = storage.presets.get(self.current_preset_index) {
+                    audio.update_preset(preset);
+                }
+                drop(storage);
+                let status = format!("Audio output: {}", audio.output);
+                self.audio = Some(audio);
+                status
+            }
+            Err(e) => format!("Failed to open audio output: {}", e),
+        };
+        *self.status_msg.lock().unwrap() = status;
+
+        let settings = settings::Settings {
+            audio: self.audio_settings.clone(),
+        };
+        if let Err(e) = settings::save(&settings) {
+            println!("Failed to save settings: {}", e);
+        }
+    }
+
+    fn draw_output_window(&mut self, ctx: &egui::Context) {
+        let mut open = self.show_output_window;
+        egui::Window::new("Audio Output")
+            .open(&mut open)
+            .resizable(false)
+            .show(ctx, |ui| {
+                let choices = self
+                    .output_choices
+                    .get_or_insert_with(|| OutputChoices::query(&self.audio_settings));
+                let mut settings = self.audio_settings.clone();
+                egui::Grid::new("output_grid")
+                    .num_columns(2)
+                    .show(ui, |ui| {
+                        ui.label("Host:");
+                        ui::default_combo(
+                            ui,
+                            "output_host",
+                            &mut settings.host,
+                            &choices.hosts,
+                            |h| h.clone(),
+                        );
+                        ui.end_row();
+                        ui.label("Device:");
+                        ui::default_combo(
+                            ui,
+                            "output_device",
+                            &mut settings.device,
+                            &choices.devices,
+                            |d| d.clone(),
+                        );
+                        ui.end_row();
+                        ui.label("Sample Rate:");
+                        ui::default_combo(
+                            ui,
+                            "output_rate",
+                            &mut settings.sample_rate,
+                            &choices.sample_rates,
+                            |r| format!("{} Hz", r),
+                        );
+                        ui.end_row();
+                        ui.label("Buffer:");
+                        ui::default_combo(
+                            ui,
+                            "output_buffer",
+                            &mut settings.buffer_frames,
+                            &choices.buffer_frames,
+                            |n| format!("{} frames", n),
+                        );
+                        ui.end_row();
+                    });
+                ui.separator();
+                match &self.audio {
+                    Some(audio) => ui.label(audio.output.to_string()),
+                    None => ui.label("No output open"),
+                };
+                let reopen = ui
+                    .button("Reopen")
+                    .on_hover_text("Open the output again, e.g. after plugging a device in")
+                    .clicked();
+
+                // What a device supports depends on the ones picked above it.
+                if settings.host != self.audio_settings.host {
+                    settings.device = None;
                 }
+                if settings.device != self.audio_settings.device {
+                    settings.sample_rate = None;
+                    settings.buffer_frames = None;
+                }
+                if reopen || settings != self.audio_settings {
+                    self.audio_settings = settings;
+                    self.reopen_audio();
+                }
+            });
+        self.show_output_window = open;
+    }
+
+    fn send_note(&mut self, note: u8, velocity: u8, on: bool) {
+        if self.audio_mode == AudioMode::Remote && self.transport.has_output() {
+            let cmd = if on { 0x90 } else { 0x80 };
+            let msg = [cmd, note, velocity];
+            if let Err(e) = self.transport.send(&msg) {
+                println!("Failed to send Note: {}", e);
             }
         }
 
@@ -388,9 +968,9 @@ impl PicoEditApp {
                 drop(storage);
 
                 if on {
-                    audio.note_on(note);
+                    audio.note_on(note, velocity);
                 } else {
-                    audio.note_off();
+                    audio.note_off(note);
                 }
             }
         }
@@ -398,16 +978,39 @@ impl PicoEditApp {
 
     fn load_from_file(&mut self) {
         if let Some(path) = rfd::FileDialog::new()
-            .add_filter("PicoDSP Preset", &["pdsp"])
+            .add_filter("SysEx", &["pdsp", "syx"])
             .pick_file()
         {
             if let Ok(data) = fs::read(&path) {
-                if let Some(new_storage) = Storage::from_sysex(&data) {
-                    *self.storage.lock().unwrap() = new_storage;
-                    *self.status_msg.lock().unwrap() = format!("Loaded from {}", path.display());
-                    self.current_preset_index = 0;
-                } else {
-                    *self.status_msg.lock().unwrap() = "Failed to parse SysEx file".to_string();
+                match syx::read_syx(&data) {
+                    Ok(file) => {
+                        for skipped in &file.skipped {
+                            log::warn!("{}: skipped {}", path.display(), skipped);
+                        }
+                        let skipped = if file.skipped.is_empty() {
+                            String::new()
+                        } else {
+                            format!(
+                                ", {} picoDSP messages ({} skipped, first at {})",
+                                file.used,
+                                file.skipped.len(),
+                                file.skipped[0]
+                            )
+                        };
+                        let upgraded = upgrade_note(file.storage.version);
+                        *self.storage.lock().unwrap() = file.storage;
+                        *self.status_msg.lock().unwrap() =
+                            format!("Loaded from {}{}{}", path.display(), upgraded, skipped);
+                        self.current_preset_index = 0;
+                    }
+                    Err(e @ ProtocolError::ChecksumMismatch { .. }) => {
+                        *self.status_msg.lock().unwrap() =
+                            format!("{} is corrupted: {}", path.display(), e);
+                    }
+                    Err(e) => {
+                        *self.status_msg.lock().unwrap() =
+                            format!("Failed to parse {}: {}", path.display(), e);
+                    }
                 }
             } else {
                 *self.status_msg.lock().unwrap() = "Failed to read file".to_string();
@@ -415,44 +1018,210 @@ impl PicoEditApp {
         }
     }
 
-    fn save_to_file(&self) {
-        if let Some(path) = rfd::FileDialog::new()
-            .add_filter("PicoDSP Preset", &["pdsp"])
-            .save_file()
-        {
+    /// Writes the bank as SysEx, either as one dump (`.pdsp` or `.syx`) or as
+    /// a `.syx` file with one message per preset.
+    fn save_to_file(&self, layout: SyxLayout) {
+        let dialog = match layout {
+            SyxLayout::Dump => rfd::FileDialog::new()
+                .add_filter("PicoDSP Preset", &["pdsp"])
+                .add_filter("SysEx", &["syx"]),
+            SyxLayout::PerPreset => rfd::FileDialog::new().add_filter("SysEx", &["syx"]),
+        };
+        if let Some(path) = dialog.save_file() {
             let storage = self.storage.lock().unwrap();
-            let data = storage.to_sysex();
+            let warnings = storage.downgrade_warnings(self.target_version);
+            let data =
+                match syx::write_syx(&storage, self.target_version, layout, self.send_checksums) {
+                    Ok(data) => data,
+                    Err(e) => {
+                        *self.status_msg.lock().unwrap() =
+                            format!("Failed to encode Storage: {}", e);
+                        return;
+                    }
+                };
             if fs::write(&path, data).is_ok() {
-                *self.status_msg.lock().unwrap() = format!("Saved to {}", path.display());
+                *self.status_msg.lock().unwrap() = format!(
+                    "Saved to {} (v{}){}",
+                    path.display(),
+                    self.target_version,
+                    downgrade_note(&warnings)
+                );
             } else {
                 *self.status_msg.lock().unwrap() = "Failed to write file".to_string();
             }
         }
     }
 
-    fn draw_top_panel(&mut self, ui: &mut egui::Ui) {
-        ui.horizontal(|ui| {
-            if let Some(midi_in) = &self.midi_in {
-                egui::ComboBox::from_id_salt("midi_in")
-                    .selected_text(self.in_port_name.as_deref().unwrap_or("Select Input"))
-                    .show_ui(ui, |ui| {
-                        for port in midi_in.ports() {
-                            let name = midi_in.port_name(&port).unwrap();
-                            ui.selectable_value(&mut self.in_port_name, Some(name.clone()), name);
-                        }
-                    });
+    fn export_preset(&self) {
+        let Some(path) = rfd::FileDialog::new()
+            .add_filter("JSON", &["json"])
+            .add_filter("TOML", &["toml"])
+            .save_file()
+        else {
+            return;
+        };
+        let format = DocFormat::from_path(&path).unwrap_or(DocFormat::Json);
+        let storage = self.storage.lock().unwrap();
+        let Some(preset) = storage.presets.get(self.current_preset_index) else {
+            return;
+        };
+        let result = preset_doc::preset_to_string(preset, format)
+            .and_then(|text| fs::write(&path, text).map_err(Into::into));
+        *self.status_msg.lock().unwrap() = match result {
+            Ok(()) => format!("Exported '{}' to {}", preset.name, path.display()),
+            Err(e) => format!("Failed to export {}: {}", path.display(), e),
+        };
+    }
+
+    fn render_preset(&self, format: WavFormat) {
+        let Some(path) = rfd::FileDialog::new()
+            .add_filter("WAV", &["wav"])
+            .save_file()
+        else {
+            return;
+        };
+        let storage = self.storage.lock().unwrap();
+        let Some(preset) = storage.presets.get(self.current_preset_index) else {
+            return;
+        };
+        let samples = render::render(
+            preset,
+            &[PREVIEW_NOTE],
+            PREVIEW_SAMPLE_RATE,
+            PREVIEW_TAIL_SECS,
+        );
+        let wav = render::encode_wav(&samples, PREVIEW_SAMPLE_RATE, format);
+        *self.status_msg.lock().unwrap() = match fs::write(&path, wav) {
+            Ok(()) => format!("Rendered '{}' to {}", preset.name, path.display()),
+            Err(e) => format!("Failed to write {}: {}", path.display(), e),
+        };
+    }
+
+    fn export_bank(&self) {
+        let Some(path) = rfd::FileDialog::new()
+            .add_filter("JSON", &["json"])
+            .add_filter("TOML", &["toml"])
+            .save_file()
+        else {
+            return;
+        };
+        let format = DocFormat::from_path(&path).unwrap_or(DocFormat::Json);
+        let storage = self.storage.lock().unwrap();
+        let result = preset_doc::bank_to_string(&storage, format)
+            .and_then(|text| fs::write(&path, text).map_err(Into::into));
+        *self.status_msg.lock().unwrap() = match result {
+            Ok(()) => format!("Exported bank to {}", path.display()),
+            Err(e) => format!("Failed to export {}: {}", path.display(), e),
+        };
+    }
+
+    /// Replaces the current preset with one read from a JSON or TOML file.
+    fn import_preset(&mut self) {
+        let Some(path) = rfd::FileDialog::new()
+            .add_filter("Preset", &["json", "toml"])
+            .pick_file()
+        else {
+            return;
+        };
+        let result = read_doc(&path, preset_doc::preset_from_str);
+        let mut storage = self.storage.lock().unwrap();
+        *self.status_msg.lock().unwrap() = match result {
+            Ok(preset) => {
+                let msg = format!("Imported '{}' from {}", preset.name, path.display());
+                match storage.presets.get_mut(self.current_preset_index) {
+                    Some(slot) => *slot = preset,
+                    None => {
+                        storage.presets.push(preset);
+                        self.current_preset_index = storage.presets.len() - 1;
+                    }
+                }
+                msg
             }
+            Err(e) => format!("Failed to import {}: {}", path.display(), e),
+        };
+    }
 
-            if let Some(midi_out) = &self.midi_out {
-                egui::ComboBox::from_id_salt("midi_out")
-                    .selected_text(self.out_port_name.as_deref().unwrap_or("Select Output"))
-                    .show_ui(ui, |ui| {
-                        for port in midi_out.ports() {
-                            let name = midi_out.port_name(&port).unwrap();
-                            ui.selectable_value(&mut self.out_port_name, Some(name.clone()), name);
-                        }
-                    });
+    fn import_bank(&mut self) {
+        let Some(path) = rfd::FileDialog::new()
+            .add_filter("Bank", &["json", "toml"])
+            .pick_file()
+        else {
+            return;
+        };
+        *self.status_msg.lock().unwrap() = match read_doc(&path, preset_doc::bank_from_str) {
+            Ok(new_storage) => {
+                let msg = format!(
+                    "Imported {} presets from {}",
+                    new_storage.presets.len(),
+                    path.display()
+                );
+                *self.storage.lock().unwrap() = new_storage;
+                self.current_preset_index = 0;
+                msg
             }
+            Err(e) => format!("Failed to import {}: {}", path.display(), e),
+        };
+    }
+
+    fn draw_controller_selection(&mut self, ui: &mut egui::Ui) {
+        let ports = self
+            .controller
+            .as_ref()
+            .map(|c| c.input_ports())
+            .unwrap_or_default();
+        let mut changed = false;
+
+        ui.label("Controller:");
+        egui::ComboBox::from_id_salt("controller_in")
+            .selected_text(self.controller_port_name.as_deref().unwrap_or("None"))
+            .show_ui(ui, |ui| {
+                changed |= ui
+                    .selectable_value(&mut self.controller_port_name, None, "None")
+                    .changed();
+                for name in ports {
+                    changed |= ui
+                        .selectable_value(&mut self.controller_port_name, Some(name.clone()), name)
+                        .changed();
+                }
+            });
+        egui::ComboBox::from_id_salt("controller_channel")
+            .selected_text(match self.controller_channel {
+                Some(channel) => format!("Ch {}", channel + 1),
+                None => "Omni".to_string(),
+            })
+            .show_ui(ui, |ui| {
+                ui.selectable_value(&mut self.controller_channel, None, "Omni");
+                for channel in 0..16 {
+                    ui.selectable_value(
+                        &mut self.controller_channel,
+                        Some(channel),
+                        format!("Ch {}", channel + 1),
+                    );
+                }
+            });
+
+        if changed {
+            self.connect_controller(&ui.ctx().clone());
+        }
+    }
+
+    fn draw_top_panel(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            egui::ComboBox::from_id_salt("midi_in")
+                .selected_text(self.in_port_name.as_deref().unwrap_or("Select Input"))
+                .show_ui(ui, |ui| {
+                    for name in self.transport.input_ports() {
+                        ui.selectable_value(&mut self.in_port_name, Some(name.clone()), name);
+                    }
+                });
+
+            egui::ComboBox::from_id_salt("midi_out")
+                .selected_text(self.out_port_name.as_deref().unwrap_or("Select Output"))
+                .show_ui(ui, |ui| {
+                    for name in self.transport.output_ports() {
+                        ui.selectable_value(&mut self.out_port_name, Some(name.clone()), name);
+                    }
+                });
 
             ui.label("Audio Mode:");
             egui::ComboBox::from_id_salt("audio_mode")
@@ -477,6 +1246,26 @@ impl PicoEditApp {
             if ui.button("Refresh").clicked() {
                 self.refresh_midi();
             }
+
+            if self.controller.is_some() {
+                ui.separator();
+                self.draw_controller_selection(ui);
+            }
+
+            ui.separator();
+            ui.label("Timeout:");
+            ui.add(
+                egui::DragValue::new(&mut self.read_timeout_secs)
+                    .range(0.5..=30.0)
+                    .speed(0.1)
+                    .suffix(" s"),
+            )
+            .on_hover_text("How long to wait for the device to answer a request");
+            ui.label("Retries:");
+            ui.add(egui::DragValue::new(&mut self.read_retries).range(0..=10));
+
+            ui.separator();
+            ui.label(self.device_summary());
         });
 
         ui.separator();
@@ -485,9 +1274,18 @@ impl PicoEditApp {
             if ui.button("Load from Device").clicked() {
                 self.send_dump_request();
             }
+            if ui.button("Reload Preset").clicked() {
+                self.send_preset_request();
+            }
             if ui.button("Save to Device").clicked() {
-                self.send_storage();
+                self.send_current_preset();
             }
+            ui.checkbox(&mut self.single_preset_writes, "Current preset only")
+                .on_hover_text("Write only the current preset. Turned on when the device reports support for single-preset commands");
+            ui.checkbox(&mut self.verify_writes, "Verify")
+                .on_hover_text("Read the bank back after the device confirms a write and compare it with what was sent");
+            ui.checkbox(&mut self.send_checksums, "CRC")
+                .on_hover_text("Append a CRC-16 to sent data. Needs firmware with checksum support; received data is always verified when it carries one.");
 
             ui.separator();
 
@@ -495,16 +1293,117 @@ impl PicoEditApp {
                 self.load_from_file();
             }
             if ui.button("Save File").clicked() {
-                self.save_to_file();
+                self.save_to_file(SyxLayout::Dump);
             }
+            ui.menu_button("Export", |ui| {
+                if ui.button("Export Preset…").clicked() {
+                    ui.close_menu();
+                    self.export_preset();
+                }
+                if ui.button("Export Bank…").clicked() {
+                    ui.close_menu();
+                    self.export_bank();
+                }
+                if ui
+                    .button("Export Bank as Preset Messages (.syx)…")
+                    .clicked()
+                {
+                    ui.close_menu();
+                    self.save_to_file(SyxLayout::PerPreset);
+                }
+                ui.separator();
+                if ui.button("Render Preset to WAV (24-bit)…").clicked() {
+                    ui.close_menu();
+                    self.render_preset(WavFormat::Pcm24);
+                }
+                if ui.button("Render Preset to WAV (32-bit float)…").clicked() {
+                    ui.close_menu();
+                    self.render_preset(WavFormat::Float32);
+                }
+            });
+            ui.menu_button("Import", |ui| {
+                if ui.button("Import Preset…").clicked() {
+                    ui.close_menu();
+                    self.import_preset();
+                }
+                if ui.button("Import Bank…").clicked() {
+                    ui.close_menu();
+                    self.import_bank();
+                }
+            });
+
+            ui.label("Format:");
+            egui::ComboBox::from_id_salt("target_version")
+                .selected_text(format!("v{}", self.target_version))
+                .show_ui(ui, |ui| {
+                    for &version in SUPPORTED_VERSIONS {
+                        ui.selectable_value(
+                            &mut self.target_version,
+                            version,
+                            format!("v{}", version),
+                        );
+                    }
+                });
 
             ui.label(self.status_msg.lock().unwrap().as_str());
+
+            let progress = self.pending_read.lock().unwrap().as_ref().map(ReadSession::progress);
+            if let Some(progress) = progress {
+                ui.spinner();
+                ui.label(progress);
+                if ui.button("Cancel").clicked() {
+                    self.cancel_read();
+                }
+            }
         });
     }
 }
 
+/// Reads a JSON or TOML document, picking the parser from the file extension.
+fn read_doc<T>(
+    path: &std::path::Path,
+    parse: fn(&str, DocFormat) -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    let format = DocFormat::from_path(path)
+        .ok_or_else(|| anyhow::anyhow!("unknown file type, expected .json or .toml"))?;
+    parse(&fs::read_to_string(path)?, format)
+}
+
+/// Status text for a dump or preset from the device that could not be used.
+/// Checksum failures mean the transfer was damaged, not that the data is
+/// in an unknown format, so they get their own message.
+fn receive_error(what: &str, e: &ProtocolError) -> String {
+    match e {
+        ProtocolError::ChecksumMismatch { .. } => {
+            format!("{} corrupted in transfer, nothing changed: {}", what, e)
+        }
+        _ => format!("Failed to parse {}: {}", what, e),
+    }
+}
+
+fn upgrade_note(version: u32) -> String {
+    if version != VERSION {
+        format!(" (upgraded from v{})", version)
+    } else {
+        String::new()
+    }
+}
+
+fn downgrade_note(warnings: &[MigrationWarning]) -> String {
+    if warnings.is_empty() {
+        return String::new();
+    }
+    for w in warnings {
+        log::warn!("Downgrade loses data in preset {}", w);
+    }
+    let details: Vec<String> = warnings.iter().map(|w| w.to_string()).collect();
+    format!(" - WARNING: {}", details.join("; "))
+}
+
 impl eframe::App for PicoEditApp {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        self.transport.poll();
+
         egui::TopBottomPanel::top("top_panel").show(ctx, |ui| {
             self.draw_top_panel(ui);
         });
@@ -514,18 +1413,76 @@ impl eframe::App for PicoEditApp {
             .show(ctx, |ui| {
                 ui::draw_visualizer(ui, &self.audio, &self.fft_planner);
                 ui.separator();
-                ui.heading("PicoDSP");
+                ui.horizontal(|ui| {
+                    ui.heading("PicoDSP");
+                    ui.separator();
+                    ui.label("Voices:");
+                    let voices = ui
+                        .add(
+                            egui::DragValue::new(&mut self.polyphony)
+                                .range(1..=voices::MAX_POLYPHONY),
+                        )
+                        .on_hover_text("Polyphony of the local preview engine");
+                    if voices.changed() {
+                        if let Some(audio) = &self.audio {
+                            audio.set_polyphony(self.polyphony);
+                        }
+                    }
+
+                    let mono = self.polyphony == 1;
+                    let mut mono_changed = false;
+                    ui.add_enabled_ui(mono, |ui| {
+                        ui.label("Priority:");
+                        egui::ComboBox::from_id_salt("note_priority")
+                            .selected_text(self.note_priority.name())
+                            .show_ui(ui, |ui| {
+                                for priority in NotePriority::ALL {
+                                    mono_changed |= ui
+                                        .selectable_value(
+                                            &mut self.note_priority,
+                                            priority,
+                                            priority.name(),
+                                        )
+                                        .changed();
+                                }
+                            });
+                        mono_changed |= ui
+                            .checkbox(&mut self.legato, "Legato")
+                            .on_hover_text(
+                                "Glide between held notes without restarting the envelopes",
+                            )
+                            .changed();
+                    });
+                    if mono_changed {
+                        if let Some(audio) = &self.audio {
+                            audio.set_mono_mode(self.note_priority, self.legato);
+                        }
+                    }
+
+                    ui.separator();
+                    if ui
+                        .button("Audio Output…")
+                        .on_hover_text(
+                            "Host, device, sample rate and buffer size of the local engine",
+                        )
+                        .clicked()
+                    {
+                        self.show_output_window = !self.show_output_window;
+                    }
+                });
                 let piano = PianoWidget::new(36, 61);
                 piano.show(ui, &mut self.active_notes)
             })
             .inner;
 
+        let capacity = self.capacity();
         egui::CentralPanel::default().show(ctx, |ui| {
             egui::ScrollArea::vertical().show(ui, |ui| {
                 let mut storage = self.storage.lock().unwrap();
-                ui::draw_preset_editor(ui, &mut storage, &mut self.current_preset_index);
+                ui::draw_preset_editor(ui, &mut storage, &mut self.current_preset_index, capacity);
             });
         });
+        self.draw_output_window(ctx);
 
         if self.current_preset_index != self.last_preset_index {
             self.send_program_change(self.current_preset_index as u8);
@@ -539,6 +1496,14 @@ impl eframe::App for PicoEditApp {
             }
         }
 
+        self.apply_device_replies();
+        self.apply_incoming_cc();
+        self.apply_controller_events();
+        self.stream_params(ctx);
+
+        self.poll_write_session(ctx);
+        self.poll_read_session(ctx);
+
         for event in piano_events {
             self.send_note(event.note, event.velocity, event.pressed);
         }
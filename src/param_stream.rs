@@ -0,0 +1,166 @@
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+use crate::params::{ParamId, PARAMS};
+use crate::protocol::{param_sysex, Preset};
+
+/// Parameter messages per second. Each message is 14 bytes, so this keeps
+/// USB-MIDI traffic below 3 KB/s while a slider is being dragged.
+const MESSAGES_PER_SECOND: f32 = 200.0;
+/// Messages that may go out back to back after the stream has been idle.
+const BURST: f32 = 8.0;
+
+/// Turns edits of the current preset into rate limited `CMD_SET_PARAM`
+/// messages. Repeated changes of the same parameter are coalesced so only the
+/// latest value is sent.
+pub struct ParamStreamer {
+    baseline: Option<(usize, Preset)>,
+    pending: BTreeMap<ParamId, f32>,
+    tokens: f32,
+    last_refill: Instant,
+}
+
+impl Default for ParamStreamer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ParamStreamer {
+    pub fn new() -> Self {
+        Self {
+            baseline: None,
+            pending: BTreeMap::new(),
+            tokens: BURST,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Forgets what the device was last sent, e.g. when streaming is off.
+    pub fn reset(&mut self) {
+        self.baseline = None;
+        self.pending.clear();
+    }
+
+    /// Queues every parameter that differs from what the device last saw.
+    /// Switching to another preset only records a new baseline, since the
+    /// device loads that preset itself on the program change.
+    pub fn track(&mut self, index: usize, preset: &Preset) {
+        match &mut self.baseline {
+            Some((base_index, base)) if *base_index == index => {
+                let mut changed = false;
+                for desc in PARAMS {
+                    let value = preset.param(desc.id);
+                    if value != base.param(desc.id) {
+                        self.pending.insert(desc.id, desc.clamp(value));
+                        changed = true;
+                    }
+                }
+                if changed {
+                    *base = preset.clone();
+                }
+            }
+            _ => {
+                self.baseline = Some((index, preset.clone()));
+                self.pending.clear();
+            }
+        }
+    }
+
+    /// Records a value the device reported itself, so it is not sent back.
+    pub fn sync(&mut self, index: usize, id: ParamId, value: f32) {
+        if let Some((base_index, base)) = &mut self.baseline {
+            if *base_index == index {
+                let _ = base.set_param(id, value);
+            }
+        }
+    }
+
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Returns the messages that may be sent now without exceeding the rate.
+    pub fn poll(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f32();
+        self.last_refill = now;
+        self.tokens = (self.tokens + elapsed * MESSAGES_PER_SECOND).min(BURST);
+
+        let mut messages = Vec::new();
+        while self.tokens >= 1.0 {
+            match self.pending.pop_first() {
+                Some((id, value)) => {
+                    messages.push(param_sysex(id, value));
+                    self.tokens -= 1.0;
+                }
+                None => break,
+            }
+        }
+        messages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::params::ParamKind;
+    use std::time::Duration;
+
+    #[test]
+    fn repeated_changes_are_coalesced() {
+        let mut stream = ParamStreamer::new();
+        let now = Instant::now();
+        let mut preset = Preset::default();
+        stream.track(0, &preset);
+
+        for cutoff in [1000.0, 1500.0, 2000.0] {
+            preset.filter.cutoff = cutoff;
+            stream.track(0, &preset);
+        }
+        assert_eq!(
+            stream.poll(now),
+            vec![param_sysex(ParamId::FilterCutoff, 2000.0)]
+        );
+        assert!(!stream.has_pending());
+
+        // A value the device reported is not echoed back.
+        stream.sync(0, ParamId::FilterResonance, 0.5);
+        preset.filter.resonance = 0.5;
+        stream.track(0, &preset);
+        assert!(!stream.has_pending());
+
+        // Another preset only becomes the new baseline.
+        preset.filter.cutoff = 500.0;
+        stream.track(1, &preset);
+        assert!(!stream.has_pending());
+    }
+
+    #[test]
+    fn messages_are_rate_limited() {
+        let mut stream = ParamStreamer::new();
+        let start = Instant::now();
+        let mut preset = Preset::default();
+        stream.track(0, &preset);
+
+        let changed: Vec<_> = PARAMS
+            .iter()
+            .filter(|d| d.kind == ParamKind::Float && d.default != d.max)
+            .take(12)
+            .collect();
+        for desc in &changed {
+            preset.set_param(desc.id, desc.max).unwrap();
+        }
+        stream.track(0, &preset);
+
+        assert_eq!(stream.poll(start).len(), BURST as usize);
+        assert!(stream.poll(start).is_empty());
+        // A little over one message interval later.
+        let interval = Duration::from_secs_f32(1.2 / MESSAGES_PER_SECOND);
+        assert_eq!(stream.poll(start + interval).len(), 1);
+        assert_eq!(
+            stream.poll(start + Duration::from_secs(1)).len(),
+            changed.len() - BURST as usize - 1
+        );
+        assert!(!stream.has_pending());
+    }
+}
@@ -0,0 +1,302 @@
+use crate::protocol::{LfoWaveform, Preset, ProtocolError, Waveform};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Taper {
+    Linear,
+    Log,
+}
+
+/// How a parameter is stored: floats are written as f32, switches and
+/// choices as u32.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParamKind {
+    Float,
+    Toggle,
+    Choice(&'static [&'static str]),
+}
+
+#[derive(Debug, Clone)]
+pub struct ParamDesc {
+    pub id: ParamId,
+    /// `Preset` field path, e.g. "filter.cutoff". Also used as the section and
+    /// key in exported preset documents.
+    pub key: &'static str,
+    pub name: &'static str,
+    /// Short label used next to sliders, e.g. "A" for an envelope attack.
+    pub label: &'static str,
+    pub unit: &'static str,
+    pub min: f32,
+    pub max: f32,
+    pub default: f32,
+    pub taper: Taper,
+    pub kind: ParamKind,
+    /// Byte offset inside a preset in the current format version.
+    pub offset: usize,
+    /// First format version that stores this parameter.
+    pub since: u32,
+    pub cc: Option<u8>,
+}
+
+/// Conversion between a `Preset` field and the f32 used by the registry.
+pub trait ParamValue: Sized {
+    const KIND: ParamKind;
+    fn to_param(&self) -> f32;
+    fn from_param(value: f32) -> Result<Self, ProtocolError>;
+}
+
+impl ParamValue for f32 {
+    const KIND: ParamKind = ParamKind::Float;
+
+    fn to_param(&self) -> f32 {
+        *self
+    }
+
+    fn from_param(value: f32) -> Result<Self, ProtocolError> {
+        Ok(value)
+    }
+}
+
+impl ParamValue for bool {
+    const KIND: ParamKind = ParamKind::Toggle;
+
+    fn to_param(&self) -> f32 {
+        if *self {
+            1.0
+        } else {
+            0.0
+        }
+    }
+
+    fn from_param(value: f32) -> Result<Self, ProtocolError> {
+        Ok(value >= 0.5)
+    }
+}
+
+impl ParamValue for Waveform {
+    const KIND: ParamKind = ParamKind::Choice(&["Sine", "Triangle", "Saw", "Square", "Noise"]);
+
+    fn to_param(&self) -> f32 {
+        *self as u32 as f32
+    }
+
+    fn from_param(value: f32) -> Result<Self, ProtocolError> {
+        Waveform::try_from(value as u32)
+    }
+}
+
+impl ParamValue for LfoWaveform {
+    const KIND: ParamKind = ParamKind::Choice(&["Sine", "Triangle", "Saw", "Square"]);
+
+    fn to_param(&self) -> f32 {
+        *self as u32 as f32
+    }
+
+    fn from_param(value: f32) -> Result<Self, ProtocolError> {
+        LfoWaveform::try_from(value as u32)
+    }
+}
+
+/// Declares `ParamId`, the `PARAMS` table and the `Preset` accessors from a
+/// single list, so adding a parameter means adding one row here (plus the
+/// `Preset` field it maps to).
+macro_rules! params {
+    ($(
+        $id:ident = $num:literal => $($field:ident).+ : $ty:ty,
+        $name:literal, $label:literal, $unit:literal,
+        $min:literal..=$max:literal, default $default:literal, $taper:ident,
+        offset $offset:literal, since $since:literal, cc $cc:expr;
+    )*) => {
+        /// Stable identifiers for every numeric field of a `Preset`, used by
+        /// `CMD_SET_PARAM`. Ids are grouped by section (oscillators at
+        /// 0x00/0x08/0x10, noise and portamento at 0x18, filter at 0x20, amp
+        /// at 0x28, LFO at 0x30, delay at 0x38, reverb at 0x40, velocity at
+        /// 0x48) and must never be renumbered. Choices are sent as their
+        /// index and switches as 0.0/1.0. The preset name is only transferred
+        /// with whole-preset writes.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        pub enum ParamId {
+            $($id = $num,)*
+        }
+
+        pub const PARAMS: &[ParamDesc] = &[
+            $(ParamDesc {
+                id: ParamId::$id,
+                key: stringify!($($field).+),
+                name: $name,
+                label: $label,
+                unit: $unit,
+                min: $min,
+                max: $max,
+                default: $default,
+                taper: Taper::$taper,
+                kind: <$ty as ParamValue>::KIND,
+                offset: $offset,
+                since: $since,
+                cc: $cc,
+            },)*
+        ];
+
+        impl Preset {
+            pub fn param(&self, id: ParamId) -> f32 {
+                match id {
+                    $(ParamId::$id => self.$($field).+.to_param(),)*
+                }
+            }
+
+            pub fn set_param(&mut self, id: ParamId, value: f32) -> Result<(), ProtocolError> {
+                match id {
+                    $(ParamId::$id => self.$($field).+ = <$ty as ParamValue>::from_param(value)?,)*
+                }
+                Ok(())
+            }
+        }
+    };
+}
+
+params! {
+    Osc1Waveform = 0x00 => osc1.waveform: Waveform,
+        "Osc 1 Wave", "Wave", "", 0.0..=4.0, default 2.0, Linear, offset 32, since 7, cc None;
+    Osc1Level = 0x01 => osc1.level: f32,
+        "Osc 1 Level", "Level", "", 0.0..=1.0, default 1.0, Linear, offset 36, since 7, cc None;
+    Osc1Octave = 0x02 => osc1.octave: f32,
+        "Osc 1 Octave", "Octave", "oct", -2.0..=2.0, default 0.0, Linear, offset 40, since 7, cc None;
+    Osc1Detune = 0x03 => osc1.detune: f32,
+        "Osc 1 Detune", "Detune", "Hz", -100.0..=100.0, default 0.0, Linear, offset 44, since 7, cc None;
+    Osc1Vibrato = 0x04 => osc1.vibrato: bool,
+        "Osc 1 Vibrato", "Vibrato", "", 0.0..=1.0, default 0.0, Linear, offset 48, since 7, cc None;
+
+    Osc2Waveform = 0x08 => osc2.waveform: Waveform,
+        "Osc 2 Wave", "Wave", "", 0.0..=4.0, default 2.0, Linear, offset 52, since 7, cc None;
+    Osc2Level = 0x09 => osc2.level: f32,
+        "Osc 2 Level", "Level", "", 0.0..=1.0, default 0.0, Linear, offset 56, since 7, cc None;
+    Osc2Octave = 0x0A => osc2.octave: f32,
+        "Osc 2 Octave", "Octave", "oct", -2.0..=2.0, default 0.0, Linear, offset 60, since 7, cc None;
+    Osc2Detune = 0x0B => osc2.detune: f32,
+        "Osc 2 Detune", "Detune", "Hz", -100.0..=100.0, default 0.0, Linear, offset 64, since 7, cc None;
+    Osc2Vibrato = 0x0C => osc2.vibrato: bool,
+        "Osc 2 Vibrato", "Vibrato", "", 0.0..=1.0, default 0.0, Linear, offset 68, since 7, cc None;
+
+    Osc3Waveform = 0x10 => osc3.waveform: Waveform,
+        "Osc 3 Wave", "Wave", "", 0.0..=4.0, default 2.0, Linear, offset 72, since 7, cc None;
+    Osc3Level = 0x11 => osc3.level: f32,
+        "Osc 3 Level", "Level", "", 0.0..=1.0, default 0.0, Linear, offset 76, since 7, cc None;
+    Osc3Octave = 0x12 => osc3.octave: f32,
+        "Osc 3 Octave", "Octave", "oct", -2.0..=2.0, default 0.0, Linear, offset 80, since 7, cc None;
+    Osc3Detune = 0x13 => osc3.detune: f32,
+        "Osc 3 Detune", "Detune", "Hz", -100.0..=100.0, default 0.0, Linear, offset 84, since 7, cc None;
+    Osc3Vibrato = 0x14 => osc3.vibrato: bool,
+        "Osc 3 Vibrato", "Vibrato", "", 0.0..=1.0, default 0.0, Linear, offset 88, since 7, cc None;
+
+    Noise = 0x18 => noise: f32,
+        "Noise Level", "Noise Level", "", 0.0..=1.0, default 0.0, Linear, offset 92, since 7, cc None;
+    Portamento = 0x19 => portamento: f32,
+        "Portamento", "Portamento", "", 0.0..=1.0, default 0.0, Linear, offset 96, since 7, cc Some(5);
+
+    FilterCutoff = 0x20 => filter.cutoff: f32,
+        "Filter Cutoff", "Cutoff", "Hz", 20.0..=20000.0, default 20000.0, Log, offset 100, since 7, cc Some(74);
+    FilterResonance = 0x21 => filter.resonance: f32,
+        "Filter Resonance", "Resonance", "", 0.0..=1.0, default 0.0, Linear, offset 104, since 7, cc Some(71);
+    FilterEnvAmt = 0x22 => filter.env_amt: f32,
+        "Filter Env Amount", "Env Amt", "Hz", -10000.0..=10000.0, default 0.0, Linear, offset 108, since 7, cc None;
+    FilterAttack = 0x23 => filter.attack: f32,
+        "Filter Attack", "A", "s", 0.0..=5.0, default 0.0, Linear, offset 112, since 7, cc None;
+    FilterDecay = 0x24 => filter.decay: f32,
+        "Filter Decay", "D", "s", 0.0..=5.0, default 0.0, Linear, offset 116, since 7, cc None;
+    FilterSustain = 0x25 => filter.sustain: f32,
+        "Filter Sustain", "S", "", 0.0..=1.0, default 1.0, Linear, offset 120, since 7, cc None;
+    FilterRelease = 0x26 => filter.release: f32,
+        "Filter Release", "R", "s", 0.0..=5.0, default 0.0, Linear, offset 124, since 7, cc None;
+
+    AmpAttack = 0x28 => amp.attack: f32,
+        "Amp Attack", "A", "s", 0.0..=5.0, default 0.01, Linear, offset 128, since 7, cc Some(73);
+    AmpDecay = 0x29 => amp.decay: f32,
+        "Amp Decay", "D", "s", 0.0..=5.0, default 0.1, Linear, offset 132, since 7, cc Some(75);
+    AmpSustain = 0x2A => amp.sustain: f32,
+        "Amp Sustain", "S", "", 0.0..=1.0, default 1.0, Linear, offset 136, since 7, cc None;
+    AmpRelease = 0x2B => amp.release: f32,
+        "Amp Release", "R", "s", 0.0..=5.0, default 0.1, Linear, offset 140, since 7, cc Some(72);
+
+    LfoEnabled = 0x30 => lfo_enabled: bool,
+        "LFO Enabled", "Enable LFO", "", 0.0..=1.0, default 0.0, Linear, offset 144, since 7, cc None;
+    LfoFreq = 0x31 => lfo.freq: f32,
+        "LFO Rate", "Rate", "Hz", 0.05..=20.0, default 1.0, Log, offset 148, since 7, cc Some(76);
+    LfoWaveform = 0x32 => lfo.waveform: LfoWaveform,
+        "LFO Wave", "Wave", "", 0.0..=3.0, default 0.0, Linear, offset 152, since 7, cc None;
+    LfoVibAmt = 0x33 => lfo.vib_amt: f32,
+        "LFO Vibrato Amount", "Vibrato", "Hz", 0.0..=20.0, default 0.0, Linear, offset 156, since 7, cc Some(77);
+    LfoFiltAmt = 0x34 => lfo.filt_amt: f32,
+        "LFO Filter Amount", "Filter", "", 0.0..=100.0, default 0.0, Linear, offset 160, since 7, cc None;
+
+    DelayTime = 0x38 => delay.time: f32,
+        "Delay Time", "Time", "s", 0.0..=2.0, default 0.0, Linear, offset 164, since 7, cc None;
+    DelayFeedback = 0x39 => delay.feedback: f32,
+        "Delay Feedback", "Feedback", "", 0.0..=1.0, default 0.0, Linear, offset 168, since 7, cc None;
+    DelayMix = 0x3A => delay.mix: f32,
+        "Delay Mix", "Mix", "", 0.0..=1.0, default 0.0, Linear, offset 172, since 7, cc Some(94);
+    DelayEnabled = 0x3B => delay.enabled: bool,
+        "Delay Enabled", "Enable Delay", "", 0.0..=1.0, default 0.0, Linear, offset 176, since 7, cc None;
+
+    ReverbSize = 0x40 => reverb.size: f32,
+        "Reverb Size", "Size", "", 0.0..=1.0, default 0.0, Linear, offset 180, since 7, cc None;
+    ReverbDamping = 0x41 => reverb.damping: f32,
+        "Reverb Damping", "Damping", "", 0.0..=1.0, default 0.0, Linear, offset 184, since 7, cc None;
+    ReverbMix = 0x42 => reverb.mix: f32,
+        "Reverb Mix", "Mix", "", 0.0..=1.0, default 0.0, Linear, offset 188, since 7, cc Some(91);
+    ReverbEnabled = 0x43 => reverb.enabled: bool,
+        "Reverb Enabled", "Enable Reverb", "", 0.0..=1.0, default 0.0, Linear, offset 192, since 7, cc None;
+
+    VelocityAmp = 0x48 => velocity.amp: f32,
+        "Velocity to Amp", "Amp", "", 0.0..=1.0, default 0.0, Linear, offset 196, since 8, cc None;
+    VelocityFilter = 0x49 => velocity.filter: f32,
+        "Velocity to Filter Env", "Filter Env", "", 0.0..=1.0, default 0.0, Linear, offset 200, since 8, cc None;
+}
+
+pub fn desc(id: ParamId) -> &'static ParamDesc {
+    PARAMS
+        .iter()
+        .find(|d| d.id == id)
+        .expect("every ParamId has a descriptor")
+}
+
+/// Looks up a parameter by the id byte used in `CMD_SET_PARAM` messages.
+pub fn desc_for_wire_id(id: u8) -> Option<&'static ParamDesc> {
+    PARAMS.iter().find(|d| d.id as u8 == id)
+}
+
+pub fn desc_for_cc(cc: u8) -> Option<&'static ParamDesc> {
+    PARAMS.iter().find(|d| d.cc == Some(cc))
+}
+
+impl ParamDesc {
+    pub fn clamp(&self, value: f32) -> f32 {
+        value.clamp(self.min, self.max)
+    }
+
+    /// Maps a 7-bit controller value onto the parameter range, following the
+    /// taper so that e.g. cutoff sweeps evenly across octaves.
+    pub fn value_from_cc(&self, value: u8) -> f32 {
+        let t = value.min(127) as f32 / 127.0;
+        let v = match self.taper {
+            Taper::Linear => self.min + t * (self.max - self.min),
+            Taper::Log => self.min * libm::powf(self.max / self.min, t),
+        };
+        match self.kind {
+            ParamKind::Float => v,
+            _ => v.round(),
+        }
+    }
+
+    /// Byte offset inside a preset stored in the given format version, or
+    /// `None` if that version predates the parameter. Older layouts are the
+    /// current one with the newer fields left out.
+    pub fn offset_in(&self, version: u32) -> Option<usize> {
+        if self.since > version {
+            return None;
+        }
+        let missing = PARAMS
+            .iter()
+            .filter(|d| d.offset < self.offset && d.since > version)
+            .count();
+        Some(self.offset - missing * 4)
+    }
+}
@@ -201,8 +201,5 @@ impl PianoWidget {
 }
 
 fn is_black_key(note: u8) -> bool {
-    match note % 12 {
-        1 | 3 | 6 | 8 | 10 => true,
-        _ => false,
-    }
+    matches!(note % 12, 1 | 3 | 6 | 8 | 10)
 }
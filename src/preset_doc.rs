@@ -0,0 +1,335 @@
+use anyhow::{anyhow, bail};
+use serde_json::{Map, Value};
+use std::path::Path;
+
+use crate::params::{ParamDesc, ParamKind, PARAMS};
+use crate::protocol::{truncate_name, Preset, ProtocolError, Storage, VERSION};
+
+/// Version of the JSON/TOML document layout. Bump it when keys are renamed or
+/// their meaning changes; adding parameters does not require a bump since
+/// missing keys fall back to their defaults.
+pub const SCHEMA_VERSION: u32 = 1;
+
+const PRESET_FORMAT: &str = "picodsp-preset";
+const BANK_FORMAT: &str = "picodsp-bank";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DocFormat {
+    Json,
+    Toml,
+}
+
+impl DocFormat {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()?.to_lowercase().as_str() {
+            "json" => Some(DocFormat::Json),
+            "toml" => Some(DocFormat::Toml),
+            _ => None,
+        }
+    }
+}
+
+/// Document key of a parameter: the last part of its field path, with the
+/// unit appended for physical quantities, e.g. "cutoff_hz" or "attack_s".
+fn doc_key(desc: &ParamDesc) -> String {
+    let field = desc.key.rsplit('.').next().unwrap_or(desc.key);
+    match desc.unit {
+        "Hz" => format!("{}_hz", field),
+        "s" => format!("{}_s", field),
+        _ => field.to_string(),
+    }
+}
+
+/// Section a parameter is written to, `None` for top-level preset fields.
+fn doc_section(desc: &ParamDesc) -> Option<&'static str> {
+    desc.key.split_once('.').map(|(section, _)| section)
+}
+
+/// Widens without exposing binary noise, so 0.01 is written as 0.01 rather
+/// than 0.009999999776482582.
+fn shortest_f64(value: f32) -> f64 {
+    value.to_string().parse().unwrap_or(value as f64)
+}
+
+fn preset_to_value(preset: &Preset) -> Value {
+    let mut root = Map::new();
+    root.insert("name".into(), Value::from(preset.name.clone()));
+
+    for desc in PARAMS {
+        let value = preset.param(desc.id);
+        let json = match desc.kind {
+            ParamKind::Float => Value::from(shortest_f64(value)),
+            ParamKind::Toggle => Value::from(value >= 0.5),
+            ParamKind::Choice(names) => Value::from(names[value as usize]),
+        };
+
+        let table = match doc_section(desc) {
+            Some(section) => root
+                .entry(section)
+                .or_insert_with(|| Value::Object(Map::new()))
+                .as_object_mut()
+                .expect("sections are tables"),
+            None => &mut root,
+        };
+        table.insert(doc_key(desc), json);
+    }
+
+    Value::Object(root)
+}
+
+fn preset_from_value(value: &Value) -> anyhow::Result<Preset> {
+    let root = value
+        .as_object()
+        .ok_or_else(|| anyhow!("preset must be a table"))?;
+
+    let mut preset = Preset::default();
+    if let Some(name) = root.get("name") {
+        let name = name
+            .as_str()
+            .ok_or_else(|| anyhow!("name must be a string"))?;
+        preset.name = truncate_name(name).to_string();
+    }
+
+    for desc in PARAMS {
+        let table = match doc_section(desc) {
+            Some(section) => match root.get(section) {
+                Some(t) => t
+                    .as_object()
+                    .ok_or_else(|| anyhow!("{} must be a table", section))?,
+                None => continue,
+            },
+            None => root,
+        };
+        let key = doc_key(desc);
+        let Some(json) = table.get(&key) else {
+            continue;
+        };
+
+        let value = match desc.kind {
+            ParamKind::Float => desc.clamp(
+                json.as_f64()
+                    .ok_or_else(|| anyhow!("{} must be a number", desc.key))?
+                    as f32,
+            ),
+            ParamKind::Toggle => {
+                if json
+                    .as_bool()
+                    .ok_or_else(|| anyhow!("{} must be true or false", desc.key))?
+                {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            ParamKind::Choice(names) => {
+                let name = json
+                    .as_str()
+                    .ok_or_else(|| anyhow!("{} must be a name", desc.key))?;
+                names
+                    .iter()
+                    .position(|n| n.eq_ignore_ascii_case(name))
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "unknown {} '{}', expected one of {}",
+                            desc.key,
+                            name,
+                            names.join(", ")
+                        )
+                    })? as f32
+            }
+        };
+        preset.set_param(desc.id, value)?;
+    }
+
+    Ok(preset)
+}
+
+fn header(format: &str) -> Map<String, Value> {
+    let mut root = Map::new();
+    root.insert("format".into(), Value::from(format));
+    root.insert("schema".into(), Value::from(SCHEMA_VERSION));
+    root
+}
+
+fn check_header(root: &Value, format: &str) -> anyhow::Result<()> {
+    match root.get("format").and_then(Value::as_str) {
+        Some(f) if f == format => {}
+        Some(f) => bail!("expected a {} document, found {}", format, f),
+        None => bail!("missing format key, not a picoDSP document"),
+    }
+    let schema = root
+        .get("schema")
+        .and_then(Value::as_u64)
+        .ok_or_else(|| anyhow!("missing schema version"))?;
+    if schema > SCHEMA_VERSION as u64 {
+        bail!(
+            "schema version {} is newer than supported ({})",
+            schema,
+            SCHEMA_VERSION
+        );
+    }
+    Ok(())
+}
+
+fn to_text(value: &Value, format: DocFormat) -> anyhow::Result<String> {
+    Ok(match format {
+        DocFormat::Json => serde_json::to_string_pretty(value)? + "\n",
+        DocFormat::Toml => toml::to_string_pretty(value)?,
+    })
+}
+
+fn from_text(text: &str, format: DocFormat) -> anyhow::Result<Value> {
+    Ok(match format {
+        DocFormat::Json => serde_json::from_str(text)?,
+        DocFormat::Toml => toml::from_str(text)?,
+    })
+}
+
+pub fn preset_to_string(preset: &Preset, format: DocFormat) -> anyhow::Result<String> {
+    let mut root = header(PRESET_FORMAT);
+    root.insert("preset".into(), preset_to_value(preset));
+    to_text(&Value::Object(root), format)
+}
+
+pub fn preset_from_str(text: &str, format: DocFormat) -> anyhow::Result<Preset> {
+    let root = from_text(text, format)?;
+    check_header(&root, PRESET_FORMAT)?;
+    preset_from_value(
+        root.get("preset")
+            .ok_or_else(|| anyhow!("missing preset table"))?,
+    )
+}
+
+pub fn bank_to_string(storage: &Storage, format: DocFormat) -> anyhow::Result<String> {
+    let mut root = header(BANK_FORMAT);
+    root.insert(
+        "presets".into(),
+        Value::Array(storage.presets.iter().map(preset_to_value).collect()),
+    );
+    to_text(&Value::Object(root), format)
+}
+
+pub fn bank_from_str(text: &str, format: DocFormat) -> anyhow::Result<Storage> {
+    let root = from_text(text, format)?;
+    check_header(&root, BANK_FORMAT)?;
+    let presets = root
+        .get("presets")
+        .and_then(Value::as_array)
+        .ok_or_else(|| anyhow!("missing presets array"))?
+        .iter()
+        .enumerate()
+        .map(|(i, p)| preset_from_value(p).map_err(|e| anyhow!("preset {}: {}", i + 1, e)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let storage = Storage {
+        presets,
+        version: VERSION,
+    };
+    // Refuse what could never be sent or saved, like `syx::read_syx` does.
+    if storage.presets.len() > storage.capacity() {
+        return Err(ProtocolError::BankFull {
+            count: storage.presets.len(),
+            capacity: storage.capacity(),
+        }
+        .into());
+    }
+    Ok(storage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{LfoWaveform, Waveform};
+
+    fn sample_preset() -> Preset {
+        let mut preset = Preset {
+            name: "Glass Pad".to_string(),
+            portamento: 0.25,
+            lfo_enabled: true,
+            ..Default::default()
+        };
+        preset.osc2.waveform = Waveform::Square;
+        preset.osc2.level = 0.7;
+        preset.filter.cutoff = 1234.5;
+        preset.amp.attack = 0.01;
+        preset.lfo.waveform = LfoWaveform::Triangle;
+        preset.reverb.enabled = true;
+        preset.velocity.filter = 0.4;
+        preset
+    }
+
+    #[test]
+    fn presets_round_trip() {
+        for format in [DocFormat::Json, DocFormat::Toml] {
+            let text = preset_to_string(&sample_preset(), format).unwrap();
+            assert_eq!(preset_from_str(&text, format).unwrap(), sample_preset());
+        }
+    }
+
+    #[test]
+    fn banks_round_trip() {
+        let storage = Storage {
+            presets: vec![Preset::default(), sample_preset()],
+            version: VERSION,
+        };
+        for format in [DocFormat::Json, DocFormat::Toml] {
+            let text = bank_to_string(&storage, format).unwrap();
+            assert_eq!(
+                bank_from_str(&text, format).unwrap().presets,
+                storage.presets
+            );
+        }
+    }
+
+    #[test]
+    fn oversized_bank_is_rejected() {
+        let storage = Storage {
+            presets: vec![Preset::default(); 21],
+            version: VERSION,
+        };
+        let text = bank_to_string(&storage, DocFormat::Json).unwrap();
+        let err = bank_from_str(&text, DocFormat::Json).err().unwrap();
+        assert_eq!(err.to_string(), "bank has 21 presets, flash holds 20");
+    }
+
+    #[test]
+    fn long_names_survive_a_device_round_trip() {
+        let text = r#"{ "format": "picodsp-preset", "schema": 1,
+            "preset": { "name": "Cordes frottées, très éthérées" } }"#;
+        let preset = preset_from_str(text, DocFormat::Json).unwrap();
+        assert_eq!(preset.name, "Cordes frottées, très éthér");
+
+        let decoded = Preset::decode(&preset.encode(VERSION).unwrap(), VERSION).unwrap();
+        assert_eq!(decoded.name, preset.name);
+    }
+
+    #[test]
+    fn missing_keys_keep_defaults() {
+        let text = r#"
+            format = "picodsp-preset"
+            schema = 1
+
+            [preset]
+            name = "Sparse"
+
+            [preset.filter]
+            cutoff_hz = 800.0
+        "#;
+        let preset = preset_from_str(text, DocFormat::Toml).unwrap();
+        let expected = Preset {
+            name: "Sparse".to_string(),
+            filter: crate::protocol::FilterSettings {
+                cutoff: 800.0,
+                ..Preset::default().filter
+            },
+            ..Default::default()
+        };
+        assert_eq!(preset, expected);
+    }
+
+    #[test]
+    fn newer_schema_is_rejected() {
+        let text = r#"{ "format": "picodsp-preset", "schema": 2, "preset": {} }"#;
+        let err = preset_from_str(text, DocFormat::Json).unwrap_err();
+        assert!(err.to_string().contains("newer"), "{}", err);
+    }
+}
@@ -1,3 +1,5 @@
+use crate::params::{ParamId, ParamKind, PARAMS};
+
 pub const SYSEX_START: u8 = 0xF0;
 pub const SYSEX_END: u8 = 0xF7;
 pub const MANUFACTURER_ID: u8 = 0x7D;
@@ -8,14 +10,157 @@ pub const CMD_DUMP_REQ: u8 = 0x01;
 pub const CMD_WRITE_REQ: u8 = 0x02;
 pub const CMD_WRITE_SUCCESS: u8 = 0x03;
 pub const CMD_WRITE_ERROR: u8 = 0x04;
+/// F0 7D 01 05 <index> F7
+pub const CMD_PRESET_REQ: u8 = 0x05;
+/// F0 7D 01 06 <index> <version> <nibbles...> F7, reply to CMD_PRESET_REQ
+pub const CMD_PRESET_DATA: u8 = 0x06;
+/// Same payload as CMD_PRESET_DATA, answered with CMD_WRITE_SUCCESS/ERROR
+pub const CMD_PRESET_WRITE: u8 = 0x07;
+/// F0 7D 01 08 <param id> <value: f32 LE, 8 nibbles> F7, edits the current
+/// preset on the device without storing it. See `ParamId` for the id table.
+pub const CMD_SET_PARAM: u8 = 0x08;
+/// F0 7D 01 09 F7, asks newer firmware for `CMD_INFO_DATA`
+pub const CMD_INFO_REQ: u8 = 0x09;
+/// F0 7D 01 0A <major> <minor> <patch> <storage version> <preset slots>
+/// <feature bits> F7
+pub const CMD_INFO_DATA: u8 = 0x0A;
+
+/// MIDI Universal Identity Request, answered by most devices.
+pub const IDENTITY_REQUEST: [u8; 6] = [SYSEX_START, 0x7E, 0x7F, 0x06, 0x01, SYSEX_END];
 
 pub const MAGIC: u32 = 0x50445350;
-pub const VERSION: u32 = 7;
+pub const VERSION: u32 = 8;
+/// Format written until the device reports its own. Firmware that predates
+/// `CMD_INFO_REQ` stores v7 and cannot read anything newer.
+pub const DEFAULT_WRITE_VERSION: u32 = 7;
+/// Every storage format version the codec can read and write, oldest first.
+pub const SUPPORTED_VERSIONS: &[u32] = &[7, 8];
 pub const STORAGE_SIZE: usize = 4096;
-pub const PRESET_SIZE: usize = 200;
+pub const PRESET_SIZE: usize = 204;
+pub const HEADER_SIZE: usize = 16;
+/// Bytes reserved for the UTF-8 preset name at the start of a preset.
+pub const NAME_LEN: usize = 32;
+pub const MAX_PRESETS: usize = (STORAGE_SIZE - HEADER_SIZE) / PRESET_SIZE;
+/// Optional CRC-16 trailer after the nibbleized data of bank dumps and preset
+/// messages. Its presence is detected from the payload length, so firmware
+/// that does not send one is still understood.
+pub const CRC_NIBBLES: usize = 4;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProtocolError {
+    /// Not a picoDSP SysEx frame (missing F0/F7 or wrong manufacturer/model).
+    InvalidFrame,
+    UnexpectedCommand(u8),
+    BadLength {
+        expected: usize,
+        actual: usize,
+    },
+    InvalidNibble {
+        offset: usize,
+        value: u8,
+    },
+    BadMagic(u32),
+    UnsupportedVersion(u32),
+    TooManyPresets {
+        count: u32,
+        max: usize,
+    },
+    /// The bank has more presets than fit in flash for the target version.
+    BankFull {
+        count: usize,
+        capacity: usize,
+    },
+    PresetIndexOutOfRange {
+        index: usize,
+        count: usize,
+    },
+    /// Preset data ended early. Carries the preset index within the bank.
+    TruncatedPreset(usize),
+    BadEnumValue {
+        field: &'static str,
+        value: u32,
+    },
+    /// The CRC trailer does not match the received data, i.e. the message
+    /// was damaged in transit.
+    ChecksumMismatch {
+        received: u16,
+        computed: u16,
+    },
+}
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+impl std::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolError::InvalidFrame => write!(f, "not a picoDSP SysEx message"),
+            ProtocolError::UnexpectedCommand(cmd) => write!(f, "unexpected command {:02X}", cmd),
+            ProtocolError::BadLength { expected, actual } => {
+                write!(f, "bad payload length {} (expected {})", actual, expected)
+            }
+            ProtocolError::InvalidNibble { offset, value } => {
+                write!(f, "invalid nibble {:02X} at offset {}", value, offset)
+            }
+            ProtocolError::BadMagic(magic) => write!(f, "bad magic {:08X}", magic),
+            ProtocolError::UnsupportedVersion(version) => {
+                write!(f, "unsupported preset version {}", version)
+            }
+            ProtocolError::TooManyPresets { count, max } => {
+                write!(f, "header claims {} presets, storage holds {}", count, max)
+            }
+            ProtocolError::BankFull { count, capacity } => {
+                write!(f, "bank has {} presets, flash holds {}", count, capacity)
+            }
+            ProtocolError::PresetIndexOutOfRange { index, count } => {
+                write!(f, "preset {} out of range (bank has {})", index + 1, count)
+            }
+            ProtocolError::TruncatedPreset(index) => {
+                write!(f, "preset {} is truncated", index + 1)
+            }
+            ProtocolError::BadEnumValue { field, value } => {
+                write!(f, "invalid {} value {}", field, value)
+            }
+            ProtocolError::ChecksumMismatch { received, computed } => write!(
+                f,
+                "checksum mismatch (received {:04X}, computed {:04X})",
+                received, computed
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+/// Size of a single preset in the given storage format version.
+///
+/// v7: name, oscillators, noise, portamento, filter, amp envelope, LFO,
+///     delay, reverb and 4 bytes of padding.
+/// v8: adds velocity to amp and filter envelope depth, the first in place
+///     of the v7 padding, growing the preset to 204 bytes.
+pub fn preset_size(version: u32) -> Result<usize, ProtocolError> {
+    match version {
+        7 => Ok(200),
+        8 => Ok(PRESET_SIZE),
+        _ => Err(ProtocolError::UnsupportedVersion(version)),
+    }
+}
+
+/// Number of presets that fit in flash for the given storage format version.
+pub fn max_presets(version: u32) -> Result<usize, ProtocolError> {
+    Ok((STORAGE_SIZE - HEADER_SIZE) / preset_size(version)?)
+}
+
+/// Cuts a preset name to the `NAME_LEN` bytes stored in flash without
+/// splitting a multi-byte character.
+pub fn truncate_name(name: &str) -> &str {
+    let mut len = name.len().min(NAME_LEN);
+    while !name.is_char_boundary(len) {
+        len -= 1;
+    }
+    &name[..len]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
 pub enum Waveform {
+    #[default]
     Sine = 0,
     Triangle = 1,
     Saw = 2,
@@ -23,20 +168,25 @@ pub enum Waveform {
     Noise = 4,
 }
 
-impl From<u32> for Waveform {
-    fn from(val: u32) -> Self {
+impl TryFrom<u32> for Waveform {
+    type Error = ProtocolError;
+
+    fn try_from(val: u32) -> Result<Self, Self::Error> {
         match val {
-            0 => Waveform::Sine,
-            1 => Waveform::Triangle,
-            2 => Waveform::Saw,
-            3 => Waveform::Square,
-            _ => Waveform::Noise,
+            0 => Ok(Waveform::Sine),
+            1 => Ok(Waveform::Triangle),
+            2 => Ok(Waveform::Saw),
+            3 => Ok(Waveform::Square),
+            4 => Ok(Waveform::Noise),
+            _ => Err(ProtocolError::BadEnumValue {
+                field: "waveform",
+                value: val,
+            }),
         }
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-#[derive(Default)]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
 pub enum LfoWaveform {
     #[default]
     Sine = 0,
@@ -45,19 +195,24 @@ pub enum LfoWaveform {
     Square = 3,
 }
 
+impl TryFrom<u32> for LfoWaveform {
+    type Error = ProtocolError;
 
-impl From<u32> for LfoWaveform {
-    fn from(val: u32) -> Self {
+    fn try_from(val: u32) -> Result<Self, Self::Error> {
         match val {
-            0 => LfoWaveform::Sine,
-            1 => LfoWaveform::Triangle,
-            2 => LfoWaveform::Saw,
-            _ => LfoWaveform::Square,
+            0 => Ok(LfoWaveform::Sine),
+            1 => Ok(LfoWaveform::Triangle),
+            2 => Ok(LfoWaveform::Saw),
+            3 => Ok(LfoWaveform::Square),
+            _ => Err(ProtocolError::BadEnumValue {
+                field: "LFO waveform",
+                value: val,
+            }),
         }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct OscSettings {
     pub waveform: Waveform,
     pub level: f32,
@@ -66,19 +221,7 @@ pub struct OscSettings {
     pub vibrato: bool,
 }
 
-impl Default for OscSettings {
-    fn default() -> Self {
-        Self {
-            waveform: Waveform::Saw,
-            level: 1.0,
-            octave: 0.0,
-            detune: 0.0,
-            vibrato: false,
-        }
-    }
-}
-
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct FilterSettings {
     pub cutoff: f32,
     pub resonance: f32,
@@ -89,21 +232,7 @@ pub struct FilterSettings {
     pub release: f32,
 }
 
-impl Default for FilterSettings {
-    fn default() -> Self {
-        Self {
-            cutoff: 20000.0,
-            resonance: 0.0,
-            env_amt: 0.0,
-            attack: 0.0,
-            decay: 0.0,
-            sustain: 1.0,
-            release: 0.0,
-        }
-    }
-}
-
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct EnvSettings {
     pub attack: f32,
     pub decay: f32,
@@ -111,18 +240,7 @@ pub struct EnvSettings {
     pub release: f32,
 }
 
-impl Default for EnvSettings {
-    fn default() -> Self {
-        Self {
-            attack: 0.01,
-            decay: 0.1,
-            sustain: 1.0,
-            release: 0.1,
-        }
-    }
-}
-
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct LfoSettings {
     pub freq: f32,
     pub waveform: LfoWaveform,
@@ -130,18 +248,7 @@ pub struct LfoSettings {
     pub filt_amt: f32,
 }
 
-impl Default for LfoSettings {
-    fn default() -> Self {
-        Self {
-            freq: 1.0,
-            waveform: LfoWaveform::Sine,
-            vib_amt: 0.0,
-            filt_amt: 0.0,
-        }
-    }
-}
-
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct DelaySettings {
     pub time: f32,
     pub feedback: f32,
@@ -149,7 +256,7 @@ pub struct DelaySettings {
     pub enabled: bool,
 }
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct ReverbSettings {
     pub size: f32,
     pub damping: f32,
@@ -157,7 +264,15 @@ pub struct ReverbSettings {
     pub enabled: bool,
 }
 
-#[derive(Debug, Clone)]
+/// How much the note velocity scales the amp level and the filter envelope
+/// amount, 0.0 (ignored) to 1.0 (fully velocity dependent).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VelocitySettings {
+    pub amp: f32,
+    pub filter: f32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Preset {
     pub name: String,
     pub osc1: OscSettings,
@@ -171,21 +286,16 @@ pub struct Preset {
     pub lfo: LfoSettings,
     pub delay: DelaySettings,
     pub reverb: ReverbSettings,
+    pub velocity: VelocitySettings,
 }
 
 impl Default for Preset {
     fn default() -> Self {
-        Self {
+        let mut preset = Self {
             name: "Init Patch".to_string(),
             osc1: OscSettings::default(),
-            osc2: OscSettings {
-                level: 0.0,
-                ..OscSettings::default()
-            },
-            osc3: OscSettings {
-                level: 0.0,
-                ..OscSettings::default()
-            },
+            osc2: OscSettings::default(),
+            osc3: OscSettings::default(),
             noise: 0.0,
             portamento: 0.0,
             filter: FilterSettings::default(),
@@ -194,14 +304,17 @@ impl Default for Preset {
             lfo: LfoSettings::default(),
             delay: DelaySettings::default(),
             reverb: ReverbSettings::default(),
+            velocity: VelocitySettings::default(),
+        };
+        for desc in PARAMS {
+            preset
+                .set_param(desc.id, desc.default)
+                .expect("registry defaults are valid");
         }
+        preset
     }
 }
 
-fn write_f32(buf: &mut Vec<u8>, val: f32) {
-    buf.extend_from_slice(&val.to_le_bytes());
-}
-
 fn read_f32(buf: &[u8], offset: &mut usize) -> f32 {
     let val = f32::from_le_bytes(buf[*offset..*offset + 4].try_into().unwrap());
     *offset += 4;
@@ -219,260 +332,1227 @@ fn read_u32(buf: &[u8], offset: &mut usize) -> u32 {
 }
 
 impl Preset {
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let mut buf = Vec::new();
+    /// Encodes the preset in the layout of an older or current format version.
+    /// Fields the target version does not know about are dropped.
+    pub fn encode(&self, version: u32) -> Result<Vec<u8>, ProtocolError> {
+        let mut buf = vec![0u8; preset_size(version)?];
 
         // Name (32 bytes)
-        let mut name_bytes = [0u8; 32];
-        let bytes = self.name.as_bytes();
-        let len = bytes.len().min(32);
-        name_bytes[..len].copy_from_slice(&bytes[..len]);
-        buf.extend_from_slice(&name_bytes);
-
-        // Oscillators (20 bytes each)
-        for osc in [&self.osc1, &self.osc2, &self.osc3] {
-            write_u32(&mut buf, osc.waveform as u32);
-            write_f32(&mut buf, osc.level);
-            write_f32(&mut buf, osc.octave);
-            write_f32(&mut buf, osc.detune);
-            write_u32(&mut buf, if osc.vibrato { 1 } else { 0 });
-        }
-
-        // Noise (4 bytes)
-        write_f32(&mut buf, self.noise);
-
-        // Portamento (4 bytes)
-        write_f32(&mut buf, self.portamento);
-
-        // Filter (28 bytes)
-        write_f32(&mut buf, self.filter.cutoff);
-        write_f32(&mut buf, self.filter.resonance);
-        write_f32(&mut buf, self.filter.env_amt);
-        write_f32(&mut buf, self.filter.attack);
-        write_f32(&mut buf, self.filter.decay);
-        write_f32(&mut buf, self.filter.sustain);
-        write_f32(&mut buf, self.filter.release);
-
-        // Amp Env (16 bytes)
-        write_f32(&mut buf, self.amp.attack);
-        write_f32(&mut buf, self.amp.decay);
-        write_f32(&mut buf, self.amp.sustain);
-        write_f32(&mut buf, self.amp.release);
-
-        // LFO Enabled (4 bytes)
-        write_u32(&mut buf, if self.lfo_enabled { 1 } else { 0 });
-
-        // LFO Settings (16 bytes)
-        write_f32(&mut buf, self.lfo.freq);
-        write_u32(&mut buf, self.lfo.waveform as u32);
-        write_f32(&mut buf, self.lfo.vib_amt);
-        write_f32(&mut buf, self.lfo.filt_amt);
-
-        // Delay Settings (16 bytes)
-        write_f32(&mut buf, self.delay.time);
-        write_f32(&mut buf, self.delay.feedback);
-        write_f32(&mut buf, self.delay.mix);
-        write_u32(&mut buf, if self.delay.enabled { 1 } else { 0 });
-
-        // Reverb Settings (16 bytes)
-        write_f32(&mut buf, self.reverb.size);
-        write_f32(&mut buf, self.reverb.damping);
-        write_f32(&mut buf, self.reverb.mix);
-        write_u32(&mut buf, if self.reverb.enabled { 1 } else { 0 });
-
-        // Padding (4 bytes)
-        write_u32(&mut buf, 0);
-
-        buf
-    }
-
-    pub fn from_bytes(data: &[u8]) -> Self {
-        let mut offset = 0;
+        let bytes = truncate_name(&self.name).as_bytes();
+        buf[..bytes.len()].copy_from_slice(bytes);
+
+        // Parameters (4 bytes each), anything left over is padding
+        for desc in PARAMS {
+            if let Some(offset) = desc.offset_in(version) {
+                let value = self.param(desc.id);
+                let bytes = match desc.kind {
+                    ParamKind::Float => value.to_le_bytes(),
+                    ParamKind::Toggle | ParamKind::Choice(_) => (value as u32).to_le_bytes(),
+                };
+                buf[offset..offset + 4].copy_from_slice(&bytes);
+            }
+        }
+
+        Ok(buf)
+    }
+
+    /// Decodes a preset stored in any supported format version and upgrades
+    /// it to the current model. Fields missing from older layouts keep their
+    /// defaults.
+    pub fn decode(data: &[u8], version: u32) -> Result<Self, ProtocolError> {
+        let size = preset_size(version)?;
+        if data.len() < size {
+            return Err(ProtocolError::BadLength {
+                expected: size,
+                actual: data.len(),
+            });
+        }
 
         // Name
-        let name_bytes = &data[offset..offset + 32];
-        let name = String::from_utf8_lossy(name_bytes)
+        let name = String::from_utf8_lossy(&data[..NAME_LEN])
             .trim_matches(char::from(0))
             .to_string();
-        offset += 32;
-
-        // Oscillators
-        let mut oscs = Vec::new();
-        for _ in 0..3 {
-            let waveform = Waveform::from(read_u32(data, &mut offset));
-            let level = read_f32(data, &mut offset);
-            let octave = read_f32(data, &mut offset);
-            let detune = read_f32(data, &mut offset);
-            let vibrato = read_u32(data, &mut offset) != 0;
-
-            oscs.push(OscSettings {
-                waveform,
-                level,
-                octave,
-                detune,
-                vibrato,
+        let mut preset = Preset {
+            name,
+            ..Preset::default()
+        };
+
+        for desc in PARAMS {
+            let Some(mut offset) = desc.offset_in(version) else {
+                continue;
+            };
+            let value = match desc.kind {
+                ParamKind::Float => read_f32(data, &mut offset),
+                ParamKind::Toggle => (read_u32(data, &mut offset) != 0) as u32 as f32,
+                ParamKind::Choice(names) => {
+                    let raw = read_u32(data, &mut offset);
+                    if raw as usize >= names.len() {
+                        return Err(ProtocolError::BadEnumValue {
+                            field: desc.name,
+                            value: raw,
+                        });
+                    }
+                    raw as f32
+                }
+            };
+            preset.set_param(desc.id, value)?;
+        }
+
+        Ok(preset)
+    }
+
+    /// Wraps the preset in a single-preset message (`CMD_PRESET_DATA` or
+    /// `CMD_PRESET_WRITE`) for the given slot, optionally with a CRC trailer.
+    pub fn to_sysex(
+        &self,
+        command: u8,
+        index: usize,
+        version: u32,
+        checksum: bool,
+    ) -> Result<Vec<u8>, ProtocolError> {
+        let capacity = max_presets(version)?;
+        if index >= capacity {
+            return Err(ProtocolError::PresetIndexOutOfRange {
+                index,
+                count: capacity,
             });
         }
 
-        let noise = read_f32(data, &mut offset);
-        let portamento = read_f32(data, &mut offset);
+        let mut msg = vec![
+            SYSEX_START,
+            MANUFACTURER_ID,
+            MODEL_ID,
+            command,
+            index as u8,
+            version as u8,
+        ];
+        push_payload(&mut msg, &self.encode(version)?, checksum);
+        msg.push(SYSEX_END);
 
-        // Filter
-        let filter = FilterSettings {
-            cutoff: read_f32(data, &mut offset),
-            resonance: read_f32(data, &mut offset),
-            env_amt: read_f32(data, &mut offset),
-            attack: read_f32(data, &mut offset),
-            decay: read_f32(data, &mut offset),
-            sustain: read_f32(data, &mut offset),
-            release: read_f32(data, &mut offset),
-        };
+        Ok(msg)
+    }
 
-        // Amp
-        let amp = EnvSettings {
-            attack: read_f32(data, &mut offset),
-            decay: read_f32(data, &mut offset),
-            sustain: read_f32(data, &mut offset),
-            release: read_f32(data, &mut offset),
-        };
+    /// Parses a `CMD_PRESET_DATA` or `CMD_PRESET_WRITE` message into its slot
+    /// index and the preset upgraded to the current model.
+    pub fn from_sysex(msg: &[u8]) -> Result<(usize, Self), ProtocolError> {
+        if msg.len() < 7
+            || msg[0] != SYSEX_START
+            || msg[msg.len() - 1] != SYSEX_END
+            || msg[1] != MANUFACTURER_ID
+            || msg[2] != MODEL_ID
+        {
+            return Err(ProtocolError::InvalidFrame);
+        }
+        if msg[3] != CMD_PRESET_DATA && msg[3] != CMD_PRESET_WRITE {
+            return Err(ProtocolError::UnexpectedCommand(msg[3]));
+        }
 
-        // LFO
-        let lfo_enabled = read_u32(data, &mut offset) != 0;
-        let lfo = LfoSettings {
-            freq: read_f32(data, &mut offset),
-            waveform: LfoWaveform::from(read_u32(data, &mut offset)),
-            vib_amt: read_f32(data, &mut offset),
-            filt_amt: read_f32(data, &mut offset),
-        };
+        let index = msg[4] as usize;
+        let version = msg[5] as u32;
+        let size = preset_size(version)?;
 
-        // Delay
-        let delay = DelaySettings {
-            time: read_f32(data, &mut offset),
-            feedback: read_f32(data, &mut offset),
-            mix: read_f32(data, &mut offset),
-            enabled: read_u32(data, &mut offset) != 0,
-        };
+        let data = read_payload(&msg[6..msg.len() - 1], size)?;
+        Ok((index, Preset::decode(&data, version)?))
+    }
 
-        // Reverb
-        let reverb = ReverbSettings {
-            size: read_f32(data, &mut offset),
-            damping: read_f32(data, &mut offset),
-            mix: read_f32(data, &mut offset),
-            enabled: read_u32(data, &mut offset) != 0,
-        };
+    /// Lists the parameters that hold non-default data and would be lost
+    /// when writing this preset in the given (older) format version.
+    pub fn downgrade_losses(&self, version: u32) -> Vec<&'static str> {
+        PARAMS
+            .iter()
+            .filter(|desc| desc.since > version && self.param(desc.id) != desc.default)
+            .map(|desc| desc.name)
+            .collect()
+    }
+}
 
-        // Padding
-        let _padding = read_u32(data, &mut offset);
+/// A preset whose data does not survive a downgrade to an older format.
+#[derive(Debug, Clone)]
+pub struct MigrationWarning {
+    pub index: usize,
+    pub name: String,
+    pub lost: Vec<&'static str>,
+}
 
-        Preset {
-            name,
-            osc1: oscs[0].clone(),
-            osc2: oscs[1].clone(),
-            osc3: oscs[2].clone(),
-            noise,
-            portamento,
-            filter,
-            amp,
-            lfo_enabled,
-            lfo,
-            delay,
-            reverb,
-        }
+impl std::fmt::Display for MigrationWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {} loses {}",
+            self.index + 1,
+            self.name,
+            self.lost.join(", ")
+        )
     }
 }
 
 pub struct Storage {
     pub presets: Vec<Preset>,
+    /// Format version the bank was decoded from.
+    pub version: u32,
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Self {
+            presets: vec![Preset::default()],
+            version: VERSION,
+        }
+    }
 }
 
 impl Storage {
-    pub fn to_sysex(&self) -> Vec<u8> {
+    /// Number of preset slots available in flash for the format version the
+    /// bank is in.
+    pub fn capacity(&self) -> usize {
+        max_presets(self.version).unwrap_or(MAX_PRESETS)
+    }
+
+    pub fn free_slots(&self) -> usize {
+        self.capacity().saturating_sub(self.presets.len())
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.free_slots() == 0
+    }
+
+    /// Encodes the bank in the given format version, e.g. `VERSION` or an
+    /// older one for devices running older firmware. Use `downgrade_warnings`
+    /// first to find out what is lost. With `checksum` a CRC trailer is
+    /// appended, which only firmware with checksum support accepts.
+    pub fn to_sysex(&self, version: u32, checksum: bool) -> Result<Vec<u8>, ProtocolError> {
+        let capacity = max_presets(version)?;
+        if self.presets.len() > capacity {
+            return Err(ProtocolError::BankFull {
+                count: self.presets.len(),
+                capacity,
+            });
+        }
+
         let mut raw_data = Vec::with_capacity(STORAGE_SIZE);
 
         // Header
         write_u32(&mut raw_data, MAGIC);
-        write_u32(&mut raw_data, VERSION);
+        write_u32(&mut raw_data, version);
         write_u32(&mut raw_data, self.presets.len() as u32);
         write_u32(&mut raw_data, 0); // Padding
 
         // Presets
         for preset in &self.presets {
-            raw_data.extend_from_slice(&preset.to_bytes());
+            raw_data.extend_from_slice(&preset.encode(version)?);
         }
 
         // Fill rest with 0xFF (flash erased state) or 0x00
-        while raw_data.len() < STORAGE_SIZE {
-            raw_data.push(0);
-        }
-
-        // Nibbleize data (split each byte into two 4-bit nibbles)
-        let mut nibble_data = Vec::with_capacity(STORAGE_SIZE * 2);
-        for byte in raw_data {
-            nibble_data.push((byte >> 4) & 0x0F); // High nibble
-            nibble_data.push(byte & 0x0F); // Low nibble
-        }
+        raw_data.resize(STORAGE_SIZE, 0);
 
         // Construct SysEx message
         let mut msg = vec![SYSEX_START, MANUFACTURER_ID, MODEL_ID, CMD_WRITE_REQ];
-        msg.extend_from_slice(&nibble_data);
+        push_payload(&mut msg, &raw_data, checksum);
         msg.push(SYSEX_END);
 
-        msg
+        Ok(msg)
+    }
+
+    /// Builds a `CMD_PRESET_WRITE` message for a single slot of the bank.
+    pub fn preset_sysex(
+        &self,
+        index: usize,
+        version: u32,
+        checksum: bool,
+    ) -> Result<Vec<u8>, ProtocolError> {
+        let preset = self
+            .presets
+            .get(index)
+            .ok_or(ProtocolError::PresetIndexOutOfRange {
+                index,
+                count: self.presets.len(),
+            })?;
+        preset.to_sysex(CMD_PRESET_WRITE, index, version, checksum)
     }
 
-    pub fn from_sysex(msg: &[u8]) -> Option<Self> {
+    /// Stores a single preset received from the device. A preset for the slot
+    /// right after the last one is appended. Returns the slot index.
+    pub fn apply_preset_sysex(&mut self, msg: &[u8]) -> Result<usize, ProtocolError> {
+        let (index, preset) = Preset::from_sysex(msg)?;
+        if index < self.presets.len() {
+            self.presets[index] = preset;
+        } else if index == self.presets.len() && !self.is_full() {
+            self.presets.push(preset);
+        } else {
+            return Err(ProtocolError::PresetIndexOutOfRange {
+                index,
+                count: self.presets.len(),
+            });
+        }
+        Ok(index)
+    }
+
+    pub fn downgrade_warnings(&self, version: u32) -> Vec<MigrationWarning> {
+        self.presets
+            .iter()
+            .enumerate()
+            .filter_map(|(index, preset)| {
+                let lost = preset.downgrade_losses(version);
+                if lost.is_empty() {
+                    None
+                } else {
+                    Some(MigrationWarning {
+                        index,
+                        name: preset.name.clone(),
+                        lost,
+                    })
+                }
+            })
+            .collect()
+    }
+
+    pub fn from_sysex(msg: &[u8]) -> Result<Self, ProtocolError> {
         if msg.len() < 5 {
-            return None;
+            return Err(ProtocolError::InvalidFrame);
         }
         if msg[0] != SYSEX_START || msg[msg.len() - 1] != SYSEX_END {
-            return None;
+            return Err(ProtocolError::InvalidFrame);
         }
         if msg[1] != MANUFACTURER_ID || msg[2] != MODEL_ID {
-            return None;
+            return Err(ProtocolError::InvalidFrame);
         }
 
         // Only parse if it is a Write Request / Dump Response
         if msg[3] != CMD_WRITE_REQ {
-            return None;
-        }
-
-        let payload = &msg[4..msg.len() - 1];
-
-        // Check if payload size matches expected nibbleized size
-        if payload.len() != STORAGE_SIZE * 2 {
-            return None;
+            return Err(ProtocolError::UnexpectedCommand(msg[3]));
         }
 
-        // De-nibbleize (combine pairs of nibbles back to bytes)
-        let mut data = Vec::with_capacity(STORAGE_SIZE);
-        for chunk in payload.chunks(2) {
-            if chunk.len() != 2 {
-                return None;
-            }
-            let high = chunk[0];
-            let low = chunk[1];
-            // Reconstruct byte: (high << 4) | low
-            data.push((high << 4) | (low & 0x0F));
-        }
+        let data = read_payload(&msg[4..msg.len() - 1], STORAGE_SIZE)?;
 
         let mut offset = 0;
         let magic = read_u32(&data, &mut offset);
         if magic != MAGIC {
-            return None;
+            return Err(ProtocolError::BadMagic(magic));
         }
 
-        let _version = read_u32(&data, &mut offset);
+        let version = read_u32(&data, &mut offset);
+        let size = preset_size(version)?;
+        let max = max_presets(version)?;
+
         let num_presets = read_u32(&data, &mut offset);
+        if num_presets as usize > max {
+            return Err(ProtocolError::TooManyPresets {
+                count: num_presets,
+                max,
+            });
+        }
         let _padding = read_u32(&data, &mut offset);
 
         let mut presets = Vec::new();
 
-        for _ in 0..num_presets {
-            let p = Preset::from_bytes(&data[offset..]);
+        for i in 0..num_presets as usize {
+            if data.len() < offset + size {
+                return Err(ProtocolError::TruncatedPreset(i));
+            }
+            let p = Preset::decode(&data[offset..offset + size], version)?;
             presets.push(p);
-            offset += PRESET_SIZE;
+            offset += size;
         }
 
-        Some(Storage { presets })
+        Ok(Storage { presets, version })
+    }
+}
+
+/// Builds a `CMD_PRESET_REQ` message asking the device for a single slot.
+pub fn preset_request(index: usize) -> Vec<u8> {
+    vec![
+        SYSEX_START,
+        MANUFACTURER_ID,
+        MODEL_ID,
+        CMD_PRESET_REQ,
+        index as u8,
+        SYSEX_END,
+    ]
+}
+
+/// Builds a `CMD_SET_PARAM` message for live editing on the device.
+pub fn param_sysex(id: ParamId, value: f32) -> Vec<u8> {
+    let mut msg = vec![
+        SYSEX_START,
+        MANUFACTURER_ID,
+        MODEL_ID,
+        CMD_SET_PARAM,
+        id as u8,
+    ];
+    msg.extend_from_slice(&nibbleize(&value.to_le_bytes()));
+    msg.push(SYSEX_END);
+    msg
+}
+
+/// Parses a `CMD_SET_PARAM` message into the parameter and its value.
+pub fn parse_param_sysex(msg: &[u8]) -> Result<(ParamId, f32), ProtocolError> {
+    if msg.len() != 14
+        || msg[0] != SYSEX_START
+        || msg[13] != SYSEX_END
+        || msg[1] != MANUFACTURER_ID
+        || msg[2] != MODEL_ID
+    {
+        return Err(ProtocolError::InvalidFrame);
+    }
+    if msg[3] != CMD_SET_PARAM {
+        return Err(ProtocolError::UnexpectedCommand(msg[3]));
+    }
+    let desc = crate::params::desc_for_wire_id(msg[4]).ok_or(ProtocolError::BadEnumValue {
+        field: "parameter id",
+        value: msg[4] as u32,
+    })?;
+    let bytes = denibbleize(&msg[5..13])?;
+    Ok((
+        desc.id,
+        f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+    ))
+}
+
+/// Splits each byte into two 4-bit nibbles (high first) so it is SysEx safe.
+pub fn nibbleize(data: &[u8]) -> Vec<u8> {
+    let mut nibble_data = Vec::with_capacity(data.len() * 2);
+    for &byte in data {
+        nibble_data.push((byte >> 4) & 0x0F); // High nibble
+        nibble_data.push(byte & 0x0F); // Low nibble
+    }
+    nibble_data
+}
+
+/// CRC-16/CCITT-FALSE (polynomial 0x1021, initial value 0xFFFF).
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Appends nibbleized data and, if requested, its CRC as 4 nibbles.
+fn push_payload(msg: &mut Vec<u8>, data: &[u8], checksum: bool) {
+    msg.extend_from_slice(&nibbleize(data));
+    if checksum {
+        msg.extend_from_slice(&nibbleize(&crc16(data).to_be_bytes()));
+    }
+}
+
+/// Decodes a payload of `size` data bytes, verifying the CRC trailer if the
+/// sender included one.
+fn read_payload(payload: &[u8], size: usize) -> Result<Vec<u8>, ProtocolError> {
+    let (data, trailer) = if payload.len() == size * 2 {
+        (payload, None)
+    } else if payload.len() == size * 2 + CRC_NIBBLES {
+        let (data, trailer) = payload.split_at(size * 2);
+        (data, Some(trailer))
+    } else {
+        return Err(ProtocolError::BadLength {
+            expected: size * 2,
+            actual: payload.len(),
+        });
+    };
+
+    let data = denibbleize(data)?;
+    if let Some(trailer) = trailer {
+        let bytes = denibbleize(trailer).map_err(|e| match e {
+            ProtocolError::InvalidNibble { offset, value } => ProtocolError::InvalidNibble {
+                offset: offset + size * 2,
+                value,
+            },
+            e => e,
+        })?;
+        let received = u16::from_be_bytes([bytes[0], bytes[1]]);
+        let computed = crc16(&data);
+        if received != computed {
+            return Err(ProtocolError::ChecksumMismatch { received, computed });
+        }
+    }
+    Ok(data)
+}
+
+/// Combines pairs of 4-bit nibbles (high first) back into bytes.
+pub fn denibbleize(payload: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+    if !payload.len().is_multiple_of(2) {
+        return Err(ProtocolError::BadLength {
+            expected: payload.len() + 1,
+            actual: payload.len(),
+        });
+    }
+
+    if let Some(offset) = payload.iter().position(|&b| b > 0x0F) {
+        return Err(ProtocolError::InvalidNibble {
+            offset,
+            value: payload[offset],
+        });
+    }
+
+    Ok(payload
+        .chunks_exact(2)
+        .map(|chunk| (chunk[0] << 4) | chunk[1])
+        .collect())
+}
+
+/// Contents of a Universal Identity Reply
+/// (F0 7E <channel> 06 02 <manufacturer> <family> <member> <revision> F7).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identity {
+    /// One byte id, or the two bytes after a leading 00 for 3 byte ids.
+    pub manufacturer: Vec<u8>,
+    pub family: u16,
+    pub member: u16,
+    pub revision: [u8; 4],
+}
+
+impl Identity {
+    /// The identity picoDSP firmware reports for the given firmware version.
+    pub fn picodsp(firmware: [u8; 3]) -> Self {
+        Self {
+            manufacturer: vec![MANUFACTURER_ID],
+            family: MODEL_ID as u16,
+            member: 0,
+            revision: [firmware[0], firmware[1], firmware[2], 0],
+        }
+    }
+
+    pub fn is_picodsp(&self) -> bool {
+        self.manufacturer == [MANUFACTURER_ID] && self.family == MODEL_ID as u16
+    }
+
+    pub fn model(&self) -> String {
+        if self.is_picodsp() {
+            "picoDSP".to_string()
+        } else {
+            let id: Vec<String> = self
+                .manufacturer
+                .iter()
+                .map(|b| format!("{:02X}", b))
+                .collect();
+            format!(
+                "Unknown device (manufacturer {}, family {:04X})",
+                id.join(" "),
+                self.family
+            )
+        }
+    }
+
+    pub fn firmware(&self) -> String {
+        let [major, minor, patch, build] = self.revision;
+        if build == 0 {
+            format!("{}.{}.{}", major, minor, patch)
+        } else {
+            format!("{}.{}.{}.{}", major, minor, patch, build)
+        }
+    }
+
+    pub fn to_sysex(&self) -> Vec<u8> {
+        let mut msg = vec![SYSEX_START, 0x7E, 0x7F, 0x06, 0x02];
+        msg.extend_from_slice(&self.manufacturer);
+        msg.extend_from_slice(&[
+            (self.family & 0x7F) as u8,
+            (self.family >> 7) as u8,
+            (self.member & 0x7F) as u8,
+            (self.member >> 7) as u8,
+        ]);
+        msg.extend_from_slice(&self.revision);
+        msg.push(SYSEX_END);
+        msg
+    }
+
+    pub fn from_sysex(msg: &[u8]) -> Result<Self, ProtocolError> {
+        if msg.len() < 5
+            || msg[0] != SYSEX_START
+            || msg[1] != 0x7E
+            || msg[3] != 0x06
+            || msg[msg.len() - 1] != SYSEX_END
+        {
+            return Err(ProtocolError::InvalidFrame);
+        }
+        if msg[4] != 0x02 {
+            return Err(ProtocolError::UnexpectedCommand(msg[4]));
+        }
+
+        let body = &msg[5..msg.len() - 1];
+        let id_len = if body.first() == Some(&0x00) { 3 } else { 1 };
+        if body.len() != id_len + 8 {
+            return Err(ProtocolError::BadLength {
+                expected: id_len + 8,
+                actual: body.len(),
+            });
+        }
+        let (id, rest) = body.split_at(id_len);
+        let manufacturer = if id_len == 3 { &id[1..] } else { id };
+        Ok(Self {
+            manufacturer: manufacturer.to_vec(),
+            family: rest[0] as u16 | (rest[1] as u16) << 7,
+            member: rest[2] as u16 | (rest[3] as u16) << 7,
+            revision: [rest[4], rest[5], rest[6], rest[7]],
+        })
+    }
+}
+
+/// Optional protocol features reported in `CMD_INFO_DATA`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Features {
+    /// `CMD_PRESET_REQ`, `CMD_PRESET_DATA` and `CMD_PRESET_WRITE`.
+    pub preset_commands: bool,
+    /// `CMD_SET_PARAM`.
+    pub live_params: bool,
+    /// CRC trailers on dumps and preset messages.
+    pub checksums: bool,
+}
+
+impl Features {
+    pub const ALL: Features = Features {
+        preset_commands: true,
+        live_params: true,
+        checksums: true,
+    };
+
+    fn to_bits(self) -> u8 {
+        self.preset_commands as u8 | (self.live_params as u8) << 1 | (self.checksums as u8) << 2
+    }
+
+    fn from_bits(bits: u8) -> Self {
+        Self {
+            preset_commands: bits & 0x01 != 0,
+            live_params: bits & 0x02 != 0,
+            checksums: bits & 0x04 != 0,
+        }
+    }
+}
+
+/// Reply to `CMD_INFO_REQ`. Firmware that predates the command does not
+/// answer, so only the identity reply (if any) is known for it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FirmwareInfo {
+    pub firmware: [u8; 3],
+    /// Preset format version the firmware stores in flash.
+    pub storage_version: u32,
+    pub capacity: usize,
+    pub features: Features,
+}
+
+/// Data bytes of a `CMD_INFO_DATA` message.
+const INFO_LEN: usize = 6;
+
+impl FirmwareInfo {
+    /// Every field is a single data byte, so values above 127 are clamped.
+    pub fn to_sysex(&self) -> Vec<u8> {
+        let byte = |value: usize| value.min(0x7F) as u8;
+        vec![
+            SYSEX_START,
+            MANUFACTURER_ID,
+            MODEL_ID,
+            CMD_INFO_DATA,
+            byte(self.firmware[0] as usize),
+            byte(self.firmware[1] as usize),
+            byte(self.firmware[2] as usize),
+            byte(self.storage_version as usize),
+            byte(self.capacity),
+            self.features.to_bits(),
+            SYSEX_END,
+        ]
+    }
+
+    pub fn from_sysex(msg: &[u8]) -> Result<Self, ProtocolError> {
+        if msg.len() < 5
+            || msg[0] != SYSEX_START
+            || msg[msg.len() - 1] != SYSEX_END
+            || msg[1] != MANUFACTURER_ID
+            || msg[2] != MODEL_ID
+        {
+            return Err(ProtocolError::InvalidFrame);
+        }
+        if msg[3] != CMD_INFO_DATA {
+            return Err(ProtocolError::UnexpectedCommand(msg[3]));
+        }
+        let data = &msg[4..msg.len() - 1];
+        if data.len() != INFO_LEN {
+            return Err(ProtocolError::BadLength {
+                expected: INFO_LEN,
+                actual: data.len(),
+            });
+        }
+        Ok(Self {
+            firmware: [data[0], data[1], data[2]],
+            storage_version: data[3] as u32,
+            capacity: data[4] as usize,
+            features: Features::from_bits(data[5]),
+        })
+    }
+}
+
+/// Longest message the editor expects: a bank dump with CRC trailer.
+pub const MAX_SYSEX_LEN: usize = 4 + STORAGE_SIZE * 2 + CRC_NIBBLES + 1;
+
+/// Something the `SysexFramer` recognised in the incoming byte stream.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MidiEvent {
+    /// A complete `F0 .. F7` message.
+    Sysex(Vec<u8>),
+    /// A channel or system common message, status byte included. Running
+    /// status is expanded.
+    Message(Vec<u8>),
+    /// A system realtime byte (clock, active sensing, ...).
+    Realtime(u8),
+    /// A SysEx message was cut short by another status byte.
+    Aborted { len: usize },
+    /// A SysEx message grew past the length limit and was dropped.
+    Overflow { len: usize },
+}
+
+/// Splits an incoming MIDI byte stream into messages. Bytes may arrive in
+/// chunks of any size; realtime bytes may appear anywhere, even inside a
+/// SysEx message, and never disturb it.
+pub struct SysexFramer {
+    max_len: usize,
+    sysex: Vec<u8>,
+    in_sysex: bool,
+    /// Length of the current SysEx message, including dropped bytes.
+    sysex_len: usize,
+    message: Vec<u8>,
+    expected: usize,
+    running_status: Option<u8>,
+}
+
+impl SysexFramer {
+    pub fn new(max_len: usize) -> Self {
+        Self {
+            max_len,
+            sysex: Vec::new(),
+            in_sysex: false,
+            sysex_len: 0,
+            message: Vec::new(),
+            expected: 0,
+            running_status: None,
+        }
+    }
+
+    /// Bytes received so far of the SysEx message in progress, if any.
+    pub fn sysex_progress(&self) -> Option<usize> {
+        self.in_sysex.then_some(self.sysex_len)
+    }
+
+    pub fn feed(&mut self, bytes: &[u8]) -> Vec<MidiEvent> {
+        let mut events = Vec::new();
+        for &byte in bytes {
+            self.push(byte, &mut events);
+        }
+        events
+    }
+
+    pub fn push(&mut self, byte: u8, events: &mut Vec<MidiEvent>) {
+        match byte {
+            0xF8..=0xFF => events.push(MidiEvent::Realtime(byte)),
+            SYSEX_START => {
+                self.abort(events);
+                self.running_status = None;
+                self.message.clear();
+                self.in_sysex = true;
+                self.sysex_len = 1;
+                self.sysex.push(byte);
+            }
+            SYSEX_END => {
+                if !self.in_sysex {
+                    return;
+                }
+                self.in_sysex = false;
+                self.sysex_len += 1;
+                if self.sysex_len > self.max_len {
+                    events.push(MidiEvent::Overflow {
+                        len: self.sysex_len,
+                    });
+                } else {
+                    self.sysex.push(byte);
+                    events.push(MidiEvent::Sysex(std::mem::take(&mut self.sysex)));
+                }
+            }
+            0x80..=0xF6 => {
+                self.abort(events);
+                self.running_status = (byte < 0xF0).then_some(byte);
+                self.message.clear();
+                self.message.push(byte);
+                self.expected = data_len(byte);
+                self.complete_message(events);
+            }
+            _ if self.in_sysex => {
+                self.sysex_len += 1;
+                if self.sysex_len < self.max_len {
+                    self.sysex.push(byte);
+                } else {
+                    // Keep counting but stop buffering until F7 arrives
+                    self.sysex.clear();
+                }
+            }
+            _ => {
+                if self.message.is_empty() {
+                    // Data without a status byte of its own
+                    match self.running_status {
+                        Some(status) => {
+                            self.message.push(status);
+                            self.expected = data_len(status);
+                        }
+                        None => return,
+                    }
+                }
+                self.message.push(byte);
+                self.complete_message(events);
+            }
+        }
+    }
+
+    fn complete_message(&mut self, events: &mut Vec<MidiEvent>) {
+        if self.message.len() == 1 + self.expected {
+            events.push(MidiEvent::Message(std::mem::take(&mut self.message)));
+        }
+    }
+
+    /// Ends an unfinished SysEx message because a new status byte arrived.
+    fn abort(&mut self, events: &mut Vec<MidiEvent>) {
+        if self.in_sysex {
+            self.in_sysex = false;
+            self.sysex.clear();
+            events.push(MidiEvent::Aborted {
+                len: self.sysex_len,
+            });
+        }
+    }
+}
+
+/// Number of data bytes following a (non realtime, non SysEx) status byte.
+fn data_len(status: u8) -> usize {
+    match status {
+        0xC0..=0xDF | 0xF1 | 0xF3 => 1,
+        0x80..=0xEF | 0xF2 => 2,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed_bytewise(framer: &mut SysexFramer, bytes: &[u8]) -> Vec<MidiEvent> {
+        let mut events = Vec::new();
+        for &byte in bytes {
+            framer.push(byte, &mut events);
+        }
+        events
+    }
+
+    #[test]
+    fn reassembles_sysex_byte_by_byte() {
+        let mut framer = SysexFramer::new(MAX_SYSEX_LEN);
+        let dump = Storage::default().to_sysex(VERSION, true).unwrap();
+        assert_eq!(
+            feed_bytewise(&mut framer, &dump),
+            vec![MidiEvent::Sysex(dump.clone())]
+        );
+        assert_eq!(dump.len(), MAX_SYSEX_LEN);
+    }
+
+    #[test]
+    fn realtime_bytes_inside_sysex_are_passed_through() {
+        let mut framer = SysexFramer::new(MAX_SYSEX_LEN);
+        let events = feed_bytewise(
+            &mut framer,
+            &[0xF0, 0x7D, 0xF8, 0x01, 0xFE, 0x03, 0xF8, 0xF7],
+        );
+        assert_eq!(
+            events,
+            vec![
+                MidiEvent::Realtime(0xF8),
+                MidiEvent::Realtime(0xFE),
+                MidiEvent::Realtime(0xF8),
+                MidiEvent::Sysex(vec![0xF0, 0x7D, 0x01, 0x03, 0xF7]),
+            ]
+        );
+    }
+
+    #[test]
+    fn status_byte_aborts_sysex() {
+        let mut framer = SysexFramer::new(MAX_SYSEX_LEN);
+        let events = feed_bytewise(
+            &mut framer,
+            &[
+                0xF0, 0x7D, 0x01, 0x90, 60, 100, 0xF0, 0x7D, 0x01, 0x03, 0xF7,
+            ],
+        );
+        assert_eq!(
+            events,
+            vec![
+                MidiEvent::Aborted { len: 3 },
+                MidiEvent::Message(vec![0x90, 60, 100]),
+                MidiEvent::Sysex(vec![0xF0, 0x7D, 0x01, 0x03, 0xF7]),
+            ]
+        );
+
+        // A new F0 also aborts the message in progress
+        let events = feed_bytewise(&mut framer, &[0xF0, 0x01, 0xF0, 0x02, 0xF7]);
+        assert_eq!(
+            events,
+            vec![
+                MidiEvent::Aborted { len: 2 },
+                MidiEvent::Sysex(vec![0xF0, 0x02, 0xF7]),
+            ]
+        );
+    }
+
+    #[test]
+    fn oversized_sysex_is_dropped() {
+        let mut framer = SysexFramer::new(8);
+        let mut msg = vec![0xF0];
+        msg.extend_from_slice(&[0x01; 100]);
+        msg.push(0xF7);
+        assert_eq!(
+            feed_bytewise(&mut framer, &msg),
+            vec![MidiEvent::Overflow { len: 102 }]
+        );
+        assert!(framer.sysex.capacity() < 100);
+
+        // The framer recovers for the next message
+        assert_eq!(
+            feed_bytewise(&mut framer, &[0xF0, 0x01, 0xF7]),
+            vec![MidiEvent::Sysex(vec![0xF0, 0x01, 0xF7])]
+        );
+    }
+
+    #[test]
+    fn channel_messages_with_running_status() {
+        let mut framer = SysexFramer::new(MAX_SYSEX_LEN);
+        let events = feed_bytewise(
+            &mut framer,
+            &[0xB0, 74, 10, 71, 20, 0xC0, 3, 4, 0xF8, 0x80, 60, 0],
+        );
+        assert_eq!(
+            events,
+            vec![
+                MidiEvent::Message(vec![0xB0, 74, 10]),
+                MidiEvent::Message(vec![0xB0, 71, 20]),
+                MidiEvent::Message(vec![0xC0, 3]),
+                MidiEvent::Message(vec![0xC0, 4]),
+                MidiEvent::Realtime(0xF8),
+                MidiEvent::Message(vec![0x80, 60, 0]),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_identity_replies() {
+        let picodsp = Identity::picodsp([1, 2, 3]);
+        let parsed = Identity::from_sysex(&picodsp.to_sysex()).unwrap();
+        assert!(parsed.is_picodsp());
+        assert_eq!(parsed.firmware(), "1.2.3");
+
+        // Three byte manufacturer id, family 0x0123 sent LSB first
+        let reply = [
+            0xF0, 0x7E, 0x10, 0x06, 0x02, 0x00, 0x20, 0x29, 0x23, 0x02, 0x01, 0x00, 0x01, 0x00,
+            0x00, 0x05, 0xF7,
+        ];
+        let other = Identity::from_sysex(&reply).unwrap();
+        assert_eq!(other.manufacturer, vec![0x20, 0x29]);
+        assert_eq!(other.family, 0x0123);
+        assert_eq!(other.firmware(), "1.0.0.5");
+        assert!(!other.is_picodsp());
+    }
+
+    #[test]
+    fn stray_data_and_end_bytes_are_ignored() {
+        let mut framer = SysexFramer::new(MAX_SYSEX_LEN);
+        assert!(feed_bytewise(&mut framer, &[0x01, 0x02, 0xF7]).is_empty());
+    }
+
+    /// The raw flash image inside a bank dump.
+    fn bank_data(msg: &[u8]) -> Vec<u8> {
+        denibbleize(&msg[4..msg.len() - 1]).unwrap()
+    }
+
+    fn bank_message(data: &[u8]) -> Vec<u8> {
+        let mut msg = vec![SYSEX_START, MANUFACTURER_ID, MODEL_ID, CMD_WRITE_REQ];
+        push_payload(&mut msg, data, false);
+        msg.push(SYSEX_END);
+        msg
+    }
+
+    #[test]
+    fn truncated_dump_is_rejected() {
+        let mut msg = Storage::default().to_sysex(VERSION, false).unwrap();
+        msg.drain(msg.len() - 11..msg.len() - 1);
+        assert_eq!(
+            Storage::from_sysex(&msg).err(),
+            Some(ProtocolError::BadLength {
+                expected: STORAGE_SIZE * 2,
+                actual: STORAGE_SIZE * 2 - 10,
+            })
+        );
+        assert_eq!(
+            Preset::decode(&[0; 100], 7).err(),
+            Some(ProtocolError::BadLength {
+                expected: 200,
+                actual: 100,
+            })
+        );
+    }
+
+    #[test]
+    fn bad_header_is_rejected() {
+        let mut data = bank_data(&Storage::default().to_sysex(VERSION, false).unwrap());
+        data[..4].copy_from_slice(&0x12345678u32.to_le_bytes());
+        assert_eq!(
+            Storage::from_sysex(&bank_message(&data)).err(),
+            Some(ProtocolError::BadMagic(0x12345678))
+        );
+
+        data[..4].copy_from_slice(&MAGIC.to_le_bytes());
+        data[8..12].copy_from_slice(&100u32.to_le_bytes());
+        assert_eq!(
+            Storage::from_sysex(&bank_message(&data)).err(),
+            Some(ProtocolError::TooManyPresets {
+                count: 100,
+                max: max_presets(VERSION).unwrap(),
+            })
+        );
+
+        // Layouts older than v7 are not known, so they are not guessed at.
+        for version in [4u32, 5, 6] {
+            data[4..8].copy_from_slice(&version.to_le_bytes());
+            assert_eq!(
+                Storage::from_sysex(&bank_message(&data)).err(),
+                Some(ProtocolError::UnsupportedVersion(version))
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_nibble_is_rejected() {
+        let mut msg = Storage::default().to_sysex(VERSION, false).unwrap();
+        msg[4 + 5] = 0x10;
+        assert_eq!(
+            Storage::from_sysex(&msg).err(),
+            Some(ProtocolError::InvalidNibble {
+                offset: 5,
+                value: 0x10,
+            })
+        );
+    }
+
+    #[test]
+    fn long_names_are_cut_on_a_char_boundary() {
+        // 31 ASCII bytes, then a two-byte character that does not fit.
+        let name = format!("{}é", "a".repeat(31));
+        assert_eq!(truncate_name(&name), "a".repeat(31));
+        assert_eq!(truncate_name("Pad"), "Pad");
+
+        let preset = Preset {
+            name: "Cordes frottées, très éthérées".to_string(),
+            ..Default::default()
+        };
+        let decoded = Preset::decode(&preset.encode(VERSION).unwrap(), VERSION).unwrap();
+        assert_eq!(decoded.name, "Cordes frottées, très éthér");
+    }
+
+    #[test]
+    fn out_of_range_choice_is_rejected() {
+        let mut data = bank_data(&Storage::default().to_sysex(VERSION, false).unwrap());
+        let offset = HEADER_SIZE + crate::params::desc(ParamId::Osc2Waveform).offset;
+        data[offset..offset + 4].copy_from_slice(&9u32.to_le_bytes());
+        assert_eq!(
+            Storage::from_sysex(&bank_message(&data)).err(),
+            Some(ProtocolError::BadEnumValue {
+                field: "Osc 2 Wave",
+                value: 9,
+            })
+        );
+    }
+
+    fn migration_preset() -> Preset {
+        let mut preset = Preset {
+            name: "Lead".to_string(),
+            portamento: 0.3,
+            ..Default::default()
+        };
+        preset.osc2.level = 0.5;
+        preset.delay.enabled = true;
+        preset.velocity.amp = 0.5;
+        preset
+    }
+
+    #[test]
+    fn v7_round_trips() {
+        let storage = Storage {
+            presets: vec![migration_preset()],
+            version: VERSION,
+        };
+        assert_eq!(migration_preset().encode(7).unwrap().len(), 200);
+
+        let msg = storage.to_sysex(7, false).unwrap();
+        let decoded = Storage::from_sysex(&msg).unwrap();
+        assert_eq!(decoded.version, 7);
+
+        // Fields v7 does not store come back as defaults.
+        let mut expected = migration_preset();
+        for desc in PARAMS.iter().filter(|d| d.since > 7) {
+            expected.set_param(desc.id, desc.default).unwrap();
+        }
+        assert_eq!(decoded.presets, vec![expected]);
+        assert_eq!(decoded.to_sysex(7, false).unwrap(), msg);
+    }
+
+    #[test]
+    fn bank_over_capacity_is_rejected() {
+        let mut storage = Storage {
+            presets: vec![Preset::default(); 21],
+            version: 7,
+        };
+        assert_eq!(storage.capacity(), 20);
+        for version in SUPPORTED_VERSIONS {
+            assert_eq!(
+                storage.to_sysex(*version, false).err(),
+                Some(ProtocolError::BankFull {
+                    count: 21,
+                    capacity: 20,
+                })
+            );
+        }
+        storage.presets.pop();
+        assert!(storage.to_sysex(7, false).is_ok());
+        assert_eq!(storage.free_slots(), 0);
+        assert!(storage.is_full());
+    }
+
+    #[test]
+    fn downgrades_warn_about_lost_fields() {
+        let storage = Storage {
+            presets: vec![Preset::default(), migration_preset()],
+            version: VERSION,
+        };
+        assert!(storage.downgrade_warnings(VERSION).is_empty());
+
+        let warnings = storage.downgrade_warnings(7);
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].index, 1);
+        assert_eq!(warnings[0].lost, vec!["Velocity to Amp"]);
+    }
+
+    #[test]
+    fn param_messages_round_trip() {
+        let msg = param_sysex(ParamId::FilterCutoff, 1234.5);
+        assert_eq!(msg.len(), 14);
+        assert_eq!(
+            msg[..5],
+            [SYSEX_START, MANUFACTURER_ID, MODEL_ID, CMD_SET_PARAM, 0x20]
+        );
+        assert_eq!(parse_param_sysex(&msg), Ok((ParamId::FilterCutoff, 1234.5)));
+
+        let mut unknown = msg.clone();
+        unknown[4] = 0x7F;
+        assert_eq!(
+            parse_param_sysex(&unknown),
+            Err(ProtocolError::BadEnumValue {
+                field: "parameter id",
+                value: 0x7F,
+            })
+        );
+        assert_eq!(
+            parse_param_sysex(&msg[..13]),
+            Err(ProtocolError::InvalidFrame)
+        );
+    }
+
+    #[test]
+    fn checksum_trailer_round_trips() {
+        let storage = Storage {
+            presets: vec![Preset::default(), migration_preset()],
+            version: VERSION,
+        };
+        let msg = storage.to_sysex(VERSION, true).unwrap();
+        assert_eq!(msg.len(), 4 + STORAGE_SIZE * 2 + CRC_NIBBLES + 1);
+        assert_eq!(Storage::from_sysex(&msg).unwrap().presets, storage.presets);
+
+        let msg = storage.preset_sysex(1, VERSION, true).unwrap();
+        assert_eq!(Preset::from_sysex(&msg), Ok((1, migration_preset())));
+    }
+
+    #[test]
+    fn corrupted_checksum_is_rejected() {
+        let mut msg = Storage::default().to_sysex(VERSION, true).unwrap();
+        let trailer = msg.len() - 1 - CRC_NIBBLES;
+        let computed = u16::from_be_bytes(
+            denibbleize(&msg[trailer..msg.len() - 1]).unwrap()[..2]
+                .try_into()
+                .unwrap(),
+        );
+        msg[trailer + CRC_NIBBLES - 1] ^= 0x01;
+        assert_eq!(
+            Storage::from_sysex(&msg).err(),
+            Some(ProtocolError::ChecksumMismatch {
+                received: computed ^ 0x0001,
+                computed,
+            })
+        );
+    }
+
+    #[test]
+    fn payload_without_trailer_is_accepted() {
+        let storage = Storage {
+            presets: vec![migration_preset()],
+            version: VERSION,
+        };
+        let msg = storage.to_sysex(VERSION, false).unwrap();
+        assert_eq!(msg.len(), 4 + STORAGE_SIZE * 2 + 1);
+        assert_eq!(Storage::from_sysex(&msg).unwrap().presets, storage.presets);
+    }
+
+    #[test]
+    fn sysex_drops_a_partial_channel_message() {
+        let mut framer = SysexFramer::new(MAX_SYSEX_LEN);
+        let ack = [
+            SYSEX_START,
+            MANUFACTURER_ID,
+            MODEL_ID,
+            CMD_WRITE_SUCCESS,
+            SYSEX_END,
+        ];
+        let mut bytes = vec![0x90, 0x3C];
+        bytes.extend_from_slice(&ack);
+        bytes.push(0x40);
+        assert_eq!(
+            feed_bytewise(&mut framer, &bytes),
+            vec![MidiEvent::Sysex(ack.to_vec())]
+        );
+    }
+
+    #[test]
+    fn firmware_info_stays_in_data_bytes() {
+        let info = FirmwareInfo {
+            firmware: [1, 2, 200],
+            storage_version: VERSION,
+            capacity: 300,
+            features: Features::ALL,
+        };
+        let msg = info.to_sysex();
+        assert!(msg[1..msg.len() - 1].iter().all(|&b| b < 0x80));
+        assert_eq!(
+            FirmwareInfo::from_sysex(&msg),
+            Ok(FirmwareInfo {
+                firmware: [1, 2, 127],
+                capacity: 127,
+                ..info
+            })
+        );
+
+        let short = [msg[..8].to_vec(), vec![SYSEX_END]].concat();
+        assert_eq!(
+            FirmwareInfo::from_sysex(&short),
+            Err(ProtocolError::BadLength {
+                expected: INFO_LEN,
+                actual: 4,
+            })
+        );
     }
 }
@@ -0,0 +1,232 @@
+use std::time::{Duration, Instant};
+
+use crate::protocol::*;
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+pub const DEFAULT_RETRIES: u32 = 2;
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum ReadTarget {
+    Bank,
+    Preset(usize),
+}
+
+/// What the app should do after `ReadSession::poll`.
+pub enum ReadStep {
+    Wait,
+    /// Send the request again and show the status.
+    Resend(Vec<u8>, String),
+    /// No answer after all retries; show the status.
+    Failed(String),
+}
+
+/// Tracks an outstanding "Load from Device" or "Reload Preset" request, so
+/// only its reply is applied. Replies arriving after the request finished,
+/// timed out or was cancelled are late or duplicates and must be ignored.
+pub struct ReadSession {
+    pub target: ReadTarget,
+    message: Vec<u8>,
+    /// Storage format version and CRC trailer the reply is expected in.
+    version: u32,
+    checksum: bool,
+    timeout: Duration,
+    max_attempts: u32,
+    attempts: u32,
+    deadline: Instant,
+    /// Bytes of the reply received so far.
+    received: usize,
+}
+
+impl ReadSession {
+    /// Starts tracking a request that is about to be sent. The timeout runs
+    /// from the request or the last received byte, so a slow transfer that
+    /// is still making progress does not time out.
+    pub fn new(
+        target: ReadTarget,
+        version: u32,
+        checksum: bool,
+        timeout: Duration,
+        retries: u32,
+        now: Instant,
+    ) -> Self {
+        let message = match target {
+            ReadTarget::Bank => vec![
+                SYSEX_START,
+                MANUFACTURER_ID,
+                MODEL_ID,
+                CMD_DUMP_REQ,
+                SYSEX_END,
+            ],
+            ReadTarget::Preset(index) => preset_request(index),
+        };
+        Self {
+            target,
+            message,
+            version,
+            checksum,
+            timeout,
+            max_attempts: retries + 1,
+            attempts: 1,
+            deadline: now + timeout,
+            received: 0,
+        }
+    }
+
+    pub fn message(&self) -> &[u8] {
+        &self.message
+    }
+
+    /// Size of the reply, e.g. 8197 bytes for a bank dump without CRC
+    /// trailer. A preset's size depends on the storage format version.
+    pub fn expected_len(&self) -> usize {
+        let body = match self.target {
+            ReadTarget::Bank => 4 + STORAGE_SIZE * 2,
+            ReadTarget::Preset(_) => 6 + preset_size(self.version).unwrap_or(PRESET_SIZE) * 2,
+        };
+        let trailer = if self.checksum { CRC_NIBBLES } else { 0 };
+        body + trailer + 1
+    }
+
+    /// True if `msg` is the reply this request is waiting for.
+    pub fn accepts(&self, msg: &[u8]) -> bool {
+        match self.target {
+            ReadTarget::Bank => msg.get(3) == Some(&CMD_WRITE_REQ),
+            ReadTarget::Preset(index) => {
+                msg.get(3) == Some(&CMD_PRESET_DATA) && msg.get(4) == Some(&(index as u8))
+            }
+        }
+    }
+
+    pub fn on_progress(&mut self, received: usize, now: Instant) {
+        if received != self.received {
+            self.deadline = now + self.timeout;
+        }
+        self.received = received;
+    }
+
+    pub fn progress(&self) -> String {
+        let what = match self.target {
+            ReadTarget::Bank => "bank".to_string(),
+            ReadTarget::Preset(index) => format!("preset {}", index + 1),
+        };
+        if self.received == 0 {
+            format!(
+                "Waiting for {} (attempt {}/{})",
+                what, self.attempts, self.max_attempts
+            )
+        } else {
+            format!(
+                "Receiving {}: {}/{} bytes",
+                what,
+                self.received,
+                self.expected_len()
+            )
+        }
+    }
+
+    pub fn poll(&mut self, now: Instant) -> ReadStep {
+        if now < self.deadline {
+            return ReadStep::Wait;
+        }
+        if self.attempts >= self.max_attempts {
+            return ReadStep::Failed(format!(
+                "No reply from device after {} attempts",
+                self.attempts
+            ));
+        }
+        self.attempts += 1;
+        self.received = 0;
+        self.deadline = now + self.timeout;
+        ReadStep::Resend(
+            self.message.clone(),
+            format!(
+                "No reply, retrying (attempt {}/{})",
+                self.attempts, self.max_attempts
+            ),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retries_then_fails_without_reply() {
+        let mut now = Instant::now();
+        let mut session =
+            ReadSession::new(ReadTarget::Bank, VERSION, false, DEFAULT_TIMEOUT, 1, now);
+        assert!(matches!(session.poll(now), ReadStep::Wait));
+
+        now += DEFAULT_TIMEOUT;
+        let ReadStep::Resend(msg, _) = session.poll(now) else {
+            panic!("expected a retry");
+        };
+        assert_eq!(msg, session.message());
+
+        now += DEFAULT_TIMEOUT;
+        assert!(matches!(session.poll(now), ReadStep::Failed(_)));
+    }
+
+    #[test]
+    fn progress_keeps_a_slow_transfer_alive() {
+        let start = Instant::now();
+        let mut session =
+            ReadSession::new(ReadTarget::Bank, VERSION, false, DEFAULT_TIMEOUT, 0, start);
+        let later = start + DEFAULT_TIMEOUT - Duration::from_millis(1);
+        session.on_progress(4096, later);
+        assert!(matches!(
+            session.poll(start + DEFAULT_TIMEOUT),
+            ReadStep::Wait
+        ));
+        assert_eq!(session.progress(), "Receiving bank: 4096/8197 bytes");
+    }
+
+    #[test]
+    fn accepts_only_the_requested_reply() {
+        let now = Instant::now();
+        let storage = Storage::default();
+        let dump = storage.to_sysex(VERSION, false).unwrap();
+        let preset = storage.presets[0]
+            .to_sysex(CMD_PRESET_DATA, 0, VERSION, false)
+            .unwrap();
+        let other_slot = storage.presets[0]
+            .to_sysex(CMD_PRESET_DATA, 3, VERSION, false)
+            .unwrap();
+
+        let bank = ReadSession::new(ReadTarget::Bank, VERSION, false, DEFAULT_TIMEOUT, 0, now);
+        assert!(bank.accepts(&dump));
+        assert!(!bank.accepts(&preset));
+
+        let single = ReadSession::new(
+            ReadTarget::Preset(0),
+            VERSION,
+            false,
+            DEFAULT_TIMEOUT,
+            0,
+            now,
+        );
+        assert!(single.accepts(&preset));
+        assert!(!single.accepts(&other_slot));
+        assert!(!single.accepts(&dump));
+    }
+
+    #[test]
+    fn expected_len_follows_version_and_checksum() {
+        let now = Instant::now();
+        let len = |target, version, checksum| {
+            ReadSession::new(target, version, checksum, DEFAULT_TIMEOUT, 0, now).expected_len()
+        };
+        assert_eq!(len(ReadTarget::Bank, 7, false), 8197);
+        assert_eq!(len(ReadTarget::Bank, 8, true), 8201);
+        assert_eq!(len(ReadTarget::Preset(2), 7, false), 407);
+        assert_eq!(len(ReadTarget::Preset(2), 7, true), 411);
+        assert_eq!(len(ReadTarget::Preset(2), 8, false), 415);
+
+        // The total matches what the reply actually carries.
+        let preset = Preset::default()
+            .to_sysex(CMD_PRESET_DATA, 2, 7, true)
+            .unwrap();
+        assert_eq!(preset.len(), 411);
+    }
+}
@@ -0,0 +1,152 @@
+//! Offline rendering of presets through the local engine, for audio previews
+//! and regression tests without an audio device.
+
+use crate::audio::Engine;
+use crate::protocol::Preset;
+
+/// Frames processed per engine call, the same order as a live stream.
+const BLOCK_FRAMES: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RenderNote {
+    pub note: u8,
+    pub velocity: u8,
+    /// How long the key is held, in seconds.
+    pub duration: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WavFormat {
+    Float32,
+    Pcm24,
+}
+
+/// Plays `notes` one after another and keeps rendering for `tail` seconds
+/// after the last release, so envelopes and effects can ring out. Returns
+/// interleaved stereo samples.
+pub fn render(preset: &Preset, notes: &[RenderNote], sample_rate: u32, tail: f32) -> Vec<f32> {
+    let mut engine = Engine::new(preset, sample_rate as f32);
+    let mut out = Vec::new();
+
+    for n in notes {
+        engine.note_on(n.note, n.velocity);
+        run(
+            &mut engine,
+            &mut out,
+            seconds_to_frames(n.duration, sample_rate),
+        );
+        engine.note_off(n.note);
+    }
+    run(&mut engine, &mut out, seconds_to_frames(tail, sample_rate));
+
+    out
+}
+
+fn seconds_to_frames(seconds: f32, sample_rate: u32) -> usize {
+    (seconds.max(0.0) * sample_rate as f32).round() as usize
+}
+
+fn run(engine: &mut Engine, out: &mut Vec<f32>, frames: usize) {
+    let start = out.len();
+    out.resize(start + frames * 2, 0.0);
+    for block in out[start..].chunks_mut(BLOCK_FRAMES * 2) {
+        engine.process(block);
+    }
+}
+
+/// Encodes interleaved stereo samples as a WAV file.
+pub fn encode_wav(samples: &[f32], sample_rate: u32, format: WavFormat) -> Vec<u8> {
+    const CHANNELS: u16 = 2;
+    let (tag, bits): (u16, u16) = match format {
+        WavFormat::Float32 => (3, 32),
+        WavFormat::Pcm24 => (1, 24),
+    };
+    let block_align = CHANNELS * bits / 8;
+    let data_len = samples.len() as u32 * (bits / 8) as u32;
+
+    let mut wav = Vec::with_capacity(44 + data_len as usize);
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+    wav.extend_from_slice(b"WAVE");
+    wav.extend_from_slice(b"fmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    wav.extend_from_slice(&tag.to_le_bytes());
+    wav.extend_from_slice(&CHANNELS.to_le_bytes());
+    wav.extend_from_slice(&sample_rate.to_le_bytes());
+    wav.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+    wav.extend_from_slice(&block_align.to_le_bytes());
+    wav.extend_from_slice(&bits.to_le_bytes());
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_len.to_le_bytes());
+
+    for &sample in samples {
+        match format {
+            WavFormat::Float32 => wav.extend_from_slice(&sample.to_le_bytes()),
+            WavFormat::Pcm24 => {
+                let value = (sample.clamp(-1.0, 1.0) * 8_388_607.0).round() as i32;
+                wav.extend_from_slice(&value.to_le_bytes()[..3]);
+            }
+        }
+    }
+    wav
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 22050;
+
+    fn peak(samples: &[f32]) -> f32 {
+        samples.iter().fold(0.0, |m, s| m.max(s.abs()))
+    }
+
+    #[test]
+    fn renders_note_and_release_tail() {
+        let note = RenderNote {
+            note: 60,
+            velocity: 100,
+            duration: 0.2,
+        };
+        let out = render(&Preset::default(), &[note], RATE, 0.5);
+
+        assert_eq!(out.len(), (0.7 * RATE as f32).round() as usize * 2);
+        assert!(out.iter().all(|s| s.is_finite()));
+        assert!(peak(&out[..out.len() / 4]) > 0.05);
+        // The default amp release is 0.1 s, so the end of the tail is silent.
+        assert!(peak(&out[out.len() - 2000..]) < 1e-3);
+    }
+
+    #[test]
+    fn velocity_scales_the_level() {
+        let mut preset = Preset::default();
+        preset.velocity.amp = 1.0;
+        let level = |velocity| {
+            let note = RenderNote {
+                note: 60,
+                velocity,
+                duration: 0.2,
+            };
+            peak(&render(&preset, &[note], RATE, 0.0))
+        };
+
+        let ratio = level(32) / level(127);
+        assert!((ratio - 32.0 / 127.0).abs() < 0.05, "ratio {}", ratio);
+    }
+
+    #[test]
+    fn encodes_wav_headers_and_samples() {
+        let wav = encode_wav(&[1.0, -1.0, 0.5, 0.0], 48000, WavFormat::Pcm24);
+        assert_eq!(&wav[..4], b"RIFF");
+        assert_eq!(&wav[8..12], b"WAVE");
+        assert_eq!(u16::from_le_bytes([wav[20], wav[21]]), 1);
+        assert_eq!(u16::from_le_bytes([wav[34], wav[35]]), 24);
+        assert_eq!(wav.len(), 44 + 4 * 3);
+        assert_eq!(&wav[44..47], &[0xFF, 0xFF, 0x7F]);
+        assert_eq!(&wav[47..50], &[0x01, 0x00, 0x80]);
+
+        let wav = encode_wav(&[0.25, 0.25], 44100, WavFormat::Float32);
+        assert_eq!(u16::from_le_bytes([wav[20], wav[21]]), 3);
+        assert_eq!(&wav[44..48], &0.25f32.to_le_bytes());
+    }
+}
@@ -0,0 +1,114 @@
+//! Editor settings kept between runs, as TOML in the user's config directory.
+
+use std::fs;
+use std::path::PathBuf;
+
+use picoedit::audio::AudioSettings;
+use toml::{Table, Value};
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Settings {
+    pub audio: AudioSettings,
+}
+
+/// `picoedit/settings.toml` in the platform's config directory, or in the
+/// working directory if there is none.
+pub fn path() -> PathBuf {
+    let config_dir = if cfg!(windows) {
+        std::env::var_os("APPDATA").map(PathBuf::from)
+    } else if cfg!(target_os = "macos") {
+        std::env::var_os("HOME").map(|home| PathBuf::from(home).join("Library/Application Support"))
+    } else {
+        std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+    };
+    match config_dir {
+        Some(dir) => dir.join("picoedit").join("settings.toml"),
+        None => PathBuf::from("picoedit-settings.toml"),
+    }
+}
+
+/// The saved settings, or the defaults if there are none or they can't be
+/// read.
+pub fn load() -> Settings {
+    let path = path();
+    let Ok(text) = fs::read_to_string(&path) else {
+        return Settings::default();
+    };
+    from_toml(&text).unwrap_or_else(|e| {
+        log::warn!("Ignoring {}: {}", path.display(), e);
+        Settings::default()
+    })
+}
+
+pub fn save(settings: &Settings) -> anyhow::Result<()> {
+    let path = path();
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(&path, to_toml(settings))?;
+    Ok(())
+}
+
+fn to_toml(settings: &Settings) -> String {
+    let a = &settings.audio;
+    let mut audio = Table::new();
+    if let Some(host) = &a.host {
+        audio.insert("host".into(), Value::String(host.clone()));
+    }
+    if let Some(device) = &a.device {
+        audio.insert("device".into(), Value::String(device.clone()));
+    }
+    if let Some(rate) = a.sample_rate {
+        audio.insert("sample_rate".into(), Value::Integer(rate.into()));
+    }
+    if let Some(frames) = a.buffer_frames {
+        audio.insert("buffer_frames".into(), Value::Integer(frames.into()));
+    }
+    let mut doc = Table::new();
+    doc.insert("audio".into(), Value::Table(audio));
+    doc.to_string()
+}
+
+/// Missing keys keep their defaults.
+fn from_toml(text: &str) -> anyhow::Result<Settings> {
+    let doc: Table = text.parse()?;
+    let mut settings = Settings::default();
+    let Some(audio) = doc.get("audio").and_then(Value::as_table) else {
+        return Ok(settings);
+    };
+    let string = |key| audio.get(key).and_then(Value::as_str).map(str::to_string);
+    let number = |key| {
+        audio
+            .get(key)
+            .and_then(Value::as_integer)
+            .and_then(|n| u32::try_from(n).ok())
+    };
+    settings.audio = AudioSettings {
+        host: string("host"),
+        device: string("device"),
+        sample_rate: number("sample_rate"),
+        buffer_frames: number("buffer_frames"),
+    };
+    Ok(settings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_round_trip() {
+        let settings = Settings {
+            audio: AudioSettings {
+                host: Some("JACK".into()),
+                device: Some("system".into()),
+                sample_rate: Some(48000),
+                buffer_frames: None,
+            },
+        };
+        assert_eq!(from_toml(&to_toml(&settings)).unwrap(), settings);
+        assert_eq!(from_toml("").unwrap(), Settings::default());
+    }
+}
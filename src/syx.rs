@@ -0,0 +1,300 @@
+use crate::protocol::*;
+
+/// How a bank is laid out when written to a `.syx` file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyxLayout {
+    /// A single `CMD_WRITE_REQ` bank dump, the same as a `.pdsp` file.
+    Dump,
+    /// One `CMD_PRESET_WRITE` message per slot, so single presets can be
+    /// cut out of the file or sent to the device individually.
+    PerPreset,
+}
+
+/// Something in a `.syx` file that did not end up in the bank.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Skipped {
+    /// Byte offset of the message (or stray data) in the file.
+    pub offset: usize,
+    pub reason: String,
+}
+
+impl std::fmt::Display for Skipped {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "byte {}: {}", self.offset, self.reason)
+    }
+}
+
+pub struct SyxFile {
+    pub storage: Storage,
+    /// Number of picoDSP messages that were used.
+    pub used: usize,
+    pub skipped: Vec<Skipped>,
+}
+
+/// Splits a file into complete `F0 .. F7` messages, returning each with its
+/// byte offset. Realtime bytes inside a message are dropped, as a capture of
+/// a live MIDI stream may contain them. Bytes outside a message and
+/// unterminated messages are reported as skipped.
+pub fn split_messages(data: &[u8]) -> (Vec<(usize, Vec<u8>)>, Vec<Skipped>) {
+    let mut messages = Vec::new();
+    let mut skipped = Vec::new();
+    let mut pos = 0;
+
+    while pos < data.len() {
+        if data[pos] != SYSEX_START {
+            let start = pos;
+            while pos < data.len() && data[pos] != SYSEX_START {
+                pos += 1;
+            }
+            skipped.push(Skipped {
+                offset: start,
+                reason: format!("{} stray bytes outside SysEx", pos - start),
+            });
+            continue;
+        }
+
+        let start = pos;
+        let mut msg = vec![SYSEX_START];
+        pos += 1;
+        // Any status byte but F7 and the realtime ones ends the message
+        // early.
+        while pos < data.len() && (data[pos] < 0x80 || data[pos] >= 0xF8) {
+            if data[pos] < 0x80 {
+                msg.push(data[pos]);
+            }
+            pos += 1;
+        }
+        if pos < data.len() && data[pos] == SYSEX_END {
+            pos += 1;
+            msg.push(SYSEX_END);
+            messages.push((start, msg));
+        } else {
+            skipped.push(Skipped {
+                offset: start,
+                reason: "unterminated SysEx message".to_string(),
+            });
+        }
+    }
+
+    (messages, skipped)
+}
+
+fn describe_foreign(msg: &[u8]) -> String {
+    match msg.get(1) {
+        Some(0x7E) => "universal non-realtime message".to_string(),
+        Some(0x7F) => "universal realtime message".to_string(),
+        Some(0x00) if msg.len() > 3 => {
+            format!("message for manufacturer 00 {:02X} {:02X}", msg[2], msg[3])
+        }
+        Some(id) => format!("message for manufacturer {:02X}", id),
+        None => "empty message".to_string(),
+    }
+}
+
+/// Reads a `.syx` (or `.pdsp`) file. The first bank dump, if any, forms the
+/// bank; single-preset messages are then applied on top of it slot by slot,
+/// later messages for the same slot winning.
+/// Everything else is listed in `skipped`.
+pub fn read_syx(data: &[u8]) -> Result<SyxFile, ProtocolError> {
+    let (messages, mut skipped) = split_messages(data);
+
+    let mut storage: Option<Storage> = None;
+    let mut presets = Vec::new();
+    let mut used = 0;
+    let mut first_error = None;
+
+    for (offset, msg) in &messages {
+        let (offset, msg) = (*offset, msg.as_slice());
+        if msg.len() < 4 || msg[1] != MANUFACTURER_ID || msg[2] != MODEL_ID {
+            skipped.push(Skipped {
+                offset,
+                reason: describe_foreign(msg),
+            });
+            continue;
+        }
+
+        let result = match msg[3] {
+            CMD_WRITE_REQ if storage.is_some() => Err("additional bank dump ignored".to_string()),
+            CMD_WRITE_REQ => match Storage::from_sysex(msg) {
+                Ok(s) => {
+                    storage = Some(s);
+                    Ok(())
+                }
+                Err(e) => {
+                    first_error.get_or_insert(e.clone());
+                    Err(format!("bank dump: {}", e))
+                }
+            },
+            CMD_PRESET_DATA | CMD_PRESET_WRITE => match Preset::from_sysex(msg) {
+                Ok((index, preset)) => {
+                    presets.push((offset, index, msg[5] as u32, preset));
+                    Ok(())
+                }
+                Err(e) => {
+                    first_error.get_or_insert(e.clone());
+                    Err(format!("preset message: {}", e))
+                }
+            },
+            cmd => Err(format!("picoDSP command {:02X}", cmd)),
+        };
+
+        match result {
+            Ok(()) => used += 1,
+            Err(reason) => skipped.push(Skipped { offset, reason }),
+        }
+    }
+
+    let mut storage = match storage {
+        Some(storage) => storage,
+        None if presets.is_empty() => {
+            return Err(first_error.unwrap_or(ProtocolError::InvalidFrame));
+        }
+        None => Storage {
+            presets: Vec::new(),
+            version: presets.iter().map(|p| p.2).min().unwrap_or(VERSION),
+        },
+    };
+
+    // Slots are filled in order, so presets for slots past the end of the
+    // bank only fit once the slots before them have arrived.
+    presets.sort_by_key(|p| p.1);
+    for (offset, index, _, preset) in presets {
+        if index < storage.presets.len() {
+            storage.presets[index] = preset;
+        } else if index == storage.presets.len() && !storage.is_full() {
+            storage.presets.push(preset);
+        } else {
+            used -= 1;
+            skipped.push(Skipped {
+                offset,
+                reason: format!(
+                    "preset for slot {} does not follow the {} slots before it",
+                    index + 1,
+                    storage.presets.len()
+                ),
+            });
+        }
+    }
+
+    skipped.sort_by_key(|s| s.offset);
+    Ok(SyxFile {
+        storage,
+        used,
+        skipped,
+    })
+}
+
+/// Encodes a bank as the contents of a `.syx` file.
+pub fn write_syx(
+    storage: &Storage,
+    version: u32,
+    layout: SyxLayout,
+    checksum: bool,
+) -> Result<Vec<u8>, ProtocolError> {
+    match layout {
+        SyxLayout::Dump => storage.to_sysex(version, checksum),
+        SyxLayout::PerPreset => {
+            let capacity = max_presets(version)?;
+            if storage.presets.len() > capacity {
+                return Err(ProtocolError::BankFull {
+                    count: storage.presets.len(),
+                    capacity,
+                });
+            }
+            let mut data = Vec::new();
+            for index in 0..storage.presets.len() {
+                data.extend_from_slice(&storage.preset_sysex(index, version, checksum)?);
+            }
+            Ok(data)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bank(names: &[&str]) -> Storage {
+        Storage {
+            presets: names
+                .iter()
+                .map(|name| Preset {
+                    name: name.to_string(),
+                    ..Preset::default()
+                })
+                .collect(),
+            version: VERSION,
+        }
+    }
+
+    fn names(storage: &Storage) -> Vec<&str> {
+        storage.presets.iter().map(|p| p.name.as_str()).collect()
+    }
+
+    #[test]
+    fn foreign_and_broken_data_is_skipped() {
+        let dump = bank(&["A", "B"]).to_sysex(VERSION, false).unwrap();
+        let mut data = IDENTITY_REQUEST.to_vec();
+        data.extend_from_slice(&[0x01, 0x02, 0x03]);
+        data.extend_from_slice(&dump);
+        data.extend_from_slice(&[SYSEX_START, 0x41, 0x10, 0x42, 0x12, SYSEX_END]);
+        let unterminated = data.len();
+        data.extend_from_slice(&[SYSEX_START, MANUFACTURER_ID, MODEL_ID, 0x01]);
+
+        let file = read_syx(&data).unwrap();
+        assert_eq!(names(&file.storage), vec!["A", "B"]);
+        assert_eq!(file.used, 1);
+        let skipped: Vec<_> = file
+            .skipped
+            .iter()
+            .map(|s| (s.offset, s.reason.as_str()))
+            .collect();
+        assert_eq!(
+            skipped,
+            vec![
+                (0, "universal non-realtime message"),
+                (6, "3 stray bytes outside SysEx"),
+                (9 + dump.len(), "message for manufacturer 41"),
+                (unterminated, "unterminated SysEx message"),
+            ]
+        );
+    }
+
+    #[test]
+    fn realtime_bytes_inside_a_message_are_dropped() {
+        let dump = bank(&["A"]).to_sysex(VERSION, false).unwrap();
+        let mut data = dump.clone();
+        data.insert(100, 0xF8);
+        data.insert(2000, 0xFE);
+
+        let (messages, skipped) = split_messages(&data);
+        assert_eq!(messages, vec![(0, dump)]);
+        assert!(skipped.is_empty());
+        assert_eq!(names(&read_syx(&data).unwrap().storage), vec!["A"]);
+    }
+
+    #[test]
+    fn presets_are_placed_by_slot() {
+        let storage = bank(&["A", "B", "C"]);
+        let mut data = Vec::new();
+        for index in [2, 0, 1] {
+            data.extend_from_slice(&storage.preset_sysex(index, VERSION, false).unwrap());
+        }
+        // Slot 5 can't be placed with slot 4 missing.
+        let mut gap = storage.preset_sysex(0, VERSION, false).unwrap();
+        gap[4] = 4;
+        data.extend_from_slice(&gap);
+
+        let file = read_syx(&data).unwrap();
+        assert_eq!(names(&file.storage), vec!["A", "B", "C"]);
+        assert_eq!(file.used, 3);
+        assert_eq!(file.skipped.len(), 1);
+        assert!(file.skipped[0].reason.starts_with("preset for slot 5"));
+
+        let written = write_syx(&file.storage, VERSION, SyxLayout::PerPreset, false).unwrap();
+        assert_eq!(
+            names(&read_syx(&written).unwrap().storage),
+            vec!["A", "B", "C"]
+        );
+    }
+}
@@ -0,0 +1,255 @@
+//! MIDI I/O behind a trait, so the editor's device logic does not depend on
+//! midir and can run against an in-memory device.
+
+use std::collections::VecDeque;
+
+#[cfg(feature = "midi")]
+use midir::{Ignore, MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+
+/// Called with every message (or SysEx chunk) received on the input port.
+pub type InputHandler = Box<dyn FnMut(&[u8]) + Send + 'static>;
+
+pub trait MidiTransport {
+    fn input_ports(&self) -> Vec<String>;
+    fn output_ports(&self) -> Vec<String>;
+    fn connect_output(&mut self, name: &str) -> anyhow::Result<()>;
+    /// Subscribes `handler` to the input port. It may run on another thread.
+    fn connect_input(&mut self, name: &str, handler: InputHandler) -> anyhow::Result<()>;
+    fn disconnect(&mut self);
+    fn has_output(&self) -> bool;
+    fn has_input(&self) -> bool;
+    fn send(&mut self, msg: &[u8]) -> anyhow::Result<()>;
+    /// Delivers received messages for transports without an input thread of
+    /// their own. Called once per frame.
+    fn poll(&mut self) {}
+}
+
+/// System MIDI ports through midir.
+#[cfg(feature = "midi")]
+pub struct MidirTransport {
+    midi_in: Option<MidiInput>,
+    midi_out: Option<MidiOutput>,
+    conn_in: Option<MidiInputConnection<()>>,
+    conn_out: Option<MidiOutputConnection>,
+}
+
+#[cfg(feature = "midi")]
+fn new_input() -> anyhow::Result<MidiInput> {
+    let mut midi_in = MidiInput::new("PicoEdit Input")?;
+    midi_in.ignore(Ignore::None);
+    Ok(midi_in)
+}
+
+#[cfg(feature = "midi")]
+fn new_output() -> anyhow::Result<MidiOutput> {
+    Ok(MidiOutput::new("PicoEdit Output")?)
+}
+
+#[cfg(feature = "midi")]
+impl MidirTransport {
+    pub fn new() -> anyhow::Result<Self> {
+        Ok(Self {
+            midi_in: Some(new_input()?),
+            midi_out: Some(new_output()?),
+            conn_in: None,
+            conn_out: None,
+        })
+    }
+}
+
+#[cfg(feature = "midi")]
+impl MidiTransport for MidirTransport {
+    fn input_ports(&self) -> Vec<String> {
+        self.midi_in
+            .as_ref()
+            .map(|midi_in| {
+                midi_in
+                    .ports()
+                    .iter()
+                    .filter_map(|p| midi_in.port_name(p).ok())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn output_ports(&self) -> Vec<String> {
+        self.midi_out
+            .as_ref()
+            .map(|midi_out| {
+                midi_out
+                    .ports()
+                    .iter()
+                    .filter_map(|p| midi_out.port_name(p).ok())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn connect_output(&mut self, name: &str) -> anyhow::Result<()> {
+        self.conn_out = None;
+        let midi_out = match self.midi_out.take() {
+            Some(midi_out) => midi_out,
+            None => new_output()?,
+        };
+        let port = midi_out
+            .ports()
+            .into_iter()
+            .find(|p| midi_out.port_name(p).is_ok_and(|n| n == name));
+
+        let Some(port) = port else {
+            self.midi_out = Some(midi_out);
+            anyhow::bail!("Output port not found");
+        };
+        match midi_out.connect(&port, "PicoEdit Out") {
+            Ok(conn) => {
+                self.conn_out = Some(conn);
+                Ok(())
+            }
+            Err(e) => {
+                self.midi_out = Some(new_output()?);
+                anyhow::bail!("Error connecting output: {}", e)
+            }
+        }
+    }
+
+    fn connect_input(&mut self, name: &str, mut handler: InputHandler) -> anyhow::Result<()> {
+        self.conn_in = None;
+        let midi_in = match self.midi_in.take() {
+            Some(midi_in) => midi_in,
+            None => new_input()?,
+        };
+        let port = midi_in
+            .ports()
+            .into_iter()
+            .find(|p| midi_in.port_name(p).is_ok_and(|n| n == name));
+
+        let Some(port) = port else {
+            self.midi_in = Some(midi_in);
+            anyhow::bail!("Input port not found");
+        };
+        match midi_in.connect(
+            &port,
+            "PicoEdit In",
+            move |_stamp, message, _| handler(message),
+            (),
+        ) {
+            Ok(conn) => {
+                self.conn_in = Some(conn);
+                Ok(())
+            }
+            Err(e) => {
+                self.midi_in = Some(new_input()?);
+                anyhow::bail!("Error connecting input: {}", e)
+            }
+        }
+    }
+
+    fn disconnect(&mut self) {
+        if let Some(conn) = self.conn_in.take() {
+            self.midi_in = Some(conn.close().0);
+        }
+        if let Some(conn) = self.conn_out.take() {
+            self.midi_out = Some(conn.close());
+        }
+    }
+
+    fn has_output(&self) -> bool {
+        self.conn_out.is_some()
+    }
+
+    fn has_input(&self) -> bool {
+        self.conn_in.is_some()
+    }
+
+    fn send(&mut self, msg: &[u8]) -> anyhow::Result<()> {
+        match &mut self.conn_out {
+            Some(conn) => Ok(conn.send(msg)?),
+            None => anyhow::bail!("Not connected to MIDI Output"),
+        }
+    }
+}
+
+/// Stands in for the device: gets every sent message, returns the replies.
+type Responder = Box<dyn FnMut(&[u8]) -> Vec<Vec<u8>> + Send>;
+
+/// Name of the single port pair a `LoopbackTransport` offers.
+pub const LOOPBACK_PORT: &str = "picoDSP Loopback";
+
+/// In-memory transport. Everything sent is passed to a responder standing in
+/// for the device, and its replies are delivered on the next `poll`.
+pub struct LoopbackTransport {
+    responder: Responder,
+    handler: Option<InputHandler>,
+    output: bool,
+    incoming: VecDeque<Vec<u8>>,
+}
+
+impl LoopbackTransport {
+    pub fn new(responder: impl FnMut(&[u8]) -> Vec<Vec<u8>> + Send + 'static) -> Self {
+        Self {
+            responder: Box::new(responder),
+            handler: None,
+            output: false,
+            incoming: VecDeque::new(),
+        }
+    }
+}
+
+impl MidiTransport for LoopbackTransport {
+    fn input_ports(&self) -> Vec<String> {
+        vec![LOOPBACK_PORT.to_string()]
+    }
+
+    fn output_ports(&self) -> Vec<String> {
+        vec![LOOPBACK_PORT.to_string()]
+    }
+
+    fn connect_output(&mut self, name: &str) -> anyhow::Result<()> {
+        if name != LOOPBACK_PORT {
+            anyhow::bail!("Output port not found");
+        }
+        self.output = true;
+        Ok(())
+    }
+
+    fn connect_input(&mut self, name: &str, handler: InputHandler) -> anyhow::Result<()> {
+        if name != LOOPBACK_PORT {
+            anyhow::bail!("Input port not found");
+        }
+        self.handler = Some(handler);
+        Ok(())
+    }
+
+    fn disconnect(&mut self) {
+        self.output = false;
+        self.handler = None;
+        self.incoming.clear();
+    }
+
+    fn has_output(&self) -> bool {
+        self.output
+    }
+
+    fn has_input(&self) -> bool {
+        self.handler.is_some()
+    }
+
+    fn send(&mut self, msg: &[u8]) -> anyhow::Result<()> {
+        if !self.output {
+            anyhow::bail!("Not connected to MIDI Output");
+        }
+        let replies = (self.responder)(msg);
+        if self.handler.is_some() {
+            self.incoming.extend(replies);
+        }
+        Ok(())
+    }
+
+    fn poll(&mut self) {
+        if let Some(handler) = &mut self.handler {
+            while let Some(msg) = self.incoming.pop_front() {
+                handler(&msg);
+            }
+        }
+    }
+}
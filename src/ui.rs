@@ -1,6 +1,7 @@
-use crate::audio::AudioManager;
-use crate::protocol::{Preset, Storage, Waveform};
 use eframe::egui;
+use picoedit::audio::AudioManager;
+use picoedit::params::{desc, ParamId, ParamKind, Taper};
+use picoedit::protocol::{truncate_name, Preset, Storage};
 use rustfft::{num_complex::Complex, FftPlanner};
 use std::sync::{Arc, Mutex};
 
@@ -24,66 +25,66 @@ pub fn draw_visualizer(
             painter.rect_filled(rect, 2.0, egui::Color32::from_rgb(20, 20, 20));
             painter.rect_stroke(rect, 1.0, egui::Stroke::new(1.0, egui::Color32::DARK_GRAY));
 
-            if let Ok(buffer) = audio.scope_buffer.try_lock() {
-                // Oscilloscope
-                let points: Vec<egui::Pos2> = buffer
-                    .iter()
-                    .enumerate()
-                    .map(|(i, &sample)| {
-                        let x = rect.min.x + (i as f32 / buffer.len() as f32) * rect.width();
-                        let y = rect.center().y - sample * (height * 0.9);
-                        egui::pos2(x, y)
-                    })
-                    .collect();
-                painter.add(egui::Shape::line(
-                    points,
-                    egui::Stroke::new(1.5, egui::Color32::GREEN),
-                ));
-
-                // Right: Spectrum (FFT)
-                let (response_fft, painter_fft) = ui.allocate_painter(
-                    egui::Vec2::new(available_width * 0.5 - 5.0, height), // -5 for spacing
-                    egui::Sense::hover(),
-                );
-                let rect_fft = response_fft.rect;
-                painter_fft.rect_filled(rect_fft, 2.0, egui::Color32::from_rgb(20, 20, 20));
-                painter_fft.rect_stroke(
-                    rect_fft,
-                    1.0,
-                    egui::Stroke::new(1.0, egui::Color32::DARK_GRAY),
-                );
+            let buffer = audio.scope_buffer.snapshot();
+
+            // Oscilloscope
+            let points: Vec<egui::Pos2> = buffer
+                .iter()
+                .enumerate()
+                .map(|(i, &sample)| {
+                    let x = rect.min.x + (i as f32 / buffer.len() as f32) * rect.width();
+                    let y = rect.center().y - sample * (height * 0.9);
+                    egui::pos2(x, y)
+                })
+                .collect();
+            painter.add(egui::Shape::line(
+                points,
+                egui::Stroke::new(1.5, egui::Color32::GREEN),
+            ));
+
+            // Right: Spectrum (FFT)
+            let (response_fft, painter_fft) = ui.allocate_painter(
+                egui::Vec2::new(available_width * 0.5 - 5.0, height), // -5 for spacing
+                egui::Sense::hover(),
+            );
+            let rect_fft = response_fft.rect;
+            painter_fft.rect_filled(rect_fft, 2.0, egui::Color32::from_rgb(20, 20, 20));
+            painter_fft.rect_stroke(
+                rect_fft,
+                1.0,
+                egui::Stroke::new(1.0, egui::Color32::DARK_GRAY),
+            );
 
-                // Compute FFT
-                let mut planner = fft_planner.lock().unwrap();
-                let fft = planner.plan_fft_forward(buffer.len());
-
-                let mut input: Vec<Complex<f32>> =
-                    buffer.iter().map(|&s| Complex::new(s, 0.0)).collect();
-                fft.process(&mut input);
-
-                // Draw Spectrum (Magnitude)
-                // Only display first half (Nyquist)
-                let spectrum_len = input.len() / 2;
-                let bar_width = rect_fft.width() / spectrum_len as f32;
-
-                for (i, complex) in input.iter().take(spectrum_len).enumerate() {
-                    let magnitude = complex.norm();
-                    // Logarithmic scaling for better visualization
-                    let scaled_mag = (magnitude / 10.0).clamp(0.0, 1.0);
-
-                    let x = rect_fft.min.x + i as f32 * bar_width;
-                    let bar_height = scaled_mag * rect_fft.height();
-                    let y = rect_fft.max.y - bar_height;
-
-                    painter_fft.rect_filled(
-                        egui::Rect::from_min_max(
-                            egui::pos2(x, y),
-                            egui::pos2(x + bar_width, rect_fft.max.y),
-                        ),
-                        0.0,
-                        egui::Color32::from_rgb(100, 150, 255).linear_multiply(0.8),
-                    );
-                }
+            // Compute FFT
+            let mut planner = fft_planner.lock().unwrap();
+            let fft = planner.plan_fft_forward(buffer.len());
+
+            let mut input: Vec<Complex<f32>> =
+                buffer.iter().map(|&s| Complex::new(s, 0.0)).collect();
+            fft.process(&mut input);
+
+            // Draw Spectrum (Magnitude)
+            // Only display first half (Nyquist)
+            let spectrum_len = input.len() / 2;
+            let bar_width = rect_fft.width() / spectrum_len as f32;
+
+            for (i, complex) in input.iter().take(spectrum_len).enumerate() {
+                let magnitude = complex.norm();
+                // Logarithmic scaling for better visualization
+                let scaled_mag = (magnitude / 10.0).clamp(0.0, 1.0);
+
+                let x = rect_fft.min.x + i as f32 * bar_width;
+                let bar_height = scaled_mag * rect_fft.height();
+                let y = rect_fft.max.y - bar_height;
+
+                painter_fft.rect_filled(
+                    egui::Rect::from_min_max(
+                        egui::pos2(x, y),
+                        egui::pos2(x + bar_width, rect_fft.max.y),
+                    ),
+                    0.0,
+                    egui::Color32::from_rgb(100, 150, 255).linear_multiply(0.8),
+                );
             }
         });
 
@@ -95,6 +96,7 @@ pub fn draw_preset_editor(
     ui: &mut egui::Ui,
     storage: &mut Storage,
     current_preset_index: &mut usize,
+    capacity: usize,
 ) {
     if storage.presets.is_empty() {
         ui.label("No presets loaded.");
@@ -128,20 +130,39 @@ pub fn draw_preset_editor(
             *current_preset_index += 1;
         }
 
-        if ui.button("Add New").clicked() {
+        let can_add = storage.presets.len() < capacity;
+
+        if ui
+            .add_enabled(can_add, egui::Button::new("Add New"))
+            .on_disabled_hover_text("Bank is full")
+            .clicked()
+        {
             storage.presets.push(Preset::default());
             *current_preset_index = storage.presets.len() - 1;
         }
 
-        if ui.button("Clone").clicked() {
+        if ui
+            .add_enabled(can_add, egui::Button::new("Clone"))
+            .on_disabled_hover_text("Bank is full")
+            .clicked()
+        {
             let mut new_preset = storage.presets[*current_preset_index].clone();
-            new_preset.name = format!("{} Copy", new_preset.name);
-            if new_preset.name.len() > 32 {
-                new_preset.name.truncate(32);
-            }
+            new_preset.name = truncate_name(&format!("{} Copy", new_preset.name)).to_string();
             storage.presets.push(new_preset);
             *current_preset_index = storage.presets.len() - 1;
         }
+
+        let slots = format!(
+            "Slots: {}/{} used, {} free",
+            storage.presets.len(),
+            capacity,
+            capacity.saturating_sub(storage.presets.len())
+        );
+        if storage.presets.len() > capacity {
+            ui.colored_label(egui::Color32::RED, slots);
+        } else {
+            ui.label(slots);
+        }
     });
 
     ui.separator();
@@ -156,29 +177,35 @@ pub fn draw_preset_editor(
     ui.columns(3, |cols| {
         for (i, col) in cols.iter_mut().enumerate() {
             col.heading(format!("Oscillator {}", i + 1));
-            let osc = match i {
-                0 => &mut preset.osc1,
-                1 => &mut preset.osc2,
-                _ => &mut preset.osc3,
+            let [wave, level, octave, detune, vibrato] = match i {
+                0 => [
+                    ParamId::Osc1Waveform,
+                    ParamId::Osc1Level,
+                    ParamId::Osc1Octave,
+                    ParamId::Osc1Detune,
+                    ParamId::Osc1Vibrato,
+                ],
+                1 => [
+                    ParamId::Osc2Waveform,
+                    ParamId::Osc2Level,
+                    ParamId::Osc2Octave,
+                    ParamId::Osc2Detune,
+                    ParamId::Osc2Vibrato,
+                ],
+                _ => [
+                    ParamId::Osc3Waveform,
+                    ParamId::Osc3Level,
+                    ParamId::Osc3Octave,
+                    ParamId::Osc3Detune,
+                    ParamId::Osc3Vibrato,
+                ],
             };
 
-            col.horizontal(|ui| {
-                ui.label("Wave:");
-                egui::ComboBox::from_id_salt(format!("wave_{}", i))
-                    .selected_text(format!("{:?}", osc.waveform))
-                    .show_ui(ui, |ui| {
-                        ui.selectable_value(&mut osc.waveform, Waveform::Sine, "Sine");
-                        ui.selectable_value(&mut osc.waveform, Waveform::Triangle, "Triangle");
-                        ui.selectable_value(&mut osc.waveform, Waveform::Saw, "Saw");
-                        ui.selectable_value(&mut osc.waveform, Waveform::Square, "Square");
-                        ui.selectable_value(&mut osc.waveform, Waveform::Noise, "Noise");
-                    });
-            });
-
-            col.add(egui::Slider::new(&mut osc.level, 0.0..=1.0).text("Level"));
-            col.add(egui::Slider::new(&mut osc.octave, -2.0..=2.0).text("Octave"));
-            col.add(egui::Slider::new(&mut osc.detune, -100.0..=100.0).text("Detune"));
-            col.checkbox(&mut osc.vibrato, "Vibrato");
+            param_combo(col, preset, wave);
+            param_slider(col, preset, level);
+            param_slider(col, preset, octave);
+            param_slider(col, preset, detune);
+            param_checkbox(col, preset, vibrato);
         }
     });
 
@@ -186,78 +213,145 @@ pub fn draw_preset_editor(
 
     ui.columns(3, |cols| {
         cols[0].heading("Filter");
-        cols[0].add(
-            egui::Slider::new(&mut preset.filter.cutoff, 20.0..=20000.0)
-                .text("Cutoff")
-                .logarithmic(true),
-        );
-        cols[0].add(egui::Slider::new(&mut preset.filter.resonance, 0.0..=1.0).text("Resonance"));
-        cols[0]
-            .add(egui::Slider::new(&mut preset.filter.env_amt, -10000.0..=10000.0).text("Env Amt"));
+        param_slider(&mut cols[0], preset, ParamId::FilterCutoff);
+        param_slider(&mut cols[0], preset, ParamId::FilterResonance);
+        param_slider(&mut cols[0], preset, ParamId::FilterEnvAmt);
 
         cols[0].label("Filter Envelope");
         cols[0].horizontal(|ui| {
-            ui.add(
-                egui::Slider::new(&mut preset.filter.attack, 0.0..=5.0)
-                    .text("A")
-                    .vertical(),
-            );
-            ui.add(
-                egui::Slider::new(&mut preset.filter.decay, 0.0..=5.0)
-                    .text("D")
-                    .vertical(),
-            );
-            ui.add(
-                egui::Slider::new(&mut preset.filter.sustain, 0.0..=1.0)
-                    .text("S")
-                    .vertical(),
-            );
-            ui.add(
-                egui::Slider::new(&mut preset.filter.release, 0.0..=5.0)
-                    .text("R")
-                    .vertical(),
-            );
+            param_vslider(ui, preset, ParamId::FilterAttack);
+            param_vslider(ui, preset, ParamId::FilterDecay);
+            param_vslider(ui, preset, ParamId::FilterSustain);
+            param_vslider(ui, preset, ParamId::FilterRelease);
         });
 
         cols[1].heading("Amp Envelope");
         cols[1].horizontal(|ui| {
-            ui.add(
-                egui::Slider::new(&mut preset.amp.attack, 0.0..=5.0)
-                    .text("A")
-                    .vertical(),
-            );
-            ui.add(
-                egui::Slider::new(&mut preset.amp.decay, 0.0..=5.0)
-                    .text("D")
-                    .vertical(),
-            );
-            ui.add(
-                egui::Slider::new(&mut preset.amp.sustain, 0.0..=1.0)
-                    .text("S")
-                    .vertical(),
-            );
-            ui.add(
-                egui::Slider::new(&mut preset.amp.release, 0.0..=5.0)
-                    .text("R")
-                    .vertical(),
-            );
+            param_vslider(ui, preset, ParamId::AmpAttack);
+            param_vslider(ui, preset, ParamId::AmpDecay);
+            param_vslider(ui, preset, ParamId::AmpSustain);
+            param_vslider(ui, preset, ParamId::AmpRelease);
         });
 
-        cols[1].add(egui::Slider::new(&mut preset.noise, 0.0..=1.0).text("Noise Level"));
-        cols[1].add(egui::Slider::new(&mut preset.portamento, 0.0..=1.0).text("Portamento"));
+        param_slider(&mut cols[1], preset, ParamId::Noise);
+        param_slider(&mut cols[1], preset, ParamId::Portamento);
+
+        cols[1].separator();
+        cols[1].label("LFO");
+        param_checkbox(&mut cols[1], preset, ParamId::LfoEnabled);
+        param_combo(&mut cols[1], preset, ParamId::LfoWaveform);
+        param_slider(&mut cols[1], preset, ParamId::LfoFreq);
+        param_slider(&mut cols[1], preset, ParamId::LfoVibAmt);
+        param_slider(&mut cols[1], preset, ParamId::LfoFiltAmt);
+
+        cols[1].separator();
+        cols[1].label("Velocity");
+        param_slider(&mut cols[1], preset, ParamId::VelocityAmp);
+        param_slider(&mut cols[1], preset, ParamId::VelocityFilter);
 
         cols[2].heading("Effects");
         cols[2].label("Delay");
-        cols[2].checkbox(&mut preset.delay.enabled, "Enable Delay");
-        cols[2].add(egui::Slider::new(&mut preset.delay.time, 0.0..=2.0).text("Time"));
-        cols[2].add(egui::Slider::new(&mut preset.delay.feedback, 0.0..=1.0).text("Feedback"));
-        cols[2].add(egui::Slider::new(&mut preset.delay.mix, 0.0..=1.0).text("Mix"));
+        param_checkbox(&mut cols[2], preset, ParamId::DelayEnabled);
+        param_slider(&mut cols[2], preset, ParamId::DelayTime);
+        param_slider(&mut cols[2], preset, ParamId::DelayFeedback);
+        param_slider(&mut cols[2], preset, ParamId::DelayMix);
 
         cols[2].separator();
         cols[2].label("Reverb");
-        cols[2].checkbox(&mut preset.reverb.enabled, "Enable Reverb");
-        cols[2].add(egui::Slider::new(&mut preset.reverb.size, 0.0..=1.0).text("Size"));
-        cols[2].add(egui::Slider::new(&mut preset.reverb.damping, 0.0..=1.0).text("Damping"));
-        cols[2].add(egui::Slider::new(&mut preset.reverb.mix, 0.0..=1.0).text("Mix"));
+        param_checkbox(&mut cols[2], preset, ParamId::ReverbEnabled);
+        param_slider(&mut cols[2], preset, ParamId::ReverbSize);
+        param_slider(&mut cols[2], preset, ParamId::ReverbDamping);
+        param_slider(&mut cols[2], preset, ParamId::ReverbMix);
+    });
+}
+
+fn param_hover_text(id: ParamId) -> String {
+    let d = desc(id);
+    match d.cc {
+        Some(cc) => format!("{} (CC {})", d.name, cc),
+        None => d.name.to_string(),
+    }
+}
+
+fn make_slider(value: &mut f32, id: ParamId) -> egui::Slider<'_> {
+    let d = desc(id);
+    let mut slider = egui::Slider::new(value, d.min..=d.max)
+        .text(d.label)
+        .logarithmic(d.taper == Taper::Log);
+    if !d.unit.is_empty() {
+        slider = slider.suffix(format!(" {}", d.unit));
+    }
+    slider
+}
+
+// Widgets only produce values inside the descriptor's range, so set_param
+// cannot fail for them.
+fn param_slider(ui: &mut egui::Ui, preset: &mut Preset, id: ParamId) {
+    let mut value = preset.param(id);
+    if ui
+        .add(make_slider(&mut value, id))
+        .on_hover_text(param_hover_text(id))
+        .changed()
+    {
+        let _ = preset.set_param(id, value);
+    }
+}
+
+fn param_vslider(ui: &mut egui::Ui, preset: &mut Preset, id: ParamId) {
+    let mut value = preset.param(id);
+    if ui
+        .add(make_slider(&mut value, id).vertical())
+        .on_hover_text(param_hover_text(id))
+        .changed()
+    {
+        let _ = preset.set_param(id, value);
+    }
+}
+
+fn param_checkbox(ui: &mut egui::Ui, preset: &mut Preset, id: ParamId) {
+    let mut checked = preset.param(id) >= 0.5;
+    if ui.checkbox(&mut checked, desc(id).label).changed() {
+        let _ = preset.set_param(id, if checked { 1.0 } else { 0.0 });
+    }
+}
+
+fn param_combo(ui: &mut egui::Ui, preset: &mut Preset, id: ParamId) {
+    let d = desc(id);
+    let ParamKind::Choice(names) = d.kind else {
+        return;
+    };
+    let mut selected = preset.param(id) as usize;
+
+    ui.horizontal(|ui| {
+        ui.label(format!("{}:", d.label));
+        egui::ComboBox::from_id_salt(id)
+            .selected_text(names.get(selected).copied().unwrap_or("?"))
+            .show_ui(ui, |ui| {
+                for (i, name) in names.iter().enumerate() {
+                    ui.selectable_value(&mut selected, i, *name);
+                }
+            });
     });
+
+    if selected as f32 != preset.param(id) {
+        let _ = preset.set_param(id, selected as f32);
+    }
+}
+
+/// A combo box over `options` with a leading "Default" entry for `None`.
+pub fn default_combo<T: Clone + PartialEq>(
+    ui: &mut egui::Ui,
+    id: &str,
+    value: &mut Option<T>,
+    options: &[T],
+    text: impl Fn(&T) -> String,
+) {
+    egui::ComboBox::from_id_salt(id)
+        .selected_text(value.as_ref().map_or("Default".to_string(), &text))
+        .show_ui(ui, |ui| {
+            ui.selectable_value(value, None, "Default");
+            for option in options {
+                ui.selectable_value(value, Some(option.clone()), text(option));
+            }
+        });
 }
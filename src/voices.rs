@@ -0,0 +1,264 @@
+//! Voice allocation for the local preview engine.
+
+/// Highest polyphony the local engine offers.
+pub const MAX_POLYPHONY: usize = 8;
+
+#[derive(Clone, Copy)]
+struct Slot {
+    note: Option<u8>,
+    held: bool,
+    /// When the voice was last started or released.
+    since: u64,
+}
+
+/// Assigns notes to a fixed number of voices. A new note takes the voice
+/// that has been released the longest (the quietest, as its envelope is
+/// furthest into the release) and steals the oldest held voice only when all
+/// of them are held.
+pub struct VoiceAllocator {
+    slots: Vec<Slot>,
+    clock: u64,
+}
+
+impl VoiceAllocator {
+    pub fn new(polyphony: usize) -> Self {
+        Self {
+            slots: vec![
+                Slot {
+                    note: None,
+                    held: false,
+                    since: 0,
+                };
+                polyphony.clamp(1, MAX_POLYPHONY)
+            ],
+            clock: 0,
+        }
+    }
+
+    /// Returns the voice that should play `note`.
+    pub fn note_on(&mut self, note: u8) -> usize {
+        self.clock += 1;
+        let index = self
+            .slots
+            .iter()
+            .position(|s| s.note == Some(note))
+            .or_else(|| {
+                self.slots
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, s)| !s.held)
+                    .min_by_key(|(_, s)| s.since)
+                    .map(|(i, _)| i)
+            })
+            .unwrap_or_else(|| {
+                self.slots
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(_, s)| s.since)
+                    .map(|(i, _)| i)
+                    .expect("at least one voice")
+            });
+        self.slots[index] = Slot {
+            note: Some(note),
+            held: true,
+            since: self.clock,
+        };
+        index
+    }
+
+    /// Returns the voice to release, if `note` is still held on one.
+    pub fn note_off(&mut self, note: u8) -> Option<usize> {
+        self.clock += 1;
+        let index = self
+            .slots
+            .iter()
+            .position(|s| s.held && s.note == Some(note))?;
+        self.slots[index].held = false;
+        self.slots[index].since = self.clock;
+        Some(index)
+    }
+}
+
+/// Which held key sounds in mono mode.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum NotePriority {
+    #[default]
+    Last,
+    Low,
+    High,
+}
+
+impl NotePriority {
+    pub const ALL: [NotePriority; 3] = [NotePriority::Last, NotePriority::Low, NotePriority::High];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            NotePriority::Last => "Last",
+            NotePriority::Low => "Low",
+            NotePriority::High => "High",
+        }
+    }
+}
+
+/// What the single voice should do after a key changes in mono mode.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MonoChange {
+    /// Move to `note`, restarting the envelopes when `retrigger` is set.
+    Play {
+        note: u8,
+        retrigger: bool,
+    },
+    Release,
+}
+
+/// The held keys in mono mode. Releasing the sounding key falls back to the
+/// next one by priority, like the hardware does.
+pub struct NoteStack {
+    /// Held keys in the order they were pressed.
+    held: Vec<u8>,
+    pub priority: NotePriority,
+    /// Glide between held notes without restarting the envelopes.
+    pub legato: bool,
+}
+
+impl Default for NoteStack {
+    fn default() -> Self {
+        Self {
+            // Room for every key, so a key press never allocates on the
+            // audio thread.
+            held: Vec::with_capacity(128),
+            priority: NotePriority::default(),
+            legato: false,
+        }
+    }
+}
+
+impl NoteStack {
+    fn current(&self) -> Option<u8> {
+        match self.priority {
+            NotePriority::Last => self.held.last().copied(),
+            NotePriority::Low => self.held.iter().min().copied(),
+            NotePriority::High => self.held.iter().max().copied(),
+        }
+    }
+
+    /// Forgets the held keys, e.g. when the voices are rebuilt.
+    pub fn clear(&mut self) {
+        self.held.clear();
+    }
+
+    pub fn note_on(&mut self, note: u8) -> Option<MonoChange> {
+        let before = self.current();
+        self.held.retain(|&n| n != note);
+        self.held.push(note);
+        let now = self.current()?;
+        // A repeated key restarts the note even though the pitch stays.
+        if Some(now) == before && now != note {
+            return None;
+        }
+        Some(MonoChange::Play {
+            note: now,
+            retrigger: before.is_none() || !self.legato,
+        })
+    }
+
+    pub fn note_off(&mut self, note: u8) -> Option<MonoChange> {
+        let before = self.current();
+        self.held.retain(|&n| n != note);
+        match self.current() {
+            None if before.is_some() => Some(MonoChange::Release),
+            Some(now) if Some(now) != before => Some(MonoChange::Play {
+                note: now,
+                retrigger: !self.legato,
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocates_free_voices_first() {
+        let mut voices = VoiceAllocator::new(3);
+        let a = voices.note_on(60);
+        let b = voices.note_on(64);
+        let c = voices.note_on(67);
+        assert_eq!((a, b, c), (0, 1, 2));
+        assert_eq!(voices.note_off(64), Some(b));
+        assert_eq!(voices.note_off(64), None);
+        assert_eq!(voices.note_on(72), b);
+    }
+
+    #[test]
+    fn steals_the_longest_released_then_the_oldest_held() {
+        let mut voices = VoiceAllocator::new(2);
+        let a = voices.note_on(60);
+        let b = voices.note_on(64);
+        voices.note_off(64);
+        voices.note_off(60);
+        // Both released: the one released first is the quietest.
+        assert_eq!(voices.note_on(67), b);
+
+        // All held: the oldest note goes.
+        voices.note_on(69);
+        assert_eq!(voices.note_on(71), b);
+        assert_eq!(voices.note_off(69), Some(a));
+    }
+
+    #[test]
+    fn repeated_note_reuses_its_voice() {
+        let mut voices = VoiceAllocator::new(4);
+        let a = voices.note_on(60);
+        voices.note_on(62);
+        voices.note_off(60);
+        assert_eq!(voices.note_on(60), a);
+    }
+
+    #[test]
+    fn release_falls_back_to_the_held_note() {
+        let mut stack = NoteStack::default();
+        let play = |note, retrigger| Some(MonoChange::Play { note, retrigger });
+
+        assert_eq!(stack.note_on(60), play(60, true));
+        assert_eq!(stack.note_on(64), play(64, true));
+        assert_eq!(stack.note_off(64), play(60, true));
+        assert_eq!(stack.note_off(60), Some(MonoChange::Release));
+
+        stack.legato = true;
+        assert_eq!(stack.note_on(60), play(60, true));
+        assert_eq!(stack.note_on(64), play(64, false));
+        // Releasing a key that is not sounding changes nothing.
+        stack.note_on(67);
+        assert_eq!(stack.note_off(64), None);
+        assert_eq!(stack.note_off(67), play(60, false));
+    }
+
+    #[test]
+    fn low_and_high_priority() {
+        let mut stack = NoteStack {
+            priority: NotePriority::Low,
+            ..Default::default()
+        };
+        stack.note_on(60);
+        assert_eq!(stack.note_on(64), None);
+        assert_eq!(stack.note_off(64), None);
+        assert!(matches!(
+            stack.note_on(55),
+            Some(MonoChange::Play { note: 55, .. })
+        ));
+
+        stack.priority = NotePriority::High;
+        stack.note_off(55);
+        assert!(matches!(
+            stack.note_on(67),
+            Some(MonoChange::Play { note: 67, .. })
+        ));
+        assert!(matches!(
+            stack.note_off(67),
+            Some(MonoChange::Play { note: 60, .. })
+        ));
+    }
+}
@@ -0,0 +1,454 @@
+use std::time::{Duration, Instant};
+
+use crate::params::{ParamKind, PARAMS};
+use crate::protocol::*;
+
+/// Time the device gets to acknowledge a write. Flash erase and program of a
+/// full bank takes well under a second on the RP2040.
+const ACK_TIMEOUT: Duration = Duration::from_secs(3);
+/// Time the device gets to answer the verify dump request.
+const DUMP_TIMEOUT: Duration = Duration::from_secs(3);
+/// Attempts per step, including the first one.
+const MAX_ATTEMPTS: u32 = 3;
+/// Mismatches listed in the status line, the rest go to the log only.
+const MAX_REPORTED: usize = 3;
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum WriteTarget {
+    Preset(usize),
+    Bank,
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+enum State {
+    AwaitingAck,
+    /// The write was acknowledged, the verify dump request still has to go out.
+    RequestDump,
+    AwaitingDump,
+    /// The device rejected or ignored a single-preset write; resend the whole
+    /// bank.
+    FallbackToBank,
+    Done,
+}
+
+/// What the app should do after `WriteSession::poll`.
+pub enum Step {
+    Wait,
+    /// Send the message and show the status.
+    Send(Vec<u8>, String),
+    /// The session gave up; show the status.
+    Failed(String),
+    FallbackToBank,
+    /// The session is over and can be dropped.
+    Done,
+}
+
+/// Tracks one "Save to Device" from the write message to the device's ACK
+/// and, optionally, a read-back of the bank that is compared with what was
+/// sent.
+pub struct WriteSession {
+    pub target: WriteTarget,
+    message: Vec<u8>,
+    /// Presets as the device should store them, i.e. after the round trip
+    /// through the target format version, with their slot index.
+    expected: Vec<(usize, Preset)>,
+    verify: bool,
+    state: State,
+    /// Set once the device confirmed the write (and the read-back matched).
+    saved: bool,
+    attempts: u32,
+    deadline: Instant,
+}
+
+impl WriteSession {
+    /// Starts a session for a write message that was just sent.
+    pub fn new(
+        target: WriteTarget,
+        message: Vec<u8>,
+        verify: bool,
+        now: Instant,
+    ) -> Result<Self, ProtocolError> {
+        // Decoding our own message yields exactly what the device can store,
+        // with fields the target version lacks already back at defaults.
+        let expected = match target {
+            WriteTarget::Bank => Storage::from_sysex(&message)?
+                .presets
+                .into_iter()
+                .enumerate()
+                .collect(),
+            WriteTarget::Preset(_) => vec![Preset::from_sysex(&message)?],
+        };
+
+        Ok(Self {
+            target,
+            message,
+            expected,
+            verify,
+            state: State::AwaitingAck,
+            saved: false,
+            attempts: 1,
+            deadline: now + ACK_TIMEOUT,
+        })
+    }
+
+    /// True while a bank dump from the device belongs to this session rather
+    /// than being a dump the user asked for.
+    pub fn wants_dump(&self) -> bool {
+        matches!(self.state, State::RequestDump | State::AwaitingDump)
+    }
+
+    pub fn on_ack(&mut self) -> String {
+        if self.state != State::AwaitingAck {
+            return "Ignored unexpected write ACK".to_string();
+        }
+        if self.verify {
+            self.state = State::RequestDump;
+            self.attempts = 0;
+            "Write acknowledged, verifying...".to_string()
+        } else {
+            self.state = State::Done;
+            self.saved = true;
+            self.saved()
+        }
+    }
+
+    /// True once the session is over and the write went through.
+    pub fn succeeded(&self) -> bool {
+        self.state == State::Done && self.saved
+    }
+
+    pub fn on_nak(&mut self, code: u8) -> String {
+        if self.state != State::AwaitingAck {
+            return format!("Ignored unexpected write error (code {})", code);
+        }
+        if let WriteTarget::Preset(_) = self.target {
+            // Older firmware only understands full bank writes
+            self.state = State::FallbackToBank;
+            "Preset write rejected, sending full bank...".to_string()
+        } else {
+            self.state = State::Done;
+            format!("Save Failed! Error Code: {}", code)
+        }
+    }
+
+    /// Compares the read-back bank with what was sent.
+    pub fn on_dump(&mut self, result: Result<Storage, ProtocolError>) -> String {
+        self.state = State::Done;
+        let storage = match result {
+            Ok(storage) => storage,
+            Err(e) => return format!("Verify failed, could not read back bank: {}", e),
+        };
+
+        let mut mismatches = Vec::new();
+        if self.target == WriteTarget::Bank && storage.presets.len() != self.expected.len() {
+            mismatches.push(format!(
+                "device holds {} presets, {} were sent",
+                storage.presets.len(),
+                self.expected.len()
+            ));
+        }
+        for (index, expected) in &self.expected {
+            match storage.presets.get(*index) {
+                Some(actual) => mismatches.extend(
+                    compare(expected, actual)
+                        .into_iter()
+                        .map(|m| format!("preset {}: {}", index + 1, m)),
+                ),
+                None => mismatches.push(format!("preset {} missing", index + 1)),
+            }
+        }
+
+        if mismatches.is_empty() {
+            self.saved = true;
+            return format!("{}, verified", self.saved());
+        }
+        for mismatch in &mismatches {
+            log::warn!("Verify mismatch: {}", mismatch);
+        }
+        let mut status = format!(
+            "Verify FAILED, {} mismatches: {}",
+            mismatches.len(),
+            mismatches[..mismatches.len().min(MAX_REPORTED)].join("; ")
+        );
+        if mismatches.len() > MAX_REPORTED {
+            status.push_str("; ...");
+        }
+        status
+    }
+
+    /// Advances timeouts and returns what the app has to do next.
+    pub fn poll(&mut self, now: Instant) -> Step {
+        match self.state {
+            State::Done => Step::Done,
+            State::FallbackToBank => {
+                self.state = State::Done;
+                Step::FallbackToBank
+            }
+            State::RequestDump => self.request_dump(now),
+            State::AwaitingAck if now >= self.deadline => {
+                if let WriteTarget::Preset(_) = self.target {
+                    // Older firmware ignores single-preset writes rather than
+                    // rejecting them.
+                    self.state = State::Done;
+                    return Step::FallbackToBank;
+                }
+                if self.attempts >= MAX_ATTEMPTS {
+                    self.state = State::Done;
+                    return Step::Failed(format!(
+                        "Save Failed! No response after {} attempts",
+                        self.attempts
+                    ));
+                }
+                self.attempts += 1;
+                self.deadline = now + ACK_TIMEOUT;
+                Step::Send(
+                    self.message.clone(),
+                    format!(
+                        "No ACK, resending (attempt {}/{})",
+                        self.attempts, MAX_ATTEMPTS
+                    ),
+                )
+            }
+            State::AwaitingDump if now >= self.deadline => {
+                if self.attempts >= MAX_ATTEMPTS {
+                    self.state = State::Done;
+                    return Step::Failed("Verify failed, device did not send its bank".to_string());
+                }
+                self.request_dump(now)
+            }
+            State::AwaitingAck | State::AwaitingDump => Step::Wait,
+        }
+    }
+
+    fn request_dump(&mut self, now: Instant) -> Step {
+        self.state = State::AwaitingDump;
+        self.attempts += 1;
+        self.deadline = now + DUMP_TIMEOUT;
+        Step::Send(
+            vec![
+                SYSEX_START,
+                MANUFACTURER_ID,
+                MODEL_ID,
+                CMD_DUMP_REQ,
+                SYSEX_END,
+            ],
+            format!(
+                "Reading back bank (attempt {}/{})",
+                self.attempts, MAX_ATTEMPTS
+            ),
+        )
+    }
+
+    fn saved(&self) -> String {
+        match self.target {
+            WriteTarget::Preset(index) => format!("Saved preset {}!", index + 1),
+            WriteTarget::Bank => "Save Successful!".to_string(),
+        }
+    }
+}
+
+/// Lists the fields that differ between two presets, by registry name.
+fn compare(expected: &Preset, actual: &Preset) -> Vec<String> {
+    let mut mismatches = Vec::new();
+    if expected.name != actual.name {
+        mismatches.push(format!(
+            "name sent '{}', read '{}'",
+            expected.name, actual.name
+        ));
+    }
+    for desc in PARAMS {
+        let (sent, read) = (expected.param(desc.id), actual.param(desc.id));
+        if sent != read {
+            mismatches.push(match desc.kind {
+                ParamKind::Choice(names) => format!(
+                    "{} sent {}, read {}",
+                    desc.name,
+                    names.get(sent as usize).unwrap_or(&"?"),
+                    names.get(read as usize).unwrap_or(&"?")
+                ),
+                _ => format!("{} sent {}, read {}", desc.name, sent, read),
+            });
+        }
+    }
+    mismatches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::EmulatedDevice;
+    use crate::transport::{LoopbackTransport, MidiTransport, LOOPBACK_PORT};
+    use std::sync::{Arc, Mutex};
+
+    /// A loopback transport whose replies are collected for the test.
+    fn connect(
+        responder: impl FnMut(&[u8]) -> Vec<Vec<u8>> + Send + 'static,
+    ) -> (LoopbackTransport, Arc<Mutex<Vec<Vec<u8>>>>) {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let sink = received.clone();
+        let mut transport = LoopbackTransport::new(responder);
+        transport.connect_output(LOOPBACK_PORT).unwrap();
+        transport
+            .connect_input(
+                LOOPBACK_PORT,
+                Box::new(move |msg| sink.lock().unwrap().push(msg.to_vec())),
+            )
+            .unwrap();
+        (transport, received)
+    }
+
+    /// Feeds everything the device sent back into the session, the way the
+    /// editor's input handler does, and returns the last status.
+    fn deliver(
+        transport: &mut LoopbackTransport,
+        received: &Mutex<Vec<Vec<u8>>>,
+        session: &mut WriteSession,
+    ) -> Option<String> {
+        transport.poll();
+        let mut status = None;
+        for msg in received.lock().unwrap().drain(..) {
+            status = Some(match msg[3] {
+                CMD_WRITE_SUCCESS => session.on_ack(),
+                CMD_WRITE_ERROR => session.on_nak(msg[4]),
+                CMD_WRITE_REQ if session.wants_dump() => session.on_dump(Storage::from_sysex(&msg)),
+                cmd => panic!("unexpected reply {:02X}", cmd),
+            });
+        }
+        status
+    }
+
+    fn two_presets() -> Storage {
+        let mut storage = Storage::default();
+        storage.presets.push(Preset {
+            name: "Second".to_string(),
+            ..Preset::default()
+        });
+        storage
+    }
+
+    #[test]
+    fn bank_write_is_acknowledged_and_verified() {
+        let mut device = EmulatedDevice::new(None);
+        let (mut transport, received) = connect(move |msg| device.handle(msg).0);
+        let now = Instant::now();
+
+        let msg = two_presets().to_sysex(VERSION, true).unwrap();
+        transport.send(&msg).unwrap();
+        let mut session = WriteSession::new(WriteTarget::Bank, msg, true, now).unwrap();
+
+        let status = deliver(&mut transport, &received, &mut session);
+        assert_eq!(status.as_deref(), Some("Write acknowledged, verifying..."));
+
+        let Step::Send(request, _) = session.poll(now) else {
+            panic!("expected a dump request");
+        };
+        assert_eq!(request[3], CMD_DUMP_REQ);
+        transport.send(&request).unwrap();
+
+        let status = deliver(&mut transport, &received, &mut session);
+        assert_eq!(status.as_deref(), Some("Save Successful!, verified"));
+        assert!(matches!(session.poll(now), Step::Done));
+    }
+
+    #[test]
+    fn verify_reports_mismatching_fields() {
+        let mut device = EmulatedDevice::new(None);
+        let (mut transport, received) = connect(move |msg| {
+            let mut replies = device.handle(msg).0;
+            if msg[3] == CMD_DUMP_REQ {
+                // Flip a bit in the first preset's name
+                replies[0][4 + HEADER_SIZE * 2 + 1] ^= 0x01;
+            }
+            replies
+        });
+        let now = Instant::now();
+
+        let msg = two_presets().to_sysex(VERSION, false).unwrap();
+        transport.send(&msg).unwrap();
+        let mut session = WriteSession::new(WriteTarget::Bank, msg, true, now).unwrap();
+        deliver(&mut transport, &received, &mut session);
+        let Step::Send(request, _) = session.poll(now) else {
+            panic!("expected a dump request");
+        };
+        transport.send(&request).unwrap();
+
+        let status = deliver(&mut transport, &received, &mut session).unwrap();
+        assert!(status.starts_with("Verify FAILED, 1 mismatches: preset 1: name"));
+    }
+
+    #[test]
+    fn missing_ack_is_retried_then_fails() {
+        let sent = Arc::new(Mutex::new(0));
+        let counter = sent.clone();
+        let (mut transport, received) = connect(move |_| {
+            *counter.lock().unwrap() += 1;
+            Vec::new()
+        });
+        let mut now = Instant::now();
+
+        let msg = two_presets().to_sysex(VERSION, false).unwrap();
+        transport.send(&msg).unwrap();
+        let mut session = WriteSession::new(WriteTarget::Bank, msg, false, now).unwrap();
+
+        assert!(matches!(session.poll(now), Step::Wait));
+        for _ in 1..MAX_ATTEMPTS {
+            now += ACK_TIMEOUT;
+            let Step::Send(retry, _) = session.poll(now) else {
+                panic!("expected a retry");
+            };
+            transport.send(&retry).unwrap();
+            assert!(deliver(&mut transport, &received, &mut session).is_none());
+        }
+        now += ACK_TIMEOUT;
+        assert!(matches!(session.poll(now), Step::Failed(_)));
+        assert_eq!(*sent.lock().unwrap(), MAX_ATTEMPTS);
+    }
+
+    #[test]
+    fn rejected_preset_write_falls_back_to_bank() {
+        let (mut transport, received) = connect(|_| {
+            vec![vec![
+                SYSEX_START,
+                MANUFACTURER_ID,
+                MODEL_ID,
+                CMD_WRITE_ERROR,
+                0x01,
+                SYSEX_END,
+            ]]
+        });
+        let now = Instant::now();
+
+        let msg = two_presets().preset_sysex(1, VERSION, false).unwrap();
+        transport.send(&msg).unwrap();
+        let mut session = WriteSession::new(WriteTarget::Preset(1), msg, false, now).unwrap();
+
+        deliver(&mut transport, &received, &mut session);
+        assert!(matches!(session.poll(now), Step::FallbackToBank));
+    }
+
+    #[test]
+    fn ignored_preset_write_falls_back_to_bank() {
+        let mut device = EmulatedDevice::new(None);
+        device.set_features(Features {
+            preset_commands: false,
+            ..Features::ALL
+        });
+        let (mut transport, received) = connect(move |msg| device.handle(msg).0);
+        let mut now = Instant::now();
+
+        let msg = two_presets().preset_sysex(1, VERSION, false).unwrap();
+        transport.send(&msg).unwrap();
+        let mut session = WriteSession::new(WriteTarget::Preset(1), msg, false, now).unwrap();
+        assert!(deliver(&mut transport, &received, &mut session).is_none());
+        assert!(matches!(session.poll(now), Step::Wait));
+        now += ACK_TIMEOUT;
+        assert!(matches!(session.poll(now), Step::FallbackToBank));
+
+        // The bank write the editor sends instead goes through.
+        let msg = two_presets().to_sysex(VERSION, false).unwrap();
+        transport.send(&msg).unwrap();
+        let mut session = WriteSession::new(WriteTarget::Bank, msg, false, now).unwrap();
+        let status = deliver(&mut transport, &received, &mut session);
+        assert_eq!(status.as_deref(), Some("Save Successful!"));
+    }
+}